@@ -1,8 +1,23 @@
+use crate::transfer::Format;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit status is 0 on success, 1 when a key is not found and 2 on any other error."
+)]
 pub struct Cli {
+    /// Log more diagnostics to stderr (repeat for more detail)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Encryption key of the store: a file holding 32 bytes or 64 hex digits
+    #[arg(long, global = true)]
+    pub key_file: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -14,7 +29,17 @@ pub enum Commands {
     Get(GetArgs),
     Set(SetArgs),
     Delete(DeleteArgs),
+    Scan(ScanArgs),
     Merge(MergeArgs),
+    Rekey(RekeyArgs),
+    Backup(BackupArgs),
+    Restore(RestoreArgs),
+    Export(ExportArgs),
+    Import(ImportArgs),
+    Shell(ShellArgs),
+    Stats(StatsArgs),
+    Serve(ServeArgs),
+    Watch(WatchArgs),
 }
 
 #[derive(Parser)]
@@ -29,24 +54,155 @@ pub struct InitArgs {
 
 #[derive(Parser)]
 pub struct GetArgs {
+    /// Print `{"key": ..., "value": ...}` instead of the raw value
+    #[arg(long)]
+    pub json: bool,
+    /// Stream the value into this file instead of printing it
+    #[arg(long, conflicts_with = "json")]
+    pub output: Option<String>,
+    /// Use the keys of this namespace instead of those outside any namespace
+    #[arg(long)]
+    pub ns: Option<String>,
     pub key: String,
     pub base_dir: Option<String>,
 }
 
 #[derive(Parser)]
 pub struct SetArgs {
+    /// Stream the value from this file instead of taking it from the command line
+    #[arg(long, conflicts_with = "value")]
+    pub file: Option<String>,
+    /// Use the keys of this namespace instead of those outside any namespace
+    #[arg(long)]
+    pub ns: Option<String>,
+    /// The base directory, for use with `--file` where no value comes before it
+    #[arg(
+        long = "base-dir",
+        value_name = "BASE_DIR",
+        conflicts_with = "base_dir"
+    )]
+    pub base_dir_flag: Option<String>,
     pub key: String,
-    pub value: String,
+    #[arg(required_unless_present = "file")]
+    pub value: Option<String>,
     pub base_dir: Option<String>,
 }
 
 #[derive(Parser)]
 pub struct DeleteArgs {
+    /// Use the keys of this namespace instead of those outside any namespace
+    #[arg(long)]
+    pub ns: Option<String>,
     pub key: String,
     pub base_dir: Option<String>,
 }
 
+#[derive(Parser)]
+pub struct ScanArgs {
+    /// Print a `{"key": ..., "value": ...}` line per key instead of `key: value`
+    #[arg(long)]
+    pub json: bool,
+    /// Only list keys starting with this prefix
+    #[arg(long, default_value = "")]
+    pub prefix: String,
+    /// Use the keys of this namespace instead of those outside any namespace
+    #[arg(long)]
+    pub ns: Option<String>,
+    pub base_dir: Option<String>,
+}
+
 #[derive(Parser)]
 pub struct MergeArgs {
+    /// Cap disk I/O at this many bytes per second
+    #[arg(long)]
+    pub rate_limit: Option<u64>,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct RekeyArgs {
+    /// File holding the new key; without one the store is left unencrypted
+    #[arg(long)]
+    pub new_key_file: Option<String>,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct BackupArgs {
+    /// Add to the incremental backup chain in `dest`, copying only new or changed data files
+    #[arg(long)]
+    pub incremental: bool,
+    /// Cap disk I/O at this many bytes per second
+    #[arg(long)]
+    pub rate_limit: Option<u64>,
+    pub dest: String,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct RestoreArgs {
+    pub backup_dir: String,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct ExportArgs {
+    #[arg(long, value_enum, default_value = "jsonl")]
+    pub format: Format,
+    /// Only export keys starting with this prefix
+    #[arg(long, default_value = "")]
+    pub prefix: String,
+    /// Write to this file instead of stdout
+    #[arg(long)]
+    pub output: Option<String>,
+    /// Cap disk I/O at this many bytes per second
+    #[arg(long)]
+    pub rate_limit: Option<u64>,
     pub base_dir: Option<String>,
 }
+
+#[derive(Parser)]
+pub struct ImportArgs {
+    pub file: String,
+    /// Defaults to csv for `.csv` files and jsonl otherwise
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct ShellArgs {
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct StatsArgs {
+    #[arg(long)]
+    pub json: bool,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct ServeArgs {
+    /// Accept Redis (RESP2) clients on this address, e.g. 127.0.0.1:6379. Key expiries set
+    /// through it last only until the server stops
+    #[arg(long, required_unless_present = "http")]
+    pub resp: Option<String>,
+    /// Serve the HTTP/JSON API on this address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    pub http: Option<String>,
+    pub base_dir: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct WatchArgs {
+    /// Address of the HTTP API of a running `serve`
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub addr: String,
+    /// Print a JSON line per change instead of `seq put key: value`
+    #[arg(long)]
+    pub json: bool,
+    /// Only follow keys starting with this prefix
+    #[arg(default_value = "")]
+    pub prefix: String,
+}
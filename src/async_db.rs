@@ -0,0 +1,153 @@
+use crate::batch::WriteBatch;
+use crate::disk_store::DiskStorage;
+use crate::error::DbError;
+use crate::options::DiskStorageOptions;
+use crate::snapshot::Snapshot;
+use crate::stats::Stats;
+use crate::Error;
+use std::sync::{Arc, Mutex};
+use tokio::{sync::mpsc, task};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+/// How many scanned entries may wait in a stream before the reading thread pauses.
+const STREAM_BUFFER: usize = 64;
+
+/// An async handle to a `DiskStorage` for tokio applications, available with the `async`
+/// feature. Every operation runs on tokio's blocking thread pool, so disk I/O never stalls
+/// the runtime. Clones share the same store.
+#[derive(Debug, Clone)]
+pub struct AsyncDb {
+    store: Arc<Mutex<DiskStorage>>,
+}
+
+impl AsyncDb {
+    /// Opens and loads the store in `base_dir`, like `DiskStorage::with_options` and `init`.
+    pub async fn open(
+        base_dir: Option<String>,
+        options: DiskStorageOptions,
+    ) -> Result<Self, Error> {
+        let store = task::spawn_blocking(move || {
+            let open = || -> Result<DiskStorage, Error> {
+                let mut store = DiskStorage::with_options(base_dir, options)?;
+                store.init()?;
+                Ok(store)
+            };
+            open().map_err(|err| err.to_string())
+        })
+        .await??;
+
+        Ok(Self::from_store(Arc::new(Mutex::new(store))))
+    }
+
+    /// Wraps a store that is already shared, e.g. with a server or `spawn_merge_thread`.
+    pub fn from_store(store: Arc<Mutex<DiskStorage>>) -> Self {
+        AsyncDb { store }
+    }
+
+    /// The shared store behind this handle.
+    pub fn store(&self) -> &Arc<Mutex<DiskStorage>> {
+        &self.store
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let key = key.to_string();
+        self.run(move |store| store.get(&key)).await
+    }
+
+    pub async fn set(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        let key = key.to_string();
+        self.try_run(move |store| store.set(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        let key = key.to_string();
+        self.try_run(move |store| store.delete(&key)).await
+    }
+
+    pub async fn apply_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        self.try_run(move |store| store.apply_batch(&batch)).await
+    }
+
+    /// Merges every sealed data file; other operations wait until the merged entries have been
+    /// swapped in.
+    pub async fn merge(&self) -> Result<(), Error> {
+        self.try_run(|store| store.merge()).await
+    }
+
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        self.run(|store| store.stats()).await
+    }
+
+    /// Streams every key in `[start, end)` with its value, in key order.
+    ///
+    /// The stream reads from a snapshot taken when it is created, so writes made while it is
+    /// being consumed are not seen and do not wait for it.
+    pub async fn range(
+        &self,
+        start: &str,
+        end: &str,
+    ) -> Result<impl Stream<Item = (String, Vec<u8>)>, Error> {
+        let end = end.to_string();
+        self.stream_from(start, move |key| key < end.as_str()).await
+    }
+
+    /// Streams every key starting with `prefix` with its value, in key order, from a snapshot
+    /// like `range`.
+    pub async fn scan(&self, prefix: &str) -> Result<impl Stream<Item = (String, Vec<u8>)>, Error> {
+        let prefix_owned = prefix.to_string();
+        self.stream_from(prefix, move |key| key.starts_with(&prefix_owned))
+            .await
+    }
+
+    async fn stream_from(
+        &self,
+        start: &str,
+        mut keep_going: impl FnMut(&str) -> bool + Send + 'static,
+    ) -> Result<ReceiverStream<(String, Vec<u8>)>, Error> {
+        let snapshot: Snapshot = self
+            .run(|store| store.snapshot().map_err(|err| err.to_string()))
+            .await??;
+
+        let start = start.to_string();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        task::spawn_blocking(move || {
+            for entry in snapshot.scan_from(&start) {
+                // Stop early once the stream has been dropped.
+                if !keep_going(&entry.0) || sender.blocking_send(entry).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(receiver))
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut DiskStorage) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        let store = self.store.clone();
+        let result = task::spawn_blocking(move || operation(&mut store.lock().unwrap())).await?;
+        Ok(result)
+    }
+
+    /// Like `run`, for operations that can fail.
+    async fn try_run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut DiskStorage) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let result = self
+            .run(move |store| operation(store).map_err(sendable))
+            .await?;
+        result.map_err(|err| err as Error)
+    }
+}
+
+/// Makes an error of the store sendable out of a blocking task, keeping a `DbError` typed so
+/// callers can still match on it.
+fn sendable(err: Error) -> Box<dyn std::error::Error + Send + Sync> {
+    match err.downcast::<DbError>() {
+        Ok(err) => err,
+        Err(err) => err.to_string().into(),
+    }
+}
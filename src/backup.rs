@@ -0,0 +1,75 @@
+use crate::Error;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The data files a backup captured, as `(file_id, size)` pairs.
+///
+/// Every backup lists all files the store had at the time, even when an incremental backup only
+/// copied the new or changed ones; earlier backups in the chain hold the rest.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub files: Vec<(u32, u64)>,
+}
+
+impl Manifest {
+    pub fn read(dir: &Path) -> Result<Self, Error> {
+        let mut files = vec![];
+        for line in fs::read_to_string(dir.join(MANIFEST_FILE))?.lines() {
+            let (id, size) = line.split_once(' ').ok_or("malformed backup manifest")?;
+            files.push((id.parse()?, size.parse()?));
+        }
+
+        Ok(Manifest { files })
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), Error> {
+        let contents: String = self
+            .files
+            .iter()
+            .map(|(id, size)| format!("{} {}\n", id, size))
+            .collect();
+        fs::write(dir.join(MANIFEST_FILE), contents)?;
+
+        Ok(())
+    }
+
+    pub fn size_of(&self, file_id: u32) -> Option<u64> {
+        self.files
+            .iter()
+            .find(|(id, _)| *id == file_id)
+            .map(|(_, size)| *size)
+    }
+}
+
+/// Backups to replay for `dir`, oldest first.
+///
+/// A full backup is a single directory with a manifest; an incremental backup root holds one
+/// numbered subdirectory per backup taken into it.
+pub fn chain(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if dir.join(MANIFEST_FILE).exists() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+
+    let mut backups: Vec<(u32, PathBuf)> = vec![];
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let index = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(index) = index {
+                if entry.path().join(MANIFEST_FILE).exists() {
+                    backups.push((index, entry.path()));
+                }
+            }
+        }
+    }
+    backups.sort();
+
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
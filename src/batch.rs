@@ -0,0 +1,35 @@
+/// A group of puts and deletes applied together by `DiskStorage::apply_batch`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &str, value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Put(key.to_string(), value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key.to_string()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
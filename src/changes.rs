@@ -0,0 +1,119 @@
+use crate::encryption::Cipher;
+use crate::error::DbError;
+use crate::format::{FileFormat, KeyValue, RECORD_HEADER_SIZE};
+use crate::positioned::PositionedIo;
+use crate::{compression, Error};
+use std::{collections::VecDeque, fs::File, sync::Arc};
+
+/// A write to a key of the default namespace, as read back by `DiskStorage::changes_since`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub seq: u64,
+    /// Write time in seconds since the epoch.
+    pub timestamp: usize,
+    pub key: String,
+    /// The value written, or `None` for a delete.
+    pub value: Option<Vec<u8>>,
+}
+
+/// A data file to read changes from, up to where it ended when the changes were asked for.
+pub(crate) struct ChangeFile {
+    pub id: u32,
+    pub file: Arc<File>,
+    pub format: FileFormat,
+    pub end: u64,
+}
+
+/// Reads the records after a sequence number from data files that hold them in order, holding
+/// its own handles so merges removing the files cannot cut it short.
+pub(crate) struct Changes {
+    since: u64,
+    files: VecDeque<ChangeFile>,
+    /// Where the next record of the first file starts.
+    position: u64,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Changes {
+    pub(crate) fn new(since: u64, files: Vec<ChangeFile>, cipher: Option<Arc<Cipher>>) -> Self {
+        let position = files
+            .first()
+            .map_or(0, |file| file.format.header_size() as u64);
+        Changes {
+            since,
+            files: files.into(),
+            position,
+            cipher,
+        }
+    }
+
+    /// Reads the record at `position` of `file`, returning it with its size.
+    fn read(&self, file: &ChangeFile) -> Result<(KeyValue, u64), Error> {
+        let corruption = || DbError::Corruption {
+            file_id: file.id,
+            position: self.position as usize,
+        };
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        file.file.read_exact_at(&mut header, self.position)?;
+        let (_, _, _, _, key_size, value_size) = KeyValue::decode_header(&header)?;
+        let total_size = KeyValue::record_size(key_size, value_size)
+            .filter(|size| *size as u64 <= file.end - self.position)
+            .ok_or_else(corruption)?;
+
+        let mut record = vec![0u8; total_size];
+        file.file.read_exact_at(&mut record, self.position)?;
+        if !file.format.verify(&record) {
+            return Err(corruption().into());
+        }
+
+        let kv = KeyValue::from_bytes(&record, self.cipher.as_deref())?;
+        Ok((kv, total_size as u64))
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change, Error>;
+
+    fn next(&mut self) -> Option<Result<Change, Error>> {
+        loop {
+            let file = self.files.front()?;
+            if self.position >= file.end {
+                self.files.pop_front();
+                self.position = self
+                    .files
+                    .front()
+                    .map_or(0, |file| file.format.header_size() as u64);
+                continue;
+            }
+
+            let change = self.read(file).and_then(|(kv, size)| {
+                self.position += size;
+                if kv.seq <= self.since || kv.namespace != KeyValue::DEFAULT_NAMESPACE {
+                    return Ok(None);
+                }
+
+                let value = match kv.is_tombstone() {
+                    true => None,
+                    false => Some(compression::decode(kv.flags, &kv.value)?),
+                };
+                Ok(Some(Change {
+                    seq: kv.seq,
+                    timestamp: kv.timestamp,
+                    key: kv.key,
+                    value,
+                }))
+            });
+            match change {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Ok(None) => {}
+                // Stopping short rather than skipping the record lets a reader that resumes
+                // from the last change it got try again.
+                Err(err) => {
+                    self.files.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::Error;
+use xxhash_rust::xxh3::Xxh3Default;
+
+/// Algorithm the records of a data file are checksummed with, recorded in the file's header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    /// CRC-32/ISO-HDLC, the checksum of data files written before files had a header.
+    Crc32,
+    /// CRC-32C (Castagnoli), computed with SSE 4.2 or ARMv8 CRC instructions where available.
+    #[default]
+    Crc32c,
+    /// The low 32 bits of 64-bit xxHash3.
+    Xxh3,
+}
+
+impl Checksum {
+    /// Identifier of the algorithm in a data file header.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Checksum::Crc32 => 1,
+            Checksum::Crc32c => 2,
+            Checksum::Xxh3 => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            1 => Ok(Checksum::Crc32),
+            2 => Ok(Checksum::Crc32c),
+            3 => Ok(Checksum::Xxh3),
+            _ => Err(format!("unknown checksum algorithm {}", id).into()),
+        }
+    }
+
+    /// Starts a checksum to be fed incrementally.
+    pub(crate) fn digest(self) -> Digest {
+        match self {
+            Checksum::Crc32 => Digest::Crc32(CRC_32.digest()),
+            Checksum::Crc32c => Digest::Crc32c(0),
+            Checksum::Xxh3 => Digest::Xxh3(Box::default()),
+        }
+    }
+}
+
+static CRC_32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// A checksum in progress, see `Checksum::digest`.
+pub(crate) enum Digest {
+    Crc32(crc::Digest<'static, u32>),
+    Crc32c(u32),
+    Xxh3(Box<Xxh3Default>),
+}
+
+impl Digest {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Digest::Crc32(digest) => digest.update(bytes),
+            Digest::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+            Digest::Xxh3(hasher) => hasher.update(bytes),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        match self {
+            Digest::Crc32(digest) => digest.finalize(),
+            Digest::Crc32c(crc) => crc,
+            Digest::Xxh3(hasher) => hasher.digest() as u32,
+        }
+    }
+}
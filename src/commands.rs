@@ -1,50 +1,276 @@
-use crate::args::{CreateArgs, DeleteArgs, GetArgs, InitArgs, MergeArgs, SetArgs};
+use crate::args::{
+    BackupArgs, CreateArgs, DeleteArgs, ExportArgs, GetArgs, ImportArgs, InitArgs, MergeArgs,
+    RekeyArgs, RestoreArgs, ScanArgs, ServeArgs, SetArgs, ShellArgs, StatsArgs, WatchArgs,
+};
+use crate::encryption::EncryptionKey;
+use crate::error::DbError;
+use crate::options::DiskStorageOptions;
+use crate::transfer::{self, Format, Record};
+use crate::watch::Event;
 use crate::{disk_store::DiskStorage, Error};
+use crate::{http, resp, shell};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-pub fn create(args: CreateArgs) -> Result<(), Error> {
-    DiskStorage::new(args.base_dir);
+pub fn create(args: CreateArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    DiskStorage::with_options(args.base_dir, options)?;
 
     Ok(())
 }
 
-pub fn init(args: InitArgs) -> Result<(), Error> {
-    let mut store = DiskStorage::new(args.base_dir);
+pub fn init(args: InitArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
     store.init()?;
 
     Ok(())
 }
 
-pub fn get(args: GetArgs) -> Result<(), Error> {
-    let mut store = DiskStorage::new(args.base_dir);
+pub fn get(args: GetArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
     store.init()?;
-    if let Some(value) = store.get(&args.key) {
-        println!("key: {}, value: {}", args.key, value);
+
+    if let Some(path) = args.output {
+        let reader = match &args.ns {
+            Some(ns) => store.namespace(ns).get_reader(&args.key),
+            None => store.get_reader(&args.key),
+        };
+        let mut reader = reader.ok_or(DbError::NotFound { key: args.key })?;
+        std::io::copy(&mut reader, &mut File::create(path)?)?;
+        return Ok(());
+    }
+
+    let value = match &args.ns {
+        Some(ns) => store.namespace(ns).get(&args.key),
+        None => store.get(&args.key),
+    };
+    let value = value.ok_or(DbError::NotFound {
+        key: args.key.clone(),
+    })?;
+
+    let mut stdout = std::io::stdout().lock();
+    if args.json {
+        serde_json::to_writer(&mut stdout, &Record::new(args.key, value))?;
+    } else {
+        stdout.write_all(&value)?;
     }
+    stdout.write_all(b"\n")?;
 
     Ok(())
 }
 
-pub fn set(args: SetArgs) -> Result<(), Error> {
-    let mut store = DiskStorage::new(args.base_dir);
+pub fn set(args: SetArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let base_dir = args.base_dir.or(args.base_dir_flag);
+    let Some(path) = args.file else {
+        let mut store = DiskStorage::with_options(base_dir, options)?;
+        store.init()?;
+        let value = args.value.unwrap_or_default();
+        match &args.ns {
+            Some(ns) => store.namespace(ns).set(&args.key, value.as_bytes())?,
+            None => store.set(&args.key, value.as_bytes())?,
+        }
+        return Ok(());
+    };
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut store = DiskStorage::with_options(base_dir, options)?;
     store.init()?;
-    store.set(&args.key, &args.value);
+    match &args.ns {
+        Some(ns) => store.namespace(ns).set_from_reader(&args.key, file, len)?,
+        None => store.set_from_reader(&args.key, file, len)?,
+    }
 
     Ok(())
 }
 
-pub fn delete(args: DeleteArgs) -> Result<(), Error> {
-    let mut store = DiskStorage::new(args.base_dir);
+pub fn delete(args: DeleteArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    match &args.ns {
+        Some(ns) => store.namespace(ns).delete(&args.key),
+        None => store.delete(&args.key),
+    }
+}
+
+pub fn scan(args: ScanArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
     store.init()?;
-    store.delete(&args.key);
-    store.merge()?;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut print = |key: String, value: Vec<u8>| -> Result<(), Error> {
+        if args.json {
+            serde_json::to_writer(&mut stdout, &Record::new(key, value))?;
+            stdout.write_all(b"\n")?;
+        } else {
+            writeln!(stdout, "{}: {}", key, String::from_utf8_lossy(&value))?;
+        }
+        Ok(())
+    };
+    match &args.ns {
+        Some(ns) => {
+            let namespace = store.namespace(ns);
+            for (key, value) in namespace.scan(&args.prefix) {
+                print(key, value)?;
+            }
+        }
+        None => {
+            for (key, value) in store.scan(&args.prefix) {
+                print(key, value)?;
+            }
+        }
+    }
 
     Ok(())
 }
 
-pub fn merge(args: MergeArgs) -> Result<(), Error> {
-    let mut store = DiskStorage::new(args.base_dir);
+pub fn merge(args: MergeArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
     store.init()?;
+    store.set_io_rate_limit(args.rate_limit);
     store.merge()?;
 
     Ok(())
 }
+
+pub fn rekey(args: RekeyArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let new_key = match args.new_key_file {
+        Some(path) => Some(EncryptionKey::from_file(path)?),
+        None => None,
+    };
+
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    store.rekey(new_key)?;
+
+    Ok(())
+}
+
+pub fn backup(args: BackupArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    store.set_io_rate_limit(args.rate_limit);
+    if args.incremental {
+        store.checkpoint_incremental(&args.dest)?;
+    } else {
+        store.checkpoint(&args.dest)?;
+    }
+
+    Ok(())
+}
+
+pub fn restore(args: RestoreArgs) -> Result<(), Error> {
+    DiskStorage::restore(&args.backup_dir, args.base_dir)?;
+
+    Ok(())
+}
+
+pub fn export(args: ExportArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    store.set_io_rate_limit(args.rate_limit);
+    match args.output {
+        Some(path) => transfer::export(&store, args.format, &args.prefix, File::create(path)?)?,
+        None => transfer::export(&store, args.format, &args.prefix, std::io::stdout().lock())?,
+    };
+
+    Ok(())
+}
+
+pub fn import(args: ImportArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let format = args.format.unwrap_or(if args.file.ends_with(".csv") {
+        Format::Csv
+    } else {
+        Format::Jsonl
+    });
+
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    let count = transfer::import(&mut store, format, File::open(&args.file)?)?;
+    log::info!("imported {} keys", count);
+
+    Ok(())
+}
+
+pub fn shell(args: ShellArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    shell::run(store)
+}
+
+pub fn stats(args: StatsArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+
+    let stats = store.stats();
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        println!("{}", stats);
+    }
+
+    Ok(())
+}
+
+pub fn serve(args: ServeArgs, options: DiskStorageOptions) -> Result<(), Error> {
+    let mut store = DiskStorage::with_options(args.base_dir, options)?;
+    store.init()?;
+    let store = Arc::new(Mutex::new(store));
+    DiskStorage::spawn_merge_thread(&store, Duration::from_secs(60));
+
+    let mut servers = vec![];
+    if let Some(addr) = args.resp {
+        let store = store.clone();
+        servers.push(thread::spawn(move || {
+            resp::serve(store, &addr).map_err(|err| err.to_string())
+        }));
+    }
+    if let Some(addr) = args.http {
+        let store = store.clone();
+        servers.push(thread::spawn(move || {
+            http::serve(store, &addr).map_err(|err| err.to_string())
+        }));
+    }
+
+    for server in servers {
+        server.join().map_err(|_| "server thread panicked")??;
+    }
+
+    Ok(())
+}
+
+pub fn watch(args: WatchArgs) -> Result<(), Error> {
+    http::watch_remote(&args.addr, &args.prefix, |event| {
+        let mut stdout = std::io::stdout().lock();
+        if args.json {
+            serde_json::to_writer(&mut stdout, &http::WatchMessage::from(event))?;
+            stdout.write_all(b"\n")?;
+            return Ok(stdout.flush()?);
+        }
+
+        match event {
+            Event::Put {
+                key,
+                seq,
+                value: Some(value),
+            } => writeln!(
+                stdout,
+                "{} put {}: {}",
+                seq,
+                key,
+                String::from_utf8_lossy(&value)
+            )?,
+            Event::Put {
+                key,
+                seq,
+                value: None,
+            } => writeln!(stdout, "{} put {}", seq, key)?,
+            Event::Delete { key, seq } => writeln!(stdout, "{} delete {}", seq, key)?,
+            Event::Lagged { missed } => log::warn!("fell behind and missed {} changes", missed),
+        }
+        Ok(stdout.flush()?)
+    })
+}
@@ -0,0 +1,57 @@
+use crate::format::KeyValue;
+use crate::Error;
+
+/// How values are compressed on disk, set with `DiskStorageOptions::compression`. Each record
+/// notes its codec in its header flags, so records written with different settings can be
+/// mixed in one store, and merge copies them as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd at the given level (1-22).
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Returns the header flags and the bytes to store for `value`. Values shorter than
+    /// `threshold`, and values that compression would not shrink, are stored as they are.
+    pub(crate) fn encode(&self, value: &[u8], threshold: usize) -> Result<(u8, Vec<u8>), Error> {
+        if value.len() < threshold {
+            return Ok((0, value.to_vec()));
+        }
+
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some((KeyValue::LZ4, lz4_flex::compress_prepend_size(value))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                Some((KeyValue::ZSTD, zstd::bulk::compress(value, *level)?))
+            }
+        };
+
+        match compressed {
+            Some((flag, compressed)) if compressed.len() < value.len() => Ok((flag, compressed)),
+            _ => Ok((0, value.to_vec())),
+        }
+    }
+}
+
+/// Restores a value stored with the codec named in `flags`.
+pub(crate) fn decode(flags: u8, stored: &[u8]) -> Result<Vec<u8>, Error> {
+    match flags & KeyValue::COMPRESSION {
+        0 => Ok(stored.to_vec()),
+        #[cfg(feature = "lz4")]
+        KeyValue::LZ4 => Ok(lz4_flex::decompress_size_prepended(stored)?),
+        #[cfg(feature = "zstd")]
+        KeyValue::ZSTD => Ok(zstd::stream::decode_all(stored)?),
+        #[cfg(not(feature = "lz4"))]
+        KeyValue::LZ4 => Err("value is lz4 compressed; rebuild with the lz4 feature".into()),
+        #[cfg(not(feature = "zstd"))]
+        KeyValue::ZSTD => Err("value is zstd compressed; rebuild with the zstd feature".into()),
+        _ => Err("unknown value compression".into()),
+    }
+}
@@ -1,53 +1,184 @@
-use crate::format::{KeyEntry, KeyValue};
-use crate::rb_trees::RBTree;
+use crate::backup::{self, Manifest};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::changes::{Change, ChangeFile, Changes};
+use crate::checksum::Checksum;
+use crate::compression;
+use crate::encryption::{Cipher, EncryptionKey};
+use crate::error::DbError;
+use crate::format::{FileFormat, KeyEntry, KeyValue, RECORD_HEADER_SIZE};
+use crate::index::{self, Extractor, Index, SecondaryIndex};
+use crate::lru::Lru;
+use crate::merge::{MergeJob, MergeOutcome, MergePlan};
+use crate::namespace::Namespace;
+use crate::options::DiskStorageOptions;
+use crate::positioned::PositionedIo;
+use crate::rate_limit::{RateLimiter, Throttled};
+use crate::rb_trees::{RBNode, RBTree};
+use crate::rekey::{self, RekeyManifest};
+use crate::snapshot::Snapshot;
+use crate::stats::{FileStats, Stats, StatsTracker};
+use crate::upgrade;
+use crate::value::{ValueReader, ValueRef};
+use crate::watch::{Event, Watcher};
 use crate::Error;
+use memmap2::Mmap;
 use std::{
-    collections::VecDeque,
-    fs::{self, File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug)]
 pub struct DiskStorage {
     file_id_counter: u32,
+    active_id: u32,
     file: File,
+    /// Format of the active file, which records are appended in.
+    active_format: FileFormat,
     write_position: usize,
-    key_dir: RBTree<String, KeyEntry>,
+    /// Keys of the default namespace, shared with the snapshots taken since it last changed.
+    key_dir: Arc<RBTree<String, KeyEntry>>,
+    /// Keys of every named namespace by id, and the names of the namespaces themselves under
+    /// `NAMESPACE_REGISTRY`.
+    namespaces: HashMap<u32, RBTree<String, KeyEntry>>,
+    /// Id of every named namespace, and the sequence number it was created at. Records of the
+    /// id older than that belong to a dropped namespace the id was taken from, as stores
+    /// written before the namespace counter did.
+    namespace_ids: HashMap<String, (u32, u64)>,
+    /// The last id handed to a namespace or index.
+    last_namespace_id: u32,
+    /// Secondary indexes over the default namespace by name, each kept in a namespace of its
+    /// own listed under `INDEX_REGISTRY`.
+    indexes: HashMap<String, SecondaryIndex>,
+    /// Extractors registered with `register_index`, by index name.
+    extractors: HashMap<String, Extractor>,
+    /// Whether `init` has loaded the data files, so indexes can be built.
+    loaded: bool,
+    /// Subscriptions made with `watch`.
+    watchers: Vec<Watcher>,
     base_dir: String,
-    tombstone: VecDeque<String>,
+    sequence: u64,
+    /// The oldest sequence number from which on every write is still on disk; merges move it
+    /// past the records they read.
+    history_start: u64,
+    stats: StatsTracker,
+    options: DiskStorageOptions,
+    merge_job: Option<MergeJob>,
+    io_limiter: RateLimiter,
+    /// Memory maps of recently used sealed files, opened on first read when `mmap_reads` is
+    /// on and bounded by `max_open_files`.
+    maps: Mutex<Lru<u32, Arc<Mmap>>>,
+    /// Read handles of recently used data files, bounded by `max_open_files`.
+    handles: Mutex<Lru<u32, Arc<File>>>,
+    /// Recently read values, bounded by `value_cache_bytes` of keys and values.
+    cache: Mutex<Lru<(u32, String), Vec<u8>>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// Seals records written from now on, and opens encrypted ones when they are read.
+    cipher: Option<Arc<Cipher>>,
+    /// Format of every data file, read from its header when it is loaded.
+    formats: HashMap<u32, FileFormat>,
+    /// The lock on the store directory, if no other open store held it. Only the holder cuts
+    /// off a write a crash left behind, as for anyone else it may be a write still under way,
+    /// and only the holder merges, rekeys and recovers.
+    lock: Option<File>,
 }
 
 impl DiskStorage {
-    const HEADER_SIZE: usize = 28;
+    /// Namespace whose records map the name of each named namespace to its id.
+    const NAMESPACE_REGISTRY: u32 = u32::MAX;
+    /// Namespace whose records map the name of each secondary index to the id of the namespace
+    /// its records are kept in.
+    const INDEX_REGISTRY: u32 = u32::MAX - 1;
+    /// Namespace of the record written ahead of the records of a single write, with their
+    /// count as its key. Such records are never live, so merges leave them behind.
+    const BATCH_MARKER: u32 = u32::MAX - 2;
+    /// Namespace whose only record holds the last namespace id handed out, so ids are never
+    /// taken again, not even those of dropped namespaces.
+    const NAMESPACE_COUNTER: u32 = u32::MAX - 3;
+    /// Namespace with a record for every index a write went past while it had no extractor
+    /// registered, under the index's name.
+    const STALE_INDEXES: u32 = u32::MAX - 4;
+    /// Namespace of the record a merge leaves in its file, holding the sequence number from
+    /// which on every write is still on disk. Its sequence number is 0, and each merge writes a
+    /// new one. This and every namespace above it is reserved.
+    pub(crate) const HISTORY_START: u32 = u32::MAX - 5;
 
-    pub fn new(base_dir: Option<String>) -> Self {
+    pub fn new(base_dir: Option<String>) -> Result<Self, Error> {
+        Self::with_options(base_dir, DiskStorageOptions::default())
+    }
+
+    pub fn with_options(
+        base_dir: Option<String>,
+        options: DiskStorageOptions,
+    ) -> Result<Self, Error> {
         let base_dir = base_dir.unwrap_or("db".to_string());
 
         if !Path::new(&base_dir).exists() {
-            std::fs::create_dir(&base_dir).unwrap();
+            std::fs::create_dir(&base_dir)?;
+        }
+        // Recovery and upgrades rewrite files, which only the holder of the lock may do: to
+        // anyone else, an interrupted rekey may be one still under way.
+        let lock = lock_dir(&base_dir)?;
+        if lock.is_some() {
+            rekey::recover(Path::new(&base_dir))?;
+            upgrade::upgrade_dir(&base_dir, options.checksum)?;
         }
 
-        let file_path = Path::new(&base_dir).join("0.db");
-        let write_position = 0;
-        let key_dir = RBTree::new();
-        let tombstone = VecDeque::new();
+        let last_id = file_ids_in(&base_dir)?.last().copied().unwrap_or(0);
+        let active_id = read_active(&base_dir)?.unwrap_or(last_id);
+        let file_path = Path::new(&base_dir).join(format!("{}.db", active_id));
+        let key_dir = Arc::new(RBTree::new());
+        let cipher = match &options.encryption_key {
+            Some(key) => Some(Arc::new(Cipher::new(key)?)),
+            None => None,
+        };
 
-        DiskStorage {
-            file_id_counter: 1,
+        let mut store = DiskStorage {
+            file_id_counter: last_id.max(active_id) + 1,
+            active_id,
             file: OpenOptions::new()
                 .read(true)
-                .write(true)
                 .create(true)
                 .append(true)
-                .open(&file_path)
-                .unwrap(),
-            write_position,
+                .open(&file_path)?,
+            active_format: FileFormat::V1(options.checksum),
+            write_position: 0,
             key_dir,
+            namespaces: HashMap::new(),
+            namespace_ids: HashMap::new(),
+            last_namespace_id: 0,
+            indexes: HashMap::new(),
+            extractors: HashMap::new(),
+            loaded: false,
+            watchers: vec![],
             base_dir,
-            tombstone,
-        }
+            sequence: 0,
+            history_start: 1,
+            stats: StatsTracker::default(),
+            io_limiter: RateLimiter::new(options.io_rate_limit),
+            handles: Mutex::new(Lru::new(options.max_open_files)),
+            maps: Mutex::new(Lru::new(options.max_open_files)),
+            cache: Mutex::new(Lru::new(options.value_cache_bytes)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            options,
+            merge_job: None,
+            cipher,
+            formats: HashMap::new(),
+            lock,
+        };
+        store.open_active(active_id)?;
+
+        Ok(store)
     }
 
     fn is_directory_empty(&self) -> std::io::Result<bool> {
@@ -58,233 +189,2199 @@ impl DiskStorage {
     pub fn init(&mut self) -> Result<(), Error> {
         if !self.is_directory_empty()? {
             self.init_key_dir()?;
+            self.open_active(self.active_id)?;
+        }
+        self.loaded = true;
+
+        let missing: Vec<String> = self
+            .extractors
+            .keys()
+            .filter(|name| !self.indexes.contains_key(*name) || self.is_stale(name))
+            .cloned()
+            .collect();
+        for name in missing {
+            self.build_index(&name)?;
+        }
 
-            let file_path =
-                Path::new(&self.base_dir).join(format!("{}.db", self.file_id_counter - 1));
+        Ok(())
+    }
 
-            self.file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&file_path)
-                .unwrap();
+    /// Sequence number of the most recent write.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Largest value the store accepts, see `DiskStorageOptions::max_value_size`.
+    pub fn max_value_size(&self) -> u64 {
+        self.options.max_value_size
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Error> {
+        self.set_in(KeyValue::DEFAULT_NAMESPACE, key, value)
+    }
+
+    pub(crate) fn set_in(&mut self, namespace: u32, key: &str, value: &[u8]) -> Result<(), Error> {
+        self.check_size(key, value.len() as u64)?;
+
+        let mut records = vec![self.put_record(namespace, key, value)?];
+        if namespace == KeyValue::DEFAULT_NAMESPACE {
+            records.extend(self.index_records(key, Some(value), &mut HashSet::new()));
+            records.extend(self.stale_index_records());
+        }
+        self.write_records(records)
+    }
+
+    /// Checks a key and value about to be written against the configured size limits.
+    fn check_size(&self, key: &str, value_size: u64) -> Result<(), DbError> {
+        if key.len() > self.options.max_key_size {
+            return Err(DbError::KeyTooLarge {
+                size: key.len(),
+                max: self.options.max_key_size,
+            });
+        }
+        if value_size > self.options.max_value_size {
+            return Err(DbError::ValueTooLarge {
+                size: value_size,
+                max: self.options.max_value_size,
+            });
         }
+
         Ok(())
     }
 
-    pub fn set(&mut self, key: &str, value: &str) {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize;
-        let kv = KeyValue::new(timestamp, key.to_string(), value.to_string());
-        let bytes = kv.to_bytes().unwrap();
-        let total_size = bytes.len();
+    /// Writes the `len` bytes read from `value` under `key`, streaming them into the active
+    /// file instead of holding them in memory. Streamed values are stored uncompressed. With
+    /// encryption on, the value is read into memory first, as each record is sealed whole; so it
+    /// is with secondary indexes, which need the whole value to index it.
+    ///
+    /// If `value` fails or ends early, the partial record is cut off again and nothing is
+    /// written.
+    pub fn set_from_reader(&mut self, key: &str, value: impl Read, len: u64) -> Result<(), Error> {
+        self.set_from_reader_in(KeyValue::DEFAULT_NAMESPACE, key, value, len)
+    }
 
-        if self.file.metadata().unwrap().len() > 100 {
-            self.file_id_counter += 1;
-            self.write_position = 0;
+    pub(crate) fn set_from_reader_in(
+        &mut self,
+        namespace: u32,
+        key: &str,
+        value: impl Read,
+        len: u64,
+    ) -> Result<(), Error> {
+        self.check_size(key, len)?;
+
+        let mut value = value.take(len);
+        let indexed = namespace == KeyValue::DEFAULT_NAMESPACE && !self.indexes.is_empty();
+        if self.cipher.is_some() || indexed {
+            let mut buf = vec![];
+            value.read_to_end(&mut buf)?;
+            if (buf.len() as u64) < len {
+                return Err("value ended before the given length".into());
+            }
+            return self.set_in(namespace, key, &buf);
+        }
 
-            let file_path =
-                Path::new(&self.base_dir).join(format!("{}.db", self.file_id_counter - 1));
+        if self.file.metadata()?.len() > self.options.max_file_size {
+            self.rotate()?;
+        }
 
-            self.file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&file_path)
-                .unwrap();
+        // The number is only taken once the record is written, so a failed write leaves no gap.
+        let kv = KeyValue::with_flags(now(), self.sequence + 1, 0, key.to_string(), vec![])
+            .in_namespace(namespace);
+        let prefix = kv.encode_prefix(len as usize);
+        let position = self.write_position;
+
+        let mut digest = self.active_format.start_checksum(&prefix);
+        digest.update(&prefix[RECORD_HEADER_SIZE..]);
+        let streamed = self.file.write_all(&prefix).and_then(|()| {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut remaining = len;
+            while remaining > 0 {
+                let read = value.read(&mut buf)?;
+                if read == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                digest.update(&buf[..read]);
+                self.file.write_all(&buf[..read])?;
+                remaining -= read as u64;
+            }
+            Ok(())
+        });
+        if let Err(err) = streamed {
+            self.file.set_len(position as u64)?;
+            return Err(err.into());
         }
 
-        self.file.write(&bytes).unwrap();
+        // Appends ignore the write offset, so the checksum goes in through a second handle. A
+        // crash before it does leaves a record that fails its checksum at the end of the file,
+        // which loading cuts off.
+        let crc = digest.finalize();
+        let checksummed = OpenOptions::new()
+            .write(true)
+            .open(self.file_path(self.active_id))
+            .and_then(|file| file.write_all_at(&crc.to_be_bytes(), position as u64));
+        if let Err(err) = checksummed {
+            self.file.set_len(position as u64)?;
+            return Err(err.into());
+        }
+        self.sequence = kv.seq;
 
-        let key_entry = KeyEntry::init(
-            self.file_id_counter - 1,
-            timestamp,
-            self.write_position,
-            total_size,
-        );
-        self.key_dir.insert(key.to_string(), key_entry);
+        let total_size = prefix.len() + len as usize;
+        self.cache
+            .lock()
+            .unwrap()
+            .remove(&(namespace, kv.key.clone()));
+        self.stats
+            .record_written(self.active_id, total_size, kv.seq);
+        let key_entry = KeyEntry::init(self.active_id, kv.timestamp, kv.seq, position, total_size);
         self.write_position += total_size;
+        if namespace == KeyValue::DEFAULT_NAMESPACE {
+            self.notify(&[Event::Put {
+                key: kv.key.clone(),
+                seq: kv.seq,
+                value: None,
+            }]);
+        }
+        self.index_key(namespace, kv.key, key_entry);
+        self.merge_after_write();
+
+        Ok(())
+    }
+
+    /// Applies every put and delete in `batch` with a single append to the active file, so
+    /// other users of a shared store see either all of the batch or none of it. A batch cut
+    /// short by a crash is dropped when the store is next opened. Nothing is written if any put
+    /// breaks the size limits.
+    pub fn apply_batch(&mut self, batch: &WriteBatch) -> Result<(), Error> {
+        for op in &batch.ops {
+            if let BatchOp::Put(key, value) = op {
+                self.check_size(key, value.len() as u64)?;
+            }
+        }
+
+        // Whether each key the batch touches exists once the earlier operations have applied.
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        let mut indexed = HashSet::new();
+        let mut records = vec![];
+
+        for op in &batch.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    records.push(self.put_record(KeyValue::DEFAULT_NAMESPACE, key, value)?);
+                    records.extend(self.index_records(key, Some(value), &mut indexed));
+                    exists.insert(key, true);
+                }
+                BatchOp::Delete(key) => {
+                    let found = exists.get(key.as_str()).copied();
+                    if !found.unwrap_or_else(|| self.contains(key)) {
+                        continue;
+                    }
+                    self.sequence += 1;
+                    records.push(KeyValue::tombstone(now(), self.sequence, key.clone()));
+                    records.extend(self.index_records(key, None, &mut indexed));
+                    exists.insert(key, false);
+                }
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        records.extend(self.stale_index_records());
+        self.write_records(records)
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        match self.key_dir.find(&key.to_string()) {
-            Some(key_entry) => {
-                let file_path = Path::new(&self.base_dir).join(format!("{}.db", key_entry.file_id));
-                let mut file = File::open(file_path).unwrap();
-                file.seek(SeekFrom::Start(key_entry.position as u64))
-                    .unwrap();
+    /// Appends `records` in one write and makes them the live records of their keys, bringing
+    /// the secondary indexes their index records belong to in line. If the write fails, the
+    /// sequence numbers the records took are handed out again.
+    fn write_records(&mut self, records: Vec<KeyValue>) -> Result<(), Error> {
+        // Index values are decoded before anything is written, so one that fails to decode
+        // leaves the store as it was.
+        let written = self
+            .index_values(&records)
+            .and_then(|values| Ok((values, self.append_all(&records)?)));
+        let (index_values, key_entries) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                if let Some(first) = records.iter().map(|kv| kv.seq).min() {
+                    self.sequence = self.sequence.min(first - 1);
+                }
+                return Err(err);
+            }
+        };
+        let events = match self.watchers.is_empty() {
+            true => vec![],
+            false => Self::events(&records),
+        };
+        for ((kv, key_entry), values) in records.into_iter().zip(key_entries).zip(index_values) {
+            let index = self
+                .indexes
+                .values_mut()
+                .find(|index| index.id == kv.namespace);
+            if let (Some(index), Some(values)) = (index, values) {
+                index.update(&kv.key, values);
+            }
 
-                let mut data_buf = vec![0u8; key_entry.total_size];
-                file.read(&mut data_buf).unwrap();
+            if kv.is_tombstone() {
+                self.stats
+                    .add_tombstone(key_entry.file_id, key_entry.total_size);
+                self.unindex_key(kv.namespace, &kv.key);
+            } else {
+                self.index_key(kv.namespace, kv.key, key_entry);
+            }
+        }
+        self.notify(&events);
+        self.merge_after_write();
 
-                let kv = KeyValue::from_bytes(&data_buf).unwrap();
+        Ok(())
+    }
 
-                let mut bytes = vec![];
+    /// The values each record of a secondary index puts its key under, none for a delete, and
+    /// `None` for the records of any other namespace.
+    fn index_values(&self, records: &[KeyValue]) -> Result<Vec<Option<Vec<Vec<u8>>>>, Error> {
+        records
+            .iter()
+            .map(|kv| {
+                if !self.indexes.values().any(|index| index.id == kv.namespace) {
+                    return Ok(None);
+                }
+                match kv.is_tombstone() {
+                    true => Ok(Some(vec![])),
+                    false => index::decode_values(&kv.value).map(Some),
+                }
+            })
+            .collect()
+    }
 
-                let timestamp_bytes = kv.timestamp.to_be_bytes();
-                let key_bytes = kv.key.as_bytes();
-                let value_bytes = kv.value.as_bytes();
+    /// Events for the changes `records` make to the default namespace.
+    fn events(records: &[KeyValue]) -> Vec<Event> {
+        records
+            .iter()
+            .filter(|kv| kv.namespace == KeyValue::DEFAULT_NAMESPACE)
+            .map(|kv| match kv.is_tombstone() {
+                true => Event::Delete {
+                    key: kv.key.clone(),
+                    seq: kv.seq,
+                },
+                false => Event::Put {
+                    key: kv.key.clone(),
+                    seq: kv.seq,
+                    value: compression::decode(kv.flags, &kv.value).ok(),
+                },
+            })
+            .collect()
+    }
 
-                bytes.extend(&timestamp_bytes);
-                bytes.extend(key_bytes);
-                bytes.extend(value_bytes);
+    /// Passes `events` on to every watcher, forgetting those whose receiver is gone.
+    fn notify(&mut self, events: &[Event]) {
+        if events.is_empty() {
+            return;
+        }
+        self.watchers
+            .retain_mut(|watcher| events.iter().all(|event| watcher.send(event)));
+    }
 
-                let checksum = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&bytes);
+    /// Subscribes to the changes to keys of the default namespace starting with `prefix`. An
+    /// event is sent for every key a `set`, `delete` or batch writes, once it is written, in
+    /// sequence order.
+    ///
+    /// Writers never wait for watchers: the receiver buffers up to `watch_buffer` events, and
+    /// once it is full further events are dropped until it has room again, when an
+    /// `Event::Lagged` tells how many were missed. Dropping the receiver ends the subscription
+    /// the next time a watched key changes.
+    pub fn watch(&mut self, prefix: &str) -> Receiver<Event> {
+        let (watcher, receiver) = Watcher::new(prefix, self.options.watch_buffer);
+        self.watchers.push(watcher);
+        receiver
+    }
 
-                if kv.crc == checksum {
-                    Some(kv.value)
-                } else {
-                    None
+    /// Index records that bring every secondary index in line with `key` now holding `value`,
+    /// or being deleted for `None`. `touched` gathers the keys given index records earlier in
+    /// the same write, which may have to be taken out of an index again.
+    fn index_records(
+        &mut self,
+        key: &str,
+        value: Option<&[u8]>,
+        touched: &mut HashSet<String>,
+    ) -> Vec<KeyValue> {
+        let mut updates = vec![];
+        for (name, index) in &self.indexes {
+            let Some(extractor) = self.extractors.get(name) else {
+                continue;
+            };
+            let values = value.map(|value| extractor.extract(value));
+            match values {
+                Some(values) if !values.is_empty() => updates.push((index.id, Some(values))),
+                _ if index.contains_key(key) || touched.contains(key) => {
+                    updates.push((index.id, None))
                 }
+                _ => {}
             }
-            None => None,
         }
+        if !updates.is_empty() {
+            touched.insert(key.to_string());
+        }
+
+        updates
+            .into_iter()
+            .map(|(id, values)| {
+                self.sequence += 1;
+                let kv = match values {
+                    Some(values) => KeyValue::with_flags(
+                        now(),
+                        self.sequence,
+                        0,
+                        key.to_string(),
+                        index::encode_values(&values),
+                    ),
+                    None => KeyValue::tombstone(now(), self.sequence, key.to_string()),
+                };
+                kv.in_namespace(id)
+            })
+            .collect()
     }
 
-    pub fn delete(&mut self, key: &str) {
-        self.key_dir.delete(&key.to_string());
-        self.tombstone.push_back(key.to_string());
+    /// Builds the record for writing `value` under the next sequence number, compressed if the
+    /// options call for it.
+    fn put_record(&mut self, namespace: u32, key: &str, value: &[u8]) -> Result<KeyValue, Error> {
+        let (flags, stored) = self
+            .options
+            .compression
+            .encode(value, self.options.compression_threshold)?;
+
+        self.sequence += 1;
+        Ok(
+            KeyValue::with_flags(now(), self.sequence, flags, key.to_string(), stored)
+                .in_namespace(namespace),
+        )
     }
 
-    pub fn merge(&mut self) -> Result<(), Error> {
-        let mut file_paths = self.files_in_dir();
-        assert!(
-            file_paths.len() > 1,
-            "you can't merge on active file alone."
-        );
+    /// Appends `records` to the active file in one write. If it fails, whatever part of it made
+    /// it to disk is cut off again and nothing is accounted for.
+    fn append_all(&mut self, records: &[KeyValue]) -> Result<Vec<KeyEntry>, Error> {
+        if self.file.metadata()?.len() > self.options.max_file_size {
+            self.rotate()?;
+        }
 
-        if let Some(active_file) = file_paths.pop() {
-            let active_file_path = Path::new(&active_file);
-            self.file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .open(&active_file_path)
-                .unwrap();
+        let mut bytes = vec![];
+        let mut marker_size = 0;
+        if records.len() > 1 {
+            let marker = KeyValue::with_flags(now(), 0, 0, records.len().to_string(), vec![])
+                .in_namespace(Self::BATCH_MARKER);
+            bytes = marker.to_bytes(self.active_format, self.cipher.as_deref())?;
+            marker_size = bytes.len();
         }
 
-        for path in &file_paths {
-            let file_path = Path::new(path);
-            let mut file = OpenOptions::new().read(true).open(&file_path)?;
+        let mut sizes = vec![];
+        for kv in records {
+            let record = kv.to_bytes(self.active_format, self.cipher.as_deref())?;
+            sizes.push(record.len());
+            bytes.extend_from_slice(&record);
+        }
+        if let Err(err) = self.file.write_all(&bytes) {
+            self.file.set_len(self.write_position as u64)?;
+            return Err(err.into());
+        }
 
-            // Open a temporary file for merged output
-            let temp_file_path = file_path.with_extension("tmp");
-            let mut temp_file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&temp_file_path)?;
+        if marker_size > 0 {
+            self.stats.record_marker(self.active_id, marker_size);
+            self.write_position += marker_size;
+        }
+        let mut key_entries = vec![];
+        let mut cache = self.cache.lock().unwrap();
+        for (kv, total_size) in records.iter().zip(sizes) {
+            // Whatever is written supersedes the cached value.
+            cache.remove(&(kv.namespace, kv.key.clone()));
 
-            let mut position = 0;
+            self.stats
+                .record_written(self.active_id, total_size, kv.seq);
+            key_entries.push(
+                KeyEntry::init(
+                    self.active_id,
+                    kv.timestamp,
+                    kv.seq,
+                    self.write_position,
+                    total_size,
+                )
+                .encrypted(self.cipher.is_some()),
+            );
+            self.write_position += total_size;
+        }
 
-            loop {
-                file.seek(SeekFrom::Start(position as u64))?;
-                let mut header_buf = [0u8; Self::HEADER_SIZE];
-                if file.read(&mut header_buf)? == 0 {
-                    break; // End of file
-                }
+        Ok(key_entries)
+    }
 
-                let (_, _, key_size, value_size) = KeyValue::decode_header(&header_buf)?;
+    /// Seals the active file and starts writing to a new one.
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.open_active(self.file_id_counter)?;
+        self.file_id_counter += 1;
+        self.stats.add_file(self.active_id);
 
-                let mut key_buf = vec![0u8; key_size];
-                file.read_exact(&mut key_buf)?;
-                let key = String::from_utf8(key_buf)?;
+        Ok(())
+    }
 
-                let mut value_buf = vec![0u8; value_size];
-                file.read_exact(&mut value_buf)?;
+    /// Makes `file_id` the active file, starting it with a header in the configured format and
+    /// recording it as the active one if it is new.
+    fn open_active(&mut self, file_id: u32) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(self.file_path(file_id))?;
 
-                let total_size = Self::HEADER_SIZE + key_size + value_size;
+        let format = if file.metadata()?.len() == 0 {
+            let format = FileFormat::V1(self.options.checksum);
+            file.write_all(&format.header())?;
+            write_active(&self.base_dir, file_id)?;
+            self.write_position = format.header_size();
+            format
+        } else {
+            FileFormat::read_from(&file)?
+        };
 
-                if self.tombstone.contains(&key) {
-                    // Skip this key-value pair if it's in the tombstone
-                    position += total_size;
-                    continue;
-                }
+        self.active_id = file_id;
+        self.file = file;
+        self.active_format = format;
+        self.formats.insert(file_id, format);
 
-                // Write valid key-value pair to the temporary file
-                temp_file.write_all(&header_buf)?;
-                temp_file.write_all(key.as_bytes())?;
-                temp_file.write_all(&value_buf)?;
+        Ok(())
+    }
 
-                position += total_size;
+    /// The keys of `namespace`, if it has any.
+    fn key_dir_of(&self, namespace: u32) -> Option<&RBTree<String, KeyEntry>> {
+        match namespace {
+            KeyValue::DEFAULT_NAMESPACE => Some(&*self.key_dir),
+            id => self.namespaces.get(&id),
+        }
+    }
+
+    fn key_dir_mut(&mut self, namespace: u32) -> &mut RBTree<String, KeyEntry> {
+        match namespace {
+            // Snapshots holding on to the keys keep them as they were.
+            KeyValue::DEFAULT_NAMESPACE => Arc::make_mut(&mut self.key_dir),
+            id => self.namespaces.entry(id).or_default(),
+        }
+    }
+
+    /// Every key directory: the default namespace's, the named namespaces' and the registry.
+    fn key_dirs(&self) -> impl Iterator<Item = &RBTree<String, KeyEntry>> {
+        std::iter::once(&*self.key_dir).chain(self.namespaces.values())
+    }
+
+    /// Makes `key_entry` the live record of `key` in `namespace`, keeping the stats in step.
+    fn index_key(&mut self, namespace: u32, key: String, key_entry: KeyEntry) {
+        self.unindex_key(namespace, &key);
+        if self.is_internal(namespace) {
+            self.stats.add_internal(&key_entry);
+        } else {
+            let value_size = Self::value_size(namespace, &key, &key_entry);
+            self.stats.add_live(key.len(), value_size, &key_entry);
+        }
+        self.key_dir_mut(namespace).insert(key, key_entry);
+    }
+
+    fn unindex_key(&mut self, namespace: u32, key: &String) {
+        let old_entry = self
+            .key_dir_of(namespace)
+            .and_then(|key_dir| key_dir.find(key))
+            .copied();
+        if let Some(old_entry) = old_entry {
+            if self.is_internal(namespace) {
+                self.stats.remove_internal(&old_entry);
+            } else {
+                let value_size = Self::value_size(namespace, key, &old_entry);
+                self.stats.remove_live(key.len(), value_size, &old_entry);
             }
+            self.key_dir_mut(namespace).delete(key);
+        }
+    }
+
+    /// Whether the keys of `namespace` are the store's own bookkeeping rather than anyone's
+    /// data, so they are left out of the key counts. Index namespaces only count as such once
+    /// the registry has been loaded.
+    fn is_internal(&self, namespace: u32) -> bool {
+        namespace >= Self::HISTORY_START || self.indexes.values().any(|index| index.id == namespace)
+    }
+
+    fn value_size(namespace: u32, key: &str, key_entry: &KeyEntry) -> usize {
+        let sealing = match key_entry.encrypted {
+            true => KeyValue::SEALING_OVERHEAD,
+            false => 0,
+        };
+        key_entry.total_size
+            - RECORD_HEADER_SIZE
+            - KeyValue::stored_key_size(namespace, key)
+            - sealing
+    }
+
+    /// Summarises key counts and space usage; kept up to date as keys are written.
+    pub fn stats(&self) -> Stats {
+        let cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        let cache_misses = self.cache_misses.load(Ordering::Relaxed);
 
-            // Finalize: replace the active file with the temporary file
-            std::fs::rename(temp_file_path, file_path)?;
+        Stats {
+            io_rate_limit: self.io_limiter.rate(),
+            throttled_ms: self.io_limiter.throttled().as_millis() as u64,
+            throttle_waits: self.io_limiter.waits(),
+            open_files: self.handles.lock().unwrap().len() as u64,
+            cache_bytes: self.cache.lock().unwrap().weight() as u64,
+            cache_hits,
+            cache_misses,
+            cache_hit_rate: match cache_hits + cache_misses {
+                0 => None,
+                lookups => Some(cache_hits as f64 / lookups as f64),
+            },
+            ..self
+                .stats
+                .stats(std::mem::size_of::<RBNode<String, KeyEntry>>())
         }
+    }
 
-        Ok(())
+    /// Changes the bytes-per-second budget of merges, backups and exports, including a merge
+    /// already running; `None` lifts the limit.
+    pub fn set_io_rate_limit(&self, bytes_per_second: Option<u64>) {
+        self.io_limiter.set_rate(bytes_per_second);
+    }
+
+    pub(crate) fn io_limiter(&self) -> &RateLimiter {
+        &self.io_limiter
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.get_ref(key).map(ValueRef::into_vec)
     }
 
-    fn files_in_dir(&mut self) -> Vec<String> {
-        let mut file_paths: Vec<String> = fs::read_dir(&self.base_dir)
+    /// Like `get`, but with `mmap_reads` on, values in sealed files are returned without being
+    /// copied out of the file's memory map.
+    pub fn get_ref(&self, key: &str) -> Option<ValueRef> {
+        self.get_ref_in(KeyValue::DEFAULT_NAMESPACE, key)
+    }
+
+    pub(crate) fn get_ref_in(&self, namespace: u32, key: &str) -> Option<ValueRef> {
+        let key_entry = self.key_dir_of(namespace)?.find(&key.to_string())?;
+        if self.options.value_cache_bytes == 0 {
+            return self
+                .source(key_entry.file_id)?
+                .read(key_entry, self.cipher.as_deref());
+        }
+
+        let cache_key = (namespace, key.to_string());
+        if let Some(value) = self.cache.lock().unwrap().get(&cache_key) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(ValueRef::owned(value.clone()));
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let value = self
+            .source(key_entry.file_id)?
+            .read(key_entry, self.cipher.as_deref())?;
+        let weight = key.len() + value.len();
+        self.cache
+            .lock()
             .unwrap()
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.file_type().unwrap().is_file())
-            .map(|entry| entry.path().display().to_string())
-            .collect();
+            .insert(cache_key, value.to_vec(), weight);
+
+        Some(value)
+    }
+
+    /// Returns a reader over the value of `key` that reads it from its data file a piece at a
+    /// time, see `ValueReader`.
+    pub fn get_reader(&self, key: &str) -> Option<ValueReader> {
+        self.get_reader_in(KeyValue::DEFAULT_NAMESPACE, key)
+    }
+
+    pub(crate) fn get_reader_in(&self, namespace: u32, key: &str) -> Option<ValueReader> {
+        let key_entry = self.key_dir_of(namespace)?.find(&key.to_string())?;
+        let file = match self.open_file(key_entry.file_id) {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("failed to open {}.db: {}", key_entry.file_id, err);
+                return None;
+            }
+        };
+
+        let stored_key_size = KeyValue::stored_key_size(namespace, key);
+        let mut prefix = vec![0u8; RECORD_HEADER_SIZE + stored_key_size];
+        file.read_exact_at(&mut prefix, key_entry.position as u64)
+            .ok()?;
+        let (crc, _, _, flags, key_size, value_size) = KeyValue::decode_header(&prefix).ok()?;
+        if flags & (KeyValue::COMPRESSION | KeyValue::ENCRYPTED) != 0 || key_size != stored_key_size
+        {
+            return self.get_ref_in(namespace, key).map(ValueReader::buffered);
+        }
 
-        file_paths.sort();
+        let format = self.formats[&key_entry.file_id];
+        let mut digest = format.start_checksum(&prefix);
+        digest.update(&prefix[RECORD_HEADER_SIZE..]);
+        let start = (key_entry.position + prefix.len()) as u64;
 
-        file_paths
+        Some(ValueReader::file(
+            file,
+            start,
+            value_size as u64,
+            digest,
+            crc,
+        ))
     }
 
-    fn init_key_dir(&mut self) -> Result<(), Error> {
-        println!("****----------initialising the database----------****");
+    /// Where to read the records of `file_id` from: its memory map when `mmap_reads` is on and
+    /// the file is sealed, otherwise the file itself. Returns `None`, logging why, if the file
+    /// cannot be opened.
+    fn source(&self, file_id: u32) -> Option<Source> {
+        let format = self.formats[&file_id];
+        if self.options.mmap_reads && file_id != self.active_id {
+            match self.map(file_id) {
+                Ok(map) => return Some(Source::Mapped(map, format)),
+                Err(err) => log::warn!("failed to map {}.db: {}", file_id, err),
+            }
+        }
+
+        match self.open_file(file_id) {
+            Ok(file) => Some(Source::File(file, format)),
+            Err(err) => {
+                log::error!("failed to open {}.db: {}", file_id, err);
+                None
+            }
+        }
+    }
+
+    /// A read handle for `file_id` from the pool, opening the file if it is not pooled yet.
+    fn open_file(&self, file_id: u32) -> Result<Arc<File>, Error> {
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(file) = handles.get(&file_id) {
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(File::open(self.file_path(file_id))?);
+        handles.insert(file_id, file.clone(), 1);
+
+        Ok(file)
+    }
+
+    fn map(&self, file_id: u32) -> Result<Arc<Mmap>, Error> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(map) = maps.get(&file_id) {
+            return Ok(map.clone());
+        }
+
+        let file = File::open(self.file_path(file_id))?;
+        // SAFETY: sealed data files are never written again; merge only ever removes them,
+        // which leaves existing mappings intact.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        maps.insert(file_id, map.clone(), 1);
+
+        Ok(map)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.contains_in(KeyValue::DEFAULT_NAMESPACE, key)
+    }
+
+    pub(crate) fn contains_in(&self, namespace: u32, key: &str) -> bool {
+        self.key_dir_of(namespace)
+            .is_some_and(|key_dir| key_dir.find(&key.to_string()).is_some())
+    }
+
+    /// Returns every live key in `[start, end)` with its value, in key order.
+    pub fn range(&self, start: &str, end: &str) -> Vec<(String, Vec<u8>)> {
+        self.scan_from(start)
+            .take_while(|(key, _)| key.as_str() < end)
+            .collect()
+    }
+
+    /// Lazily yields every live key starting with `prefix` and its value, in key order.
+    pub fn scan<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (String, Vec<u8>)> + 'a {
+        self.scan_from(prefix)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
 
-        let file_paths = self.files_in_dir();
-        self.file_id_counter = file_paths.len() as u32;
+    /// Lazily yields every live key from `start` onwards and its value, in key order.
+    pub fn scan_from(&self, start: &str) -> impl Iterator<Item = (String, Vec<u8>)> + '_ {
+        self.scan_from_in(KeyValue::DEFAULT_NAMESPACE, start)
+    }
+
+    pub(crate) fn scan_from_in(
+        &self,
+        namespace: u32,
+        start: &str,
+    ) -> impl Iterator<Item = (String, Vec<u8>)> + '_ {
+        let mut sources: HashMap<u32, Source> = HashMap::new();
+        let start = start.to_string();
+
+        self.key_dir_of(namespace)
+            .into_iter()
+            .flat_map(move |key_dir| key_dir.iter_from(&start))
+            .filter_map(move |node| {
+                let source = match sources.entry(node.value.file_id) {
+                    Entry::Occupied(slot) => slot.into_mut(),
+                    Entry::Vacant(slot) => slot.insert(self.source(node.value.file_id)?),
+                };
+                let value = source.read(&node.value, self.cipher.as_deref())?;
+                Some((node.key.clone(), value.into_vec()))
+            })
+    }
+
+    /// Live keys starting with `prefix`, in order.
+    pub fn keys<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.keys_in(KeyValue::DEFAULT_NAMESPACE, prefix)
+    }
+
+    /// Live keys from `start` onwards, in order.
+    pub fn keys_from(&self, start: &str) -> impl Iterator<Item = &str> + '_ {
+        self.key_dir
+            .iter_from(&start.to_string())
+            .map(|node| node.key.as_str())
+    }
+
+    pub(crate) fn keys_in<'a>(
+        &'a self,
+        namespace: u32,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.key_dir_of(namespace)
+            .into_iter()
+            .flat_map(move |key_dir| key_dir.iter_from(&prefix.to_string()))
+            .map(|node| node.key.as_str())
+            .take_while(move |key| key.starts_with(prefix))
+    }
+
+    /// A handle on the keys of the namespace called `name`, which is created by the first
+    /// write to it. Keys in different namespaces are independent of each other and of the
+    /// keys outside any namespace.
+    pub fn namespace(&mut self, name: &str) -> Namespace<'_> {
+        let id = self.namespace_ids.get(name).map(|(id, _)| *id);
+        Namespace::new(self, name, id)
+    }
+
+    /// Names of the namespaces that hold or have held keys since they were last dropped, in
+    /// order.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.keys_in(Self::NAMESPACE_REGISTRY, "")
+    }
+
+    /// Drops the namespace called `name` with all of its keys, by writing a single record.
+    /// Returns `false` if there is no such namespace.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<bool, Error> {
+        let Some((id, _)) = self.namespace_ids.get(name).copied() else {
+            return Ok(false);
+        };
+        self.unregister(Self::NAMESPACE_REGISTRY, name, id)?;
+        self.namespace_ids.remove(name);
+
+        Ok(true)
+    }
 
-        for (id, file) in file_paths.iter().enumerate() {
-            self.write_position = 0;
-            self.file = File::open(&file)?;
-            self.load_file(id as u32)?;
+    /// Id of the namespace called `name`, writing the record that creates it if it does not
+    /// exist yet.
+    pub(crate) fn create_namespace(&mut self, name: &str) -> Result<u32, Error> {
+        if let Some((id, _)) = self.namespace_ids.get(name) {
+            return Ok(*id);
         }
 
-        println!("****----------initialisation complete----------****");
+        let records = self.registry_records(Self::NAMESPACE_REGISTRY, name)?;
+        let id = u32::from_be_bytes(records[0].value[..].try_into()?);
+        let seq = records[0].seq;
+        self.write_records(records)?;
+        self.namespace_ids.insert(name.to_string(), (id, seq));
+
+        Ok(id)
+    }
+
+    /// Indexes the keys of the default namespace under whatever `extractor` returns for their
+    /// values, so they can be looked up by it through `index`. The index is kept up to date by
+    /// every write and stored along with the keys; the first registration under `name` indexes
+    /// the keys already in the store.
+    ///
+    /// Indexes are registered again every time the store is opened, before `init`, which loads
+    /// them. An index left unregistered goes stale with the next write, which it misses: `index`
+    /// no longer returns it, and registering it again rebuilds it from the keys in the store.
+    pub fn register_index(
+        &mut self,
+        name: &str,
+        extractor: impl Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        self.extractors
+            .insert(name.to_string(), Extractor::new(extractor));
+        if self.loaded && (!self.indexes.contains_key(name) || self.is_stale(name)) {
+            self.build_index(name)?;
+        }
 
         Ok(())
     }
 
-    fn load_file(&mut self, id: u32) -> Result<(), Error> {
-        loop {
-            let mut header_buf = [0u8; Self::HEADER_SIZE];
-            self.file.read(&mut header_buf)?;
-            if header_buf == [0u8; Self::HEADER_SIZE] {
-                break;
+    /// The secondary index called `name`, if there is one and it is up to date.
+    pub fn index(&self, name: &str) -> Option<Index<'_>> {
+        if self.is_stale(name) {
+            return None;
+        }
+        Some(Index::new(self, self.indexes.get(name)?))
+    }
+
+    /// Drops the secondary index called `name` and its extractor by writing a single record.
+    /// Returns `false` if there is no such index.
+    pub fn drop_index(&mut self, name: &str) -> Result<bool, Error> {
+        self.extractors.remove(name);
+        let Some(id) = self.indexes.get(name).map(|index| index.id) else {
+            return Ok(false);
+        };
+        self.unregister(Self::INDEX_REGISTRY, name, id)?;
+        self.indexes.remove(name);
+
+        Ok(true)
+    }
+
+    /// Creates the index called `name` with its registered extractor, indexing every key in
+    /// the same write. A stale index is brought up to date in place instead.
+    fn build_index(&mut self, name: &str) -> Result<(), Error> {
+        log::info!("building index {}", name);
+
+        let existing = self.indexes.get(name).map(|index| index.id);
+        let (id, mut records) = match existing {
+            Some(id) => (id, vec![]),
+            None => {
+                let records = self.registry_records(Self::INDEX_REGISTRY, name)?;
+                let id = u32::from_be_bytes(records[0].value[..].try_into()?);
+                self.indexes
+                    .insert(name.to_string(), SecondaryIndex::new(id, records[0].seq));
+                (id, records)
+            }
+        };
+        if self.is_stale(name) {
+            self.sequence += 1;
+            records.push(
+                KeyValue::tombstone(now(), self.sequence, name.to_string())
+                    .in_namespace(Self::STALE_INDEXES),
+            );
+        }
+
+        let extractor = self.extractors[name].clone();
+        let mut unindexed: HashSet<String> = self.keys_in(id, "").map(String::from).collect();
+        let keys: Vec<String> = self.keys("").map(String::from).collect();
+        for key in keys {
+            let Some(value) = self.get(&key) else {
+                continue;
+            };
+            let values = extractor.extract(&value);
+            if values.is_empty() {
+                continue;
+            }
+
+            unindexed.remove(&key);
+            self.sequence += 1;
+            let value = index::encode_values(&values);
+            records
+                .push(KeyValue::with_flags(now(), self.sequence, 0, key, value).in_namespace(id));
+        }
+        for key in unindexed {
+            self.sequence += 1;
+            records.push(KeyValue::tombstone(now(), self.sequence, key).in_namespace(id));
+        }
+        if let Err(err) = self.write_records(records) {
+            if existing.is_none() {
+                self.indexes.remove(name);
             }
+            return Err(err);
+        }
 
-            let (_, _, key_size, value_size) = KeyValue::decode_header(&header_buf)?;
+        Ok(())
+    }
 
-            let data_size = key_size + value_size;
-            let total_size = Self::HEADER_SIZE + data_size;
-            let mut data_buf = vec![0u8; data_size];
-            self.file.read(&mut data_buf)?;
+    /// Whether the index called `name` missed a write while it had no extractor registered.
+    fn is_stale(&self, name: &str) -> bool {
+        self.key_dir_of(Self::STALE_INDEXES)
+            .is_some_and(|key_dir| key_dir.find(&name.to_string()).is_some())
+    }
 
-            let full_data = [header_buf.to_vec(), data_buf].concat();
+    /// The records that mark the indexes without a registered extractor stale, for a write
+    /// that changes the default namespace and so goes past them.
+    fn stale_index_records(&mut self) -> Vec<KeyValue> {
+        let names: Vec<String> = self
+            .indexes
+            .keys()
+            .filter(|name| !self.extractors.contains_key(*name) && !self.is_stale(name))
+            .cloned()
+            .collect();
+        names
+            .into_iter()
+            .map(|name| {
+                log::info!("index {} has no extractor registered and goes stale", name);
+                self.sequence += 1;
+                let kv = KeyValue::with_flags(now(), self.sequence, 0, name, vec![]);
+                kv.in_namespace(Self::STALE_INDEXES)
+            })
+            .collect()
+    }
 
-            let kv = KeyValue::from_bytes(&full_data)?;
+    /// The record that enters `name` into `registry` under a new namespace id, as its value,
+    /// followed by the one that moves the namespace counter past that id.
+    fn registry_records(&mut self, registry: u32, name: &str) -> Result<Vec<KeyValue>, Error> {
+        self.check_size(name, 4)?;
 
-            let key_entry = KeyEntry::init(id, kv.timestamp, self.write_position, total_size);
-            self.key_dir.insert(kv.key.clone(), key_entry);
-            self.write_position += total_size;
-            println!("loaded key: {}, value: {}", kv.key, kv.value);
+        let id = self.last_namespace_id + 1;
+        if id >= Self::HISTORY_START {
+            return Err("too many namespaces".into());
         }
+        self.last_namespace_id = id;
+
+        let value = id.to_be_bytes().to_vec();
+        self.sequence += 1;
+        let entry = KeyValue::with_flags(now(), self.sequence, 0, name.to_string(), value.clone())
+            .in_namespace(registry);
+        self.sequence += 1;
+        let counter = KeyValue::with_flags(now(), self.sequence, 0, String::new(), value)
+            .in_namespace(Self::NAMESPACE_COUNTER);
+
+        Ok(vec![entry, counter])
+    }
+
+    /// Takes `name` out of `registry` with a single record, and forgets the keys of the
+    /// namespace `id` it named.
+    fn unregister(&mut self, registry: u32, name: &str, id: u32) -> Result<(), Error> {
+        self.sequence += 1;
+        let kv = KeyValue::tombstone(now(), self.sequence, name.to_string()).in_namespace(registry);
+        self.write_records(vec![kv])?;
+        self.unindex_namespace(id);
 
         Ok(())
     }
-}
 
-impl Write for DiskStorage {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.file.write(buf)
+    /// Forgets every key of namespace `id`.
+    fn unindex_namespace(&mut self, id: u32) {
+        let keys: Vec<String> = match self.namespaces.get(&id) {
+            Some(key_dir) => key_dir.iter().map(|node| node.key.clone()).collect(),
+            None => return,
+        };
+        for key in keys {
+            self.unindex_key(id, &key);
+        }
+        self.namespaces.remove(&id);
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.file.flush()
+    /// Takes a read-only view of the default namespace as of the latest sequence number.
+    ///
+    /// The snapshot shares the key directory with the store, which copies it on the next write
+    /// while the snapshot is still around, so taking one costs no more than opening every data
+    /// file. It holds those files open, so a later `merge` that rewrites them cannot change what
+    /// it sees.
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        let mut files = HashMap::new();
+        for (id, format) in &self.formats {
+            files.insert(*id, (self.open_file(*id)?, *format));
+        }
+
+        Ok(Snapshot::new(
+            self.sequence,
+            self.key_dir.clone(),
+            files,
+            self.cipher.clone(),
+        ))
+    }
+
+    /// Reads back every write to the default namespace with a sequence number above `seq`,
+    /// deletes included, in the order they were made. Pass the `seq` of the last change
+    /// processed to pick up where it left off.
+    ///
+    /// The changes are read from the data files, and merges drop the records that were
+    /// overwritten since; once a merge has covered any change after `seq`, this fails with
+    /// `DbError::Truncated`, whose `oldest` tells where the remaining history starts.
+    ///
+    /// The iterator keeps its own handles to the files, so it can be read while the store is
+    /// written to, but only yields what was written before the call. A record that cannot be
+    /// read ends it with an error; resuming from the last change it yielded tries it again.
+    pub fn changes_since(
+        &self,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = Result<Change, Error>>, Error> {
+        let oldest = self.history_start;
+        if seq.saturating_add(1) < oldest {
+            return Err(DbError::Truncated { seq, oldest }.into());
+        }
+
+        let mut file_ids: Vec<u32> = self
+            .stats
+            .file_ids()
+            .filter(|id| self.stats.seqs(*id).is_some_and(|seqs| seqs.max > seq))
+            .collect();
+        file_ids.sort_by_key(|id| self.stats.min_seq(*id));
+
+        let mut files = vec![];
+        for id in file_ids {
+            let file = self.open_file(id)?;
+            files.push(ChangeFile {
+                id,
+                end: file.metadata()?.len(),
+                file,
+                format: self.formats[&id],
+            });
+        }
+
+        Ok(Changes::new(seq, files, self.cipher.clone()))
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.delete_in(KeyValue::DEFAULT_NAMESPACE, key)
+    }
+
+    pub(crate) fn delete_in(&mut self, namespace: u32, key: &str) -> Result<(), Error> {
+        if !self.contains_in(namespace, key) {
+            return Ok(());
+        }
+        self.sequence += 1;
+        let kv = KeyValue::tombstone(now(), self.sequence, key.to_string()).in_namespace(namespace);
+        let mut records = vec![kv];
+        if namespace == KeyValue::DEFAULT_NAMESPACE {
+            records.extend(self.index_records(key, None, &mut HashSet::new()));
+            records.extend(self.stale_index_records());
+        }
+        self.write_records(records)
+    }
+
+    /// Merges every sealed data file, waiting for the merge to finish.
+    pub fn merge(&mut self) -> Result<(), Error> {
+        self.finish_merge()?;
+        let sealed: Vec<u32> = self
+            .file_ids()?
+            .into_iter()
+            .filter(|id| *id != self.active_id)
+            .collect();
+
+        self.merge_files(&sealed)
+    }
+
+    /// Merges the given sealed files, waiting for the merge (and any merge already running) to
+    /// finish. The active file is never merged.
+    pub fn merge_files(&mut self, file_ids: &[u32]) -> Result<(), Error> {
+        self.finish_merge()?;
+        self.start_merge(file_ids)?;
+        self.finish_merge()
+    }
+
+    /// Starts merging the given sealed files on a background thread and returns at once.
+    ///
+    /// The worker copies the records that are live now into a new data file; reads and writes
+    /// carry on meanwhile. Once it is done, `poll_merge` or `finish_merge` points each copied
+    /// key at its new location, unless the key was overwritten or deleted in the meantime, and
+    /// removes the input files. Returns `false` if nothing was started because another merge
+    /// is still running or no sealed file was given, and fails with `DbError::Locked` if
+    /// another open store holds the directory.
+    pub fn start_merge(&mut self, file_ids: &[u32]) -> Result<bool, Error> {
+        self.start_merge_with(file_ids, None)
+    }
+
+    /// Starts a merge like `start_merge`. With `reencode` set, every record copied is rewritten
+    /// under the given cipher, or unencrypted for `None`, instead of being copied as is.
+    fn start_merge_with(
+        &mut self,
+        file_ids: &[u32],
+        reencode: Option<Option<Arc<Cipher>>>,
+    ) -> Result<bool, Error> {
+        self.poll_merge()?;
+        if self.merge_job.is_some() {
+            return Ok(false);
+        }
+        self.require_lock()?;
+
+        let file_ids: Vec<u32> = file_ids
+            .iter()
+            .copied()
+            .filter(|id| *id != self.active_id && self.stats.file(*id).is_some())
+            .collect();
+        if file_ids.is_empty() {
+            return Ok(false);
+        }
+
+        let live = self
+            .key_dirs()
+            .flat_map(|key_dir| key_dir.iter())
+            .filter(|node| file_ids.contains(&node.value.file_id))
+            .map(|node| (node.value.file_id, node.value.position))
+            .collect();
+
+        // A tombstone can only be dropped once no file outside this merge could still hold an
+        // older record for its key.
+        let drop_below = self
+            .stats
+            .file_ids()
+            .filter(|id| !file_ids.contains(id))
+            .filter_map(|id| self.stats.min_seq(id))
+            .min()
+            .unwrap_or(u64::MAX);
+
+        // Whatever the merge drops of its files is gone from the history of changes, and what it
+        // keeps no longer comes in the order it was written.
+        let history_start = file_ids
+            .iter()
+            .filter_map(|id| self.stats.seqs(*id))
+            .map(|seqs| seqs.max + 1)
+            .fold(self.history_start, u64::max);
+
+        let merged_id = self.file_id_counter;
+        self.file_id_counter += 1;
+
+        let plan = MergePlan {
+            base_dir: PathBuf::from(&self.base_dir),
+            file_ids,
+            merged_id,
+            format: FileFormat::V1(self.options.checksum),
+            live,
+            drop_below,
+            history_start,
+            limiter: self.io_limiter.clone(),
+            cipher: self.cipher.clone(),
+            reencode,
+        };
+        self.merge_job = Some(plan.spawn());
+
+        Ok(true)
+    }
+
+    /// Fails unless this store holds the lock on its directory.
+    fn require_lock(&self) -> Result<(), DbError> {
+        match self.lock {
+            Some(_) => Ok(()),
+            None => Err(DbError::Locked {
+                dir: self.base_dir.clone(),
+            }),
+        }
+    }
+
+    /// Whether a background merge is running or waiting to be applied.
+    pub fn is_merging(&self) -> bool {
+        self.merge_job.is_some()
+    }
+
+    /// Applies the background merge if it has finished, without waiting for it.
+    pub fn poll_merge(&mut self) -> Result<(), Error> {
+        match &self.merge_job {
+            Some(job) if job.is_finished() => self.finish_merge(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the background merge, if any, and applies it.
+    pub fn finish_merge(&mut self) -> Result<(), Error> {
+        let Some(job) = self.merge_job.take() else {
+            return Ok(());
+        };
+        let outcome = job.join().map_err(|_| "merge thread panicked")??;
+        self.apply_merge(outcome)
+    }
+
+    /// Points the keys a merge relocated at the merged file and removes the files it merged.
+    fn apply_merge(&mut self, outcome: MergeOutcome) -> Result<(), Error> {
+        if let Some(seqs) = outcome.seqs {
+            self.stats
+                .record_merged(outcome.merged_id, outcome.merged_bytes, seqs);
+        }
+        if outcome.history_size > 0 {
+            self.stats
+                .record_history(outcome.merged_id, outcome.history_size);
+        }
+        if self.stats.file(outcome.merged_id).is_some() {
+            self.formats.insert(outcome.merged_id, outcome.format);
+        }
+        self.history_start = self.history_start.max(outcome.history_start);
+        for size in outcome.tombstones {
+            self.stats.add_tombstone(outcome.merged_id, size);
+        }
+
+        for relocation in outcome.relocations {
+            // Anything written since the merge started lives in the active file, so a key still
+            // pointing at its old location has not been touched.
+            let unchanged = self
+                .key_dir_of(relocation.namespace)
+                .and_then(|key_dir| key_dir.find(&relocation.key))
+                .is_some_and(|entry| {
+                    entry.file_id == relocation.from_file
+                        && entry.position == relocation.from_position
+                });
+            if unchanged {
+                self.index_key(relocation.namespace, relocation.key, relocation.entry);
+            }
+        }
+
+        for id in outcome.file_ids {
+            fs::remove_file(self.file_path(id))?;
+            self.stats.remove_file(id);
+            self.formats.remove(&id);
+            self.maps.lock().unwrap().remove(&id);
+            self.handles.lock().unwrap().remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites every data file under `key`, or without encryption for `None`, by merging them
+    /// all; records written from now on are sealed with `key` too. Plain records in a store that
+    /// was never encrypted are encrypted along the way.
+    ///
+    /// If the process dies part way, opening the store finishes the rekey once the rewritten
+    /// file is complete, and otherwise rolls it back, leaving the store under the old key. Fails
+    /// with `DbError::Locked` if another open store holds the directory.
+    pub fn rekey(&mut self, key: Option<EncryptionKey>) -> Result<(), Error> {
+        let cipher = match &key {
+            Some(key) => Some(Arc::new(Cipher::new(key)?)),
+            None => None,
+        };
+
+        self.require_lock()?;
+        self.finish_merge()?;
+        if self.write_position > self.active_format.header_size() {
+            self.rotate()?;
+        }
+        let sealed: Vec<u32> = self
+            .file_ids()?
+            .into_iter()
+            .filter(|id| *id != self.active_id && self.stats.file(*id).is_some())
+            .collect();
+
+        if !sealed.is_empty() {
+            let dir = PathBuf::from(&self.base_dir);
+            let mut manifest = RekeyManifest {
+                merged_id: self.file_id_counter,
+                replaces: sealed.clone(),
+                committed: false,
+            };
+            manifest.write(&dir)?;
+
+            self.start_merge_with(&sealed, Some(cipher.clone()))?;
+            let Some(job) = self.merge_job.take() else {
+                RekeyManifest::remove(&dir)?;
+                return Err("rekey could not start its merge".into());
+            };
+            let outcome = job.join().map_err(|_| "merge thread panicked")??;
+
+            manifest.committed = true;
+            manifest.write(&dir)?;
+            self.apply_merge(outcome)?;
+            RekeyManifest::remove(&dir)?;
+        }
+
+        self.cipher = cipher;
+        self.options.encryption_key = key;
+
+        Ok(())
+    }
+
+    /// Starts merging whichever sealed files the configured `MergeTriggers` currently select,
+    /// unless a merge is already running.
+    pub fn maybe_merge(&mut self) -> Result<(), Error> {
+        self.poll_merge()?;
+        if self.merge_job.is_some() || self.lock.is_none() {
+            return Ok(());
+        }
+
+        let file_ids = self.merge_candidates();
+        if file_ids.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("merging data files {:?}", file_ids);
+        self.start_merge(&file_ids)?;
+        Ok(())
+    }
+
+    fn merge_after_write(&mut self) {
+        let result = if self.options.merge_triggers.check_after_writes {
+            self.maybe_merge()
+        } else {
+            self.poll_merge()
+        };
+        if let Err(err) = result {
+            log::error!("automatic merge failed: {}", err);
+        }
+    }
+
+    fn merge_candidates(&self) -> Vec<u32> {
+        let triggers = &self.options.merge_triggers;
+        let hour = (now() / 3600 % 24) as u32;
+        if !triggers.in_window(hour) {
+            return vec![];
+        }
+
+        let sealed: Vec<&FileStats> = self
+            .stats
+            .file_ids()
+            .filter(|id| *id != self.active_id)
+            .filter_map(|id| self.stats.file(id))
+            .collect();
+        let dead_bytes = |file: &FileStats| file.total_bytes - file.live_bytes;
+
+        let too_many_files = triggers
+            .max_sealed_files
+            .is_some_and(|limit| sealed.len() > limit);
+        if too_many_files {
+            return sealed.iter().map(|file| file.file_id).collect();
+        }
+
+        let total_dead: u64 = sealed.iter().map(|file| dead_bytes(file)).sum();
+        let too_much_dead = triggers
+            .total_dead_bytes
+            .is_some_and(|limit| total_dead >= limit);
+
+        sealed
+            .iter()
+            .filter(|file| {
+                let dead = dead_bytes(file);
+                let over_ratio = triggers.file_dead_ratio.is_some_and(|ratio| {
+                    file.total_bytes > 0 && dead as f64 / file.total_bytes as f64 >= ratio
+                });
+                over_ratio || (too_much_dead && dead > 0)
+            })
+            .map(|file| file.file_id)
+            .collect()
+    }
+
+    /// Checks the merge triggers of a shared store every `interval` from a background thread,
+    /// so time windows and thresholds are honoured even while no writes arrive. The thread
+    /// exits once the store is dropped.
+    pub fn spawn_merge_thread(
+        store: &Arc<Mutex<DiskStorage>>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let store = Arc::downgrade(store);
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            let result = store.lock().unwrap().maybe_merge();
+            if let Err(err) = result {
+                log::error!("background merge failed: {}", err);
+            }
+        })
+    }
+
+    /// Writes a consistent copy of the store into `dest_dir`, which must not exist yet.
+    ///
+    /// Sealed data files never change, so they are hard-linked (or copied when linking fails);
+    /// the active file is copied only up to the current write position.
+    pub fn checkpoint(&self, dest_dir: &str) -> Result<Manifest, Error> {
+        fs::create_dir(dest_dir)?;
+        self.checkpoint_into(Path::new(dest_dir), &Manifest::default())
+    }
+
+    /// Adds a backup to the incremental chain under `dest_dir`, copying only the data files that
+    /// are new or have changed size since the previous backup in the chain.
+    pub fn checkpoint_incremental(&self, dest_dir: &str) -> Result<Manifest, Error> {
+        fs::create_dir_all(dest_dir)?;
+
+        let chain = backup::chain(Path::new(dest_dir))?;
+        let previous = match chain.last() {
+            Some(dir) => Manifest::read(dir)?,
+            None => Manifest::default(),
+        };
+
+        let dest = Path::new(dest_dir).join(chain.len().to_string());
+        fs::create_dir(&dest)?;
+        self.checkpoint_into(&dest, &previous)
+    }
+
+    fn checkpoint_into(&self, dest: &Path, previous: &Manifest) -> Result<Manifest, Error> {
+        let active_id = self.active_id;
+        let mut manifest = Manifest::default();
+
+        for id in self.file_ids()? {
+            let src = self.file_path(id);
+            let size = if id == active_id {
+                self.write_position as u64
+            } else {
+                fs::metadata(&src)?.len()
+            };
+            manifest.files.push((id, size));
+
+            if previous.size_of(id) == Some(size) {
+                continue;
+            }
+
+            let dest = dest.join(format!("{}.db", id));
+            if id == active_id || fs::hard_link(&src, &dest).is_err() {
+                let file = File::open(&src)?.take(size);
+                let mut file = Throttled::new(file, self.io_limiter.clone());
+                std::io::copy(&mut file, &mut File::create(&dest)?)?;
+            }
+        }
+
+        manifest.write(dest)?;
+
+        Ok(manifest)
+    }
+
+    /// Checks the checksum of every record in the data files under `dir`, returning how many
+    /// records were read.
+    pub fn verify(dir: &str) -> Result<usize, Error> {
+        let mut records = 0;
+
+        for id in file_ids_in(dir)? {
+            let bytes = fs::read(Path::new(dir).join(format!("{}.db", id)))?;
+            let format = FileFormat::detect(&bytes)?;
+            let mut position = format.header_size();
+
+            while position < bytes.len() {
+                let corruption = || DbError::Corruption {
+                    file_id: id,
+                    position,
+                };
+                if bytes.len() - position < RECORD_HEADER_SIZE {
+                    return Err(corruption().into());
+                }
+
+                let (_, _, _, _, key_size, value_size) =
+                    KeyValue::decode_header(&bytes[position..])?;
+                let total_size = KeyValue::record_size(key_size, value_size)
+                    .filter(|size| *size <= bytes.len() - position)
+                    .ok_or_else(corruption)?;
+
+                if !format.verify(&bytes[position..position + total_size]) {
+                    return Err(corruption().into());
+                }
+
+                records += 1;
+                position += total_size;
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Replaces the store at `base_dir` with the backup in `backup_dir`.
+    ///
+    /// `backup_dir` may be a single backup or an incremental chain, which is replayed oldest
+    /// first. The result is checked against the last manifest and every record's checksum is
+    /// verified before it is swapped in. Fails with `DbError::Locked` while the store is open,
+    /// as writes made meanwhile would go to the files swapped out.
+    pub fn restore(backup_dir: &str, base_dir: Option<String>) -> Result<(), Error> {
+        let chain = backup::chain(Path::new(backup_dir))?;
+        if chain.is_empty() {
+            return Err(format!("no backup found in {}", backup_dir).into());
+        }
+
+        let base_dir = base_dir.unwrap_or("db".to_string());
+        // Held until the old files are gone, so the store cannot be opened in between.
+        let _lock = match Path::new(&base_dir).exists() {
+            true => Some(lock_dir(&base_dir)?.ok_or_else(|| DbError::Locked {
+                dir: base_dir.clone(),
+            })?),
+            false => None,
+        };
+        let staging_dir = format!("{}.restoring", base_dir);
+        let old_dir = format!("{}.old", base_dir);
+
+        if Path::new(&staging_dir).exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir(&staging_dir)?;
+
+        let mut manifest = Manifest::default();
+        for dir in &chain {
+            manifest = Manifest::read(dir)?;
+            for id in file_ids_in(dir.to_str().ok_or("invalid backup path")?)? {
+                let name = format!("{}.db", id);
+                fs::copy(dir.join(&name), Path::new(&staging_dir).join(&name))?;
+            }
+        }
+
+        if let Err(err) = Self::check_staged(&staging_dir, &manifest) {
+            fs::remove_dir_all(&staging_dir)?;
+            return Err(err);
+        }
+
+        if Path::new(&base_dir).exists() {
+            fs::rename(&base_dir, &old_dir)?;
+        }
+        fs::rename(&staging_dir, &base_dir)?;
+        if Path::new(&old_dir).exists() {
+            fs::remove_dir_all(&old_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops staged files that were merged away before the last backup, then checks the rest
+    /// against `manifest`.
+    fn check_staged(staging_dir: &str, manifest: &Manifest) -> Result<(), Error> {
+        for id in file_ids_in(staging_dir)? {
+            if manifest.size_of(id).is_none() {
+                fs::remove_file(Path::new(staging_dir).join(format!("{}.db", id)))?;
+            }
+        }
+
+        for (id, size) in &manifest.files {
+            let path = Path::new(staging_dir).join(format!("{}.db", id));
+            match fs::metadata(path) {
+                Ok(metadata) if metadata.len() == *size => {}
+                _ => return Err(DbError::IncompleteBackup { file_id: *id }.into()),
+            }
+        }
+
+        // Backups taken before an upgrade hold files in the layout of their version.
+        upgrade::upgrade_dir(staging_dir, Checksum::default())?;
+        Self::verify(staging_dir)?;
+
+        Ok(())
+    }
+
+    fn file_path(&self, file_id: u32) -> PathBuf {
+        Path::new(&self.base_dir).join(format!("{}.db", file_id))
+    }
+
+    fn file_ids(&self) -> Result<Vec<u32>, Error> {
+        file_ids_in(&self.base_dir)
+    }
+
+    fn init_key_dir(&mut self) -> Result<(), Error> {
+        log::info!("initialising the database in {}", self.base_dir);
+
+        // Merged files take ids above the active file's, so the active file is the one
+        // recorded, or the last one in stores that predate the record.
+        let file_ids = self.file_ids()?;
+        let last_id = file_ids.last().copied().unwrap_or(0);
+        let recorded = read_active(&self.base_dir)?;
+        self.active_id = recorded.unwrap_or(last_id);
+        self.file_id_counter = last_id.max(self.active_id) + 1;
+
+        // Files are not loaded in write order once merges have run, so remember the newest
+        // delete of each key to ignore older puts that are loaded after it.
+        let mut deleted = HashMap::new();
+        let mut active_merged = false;
+        let mut active_position = 0;
+        for id in file_ids {
+            self.file = File::open(self.file_path(id))?;
+            let format = FileFormat::read_from(&self.file)?;
+            self.formats.insert(id, format);
+            self.write_position = format.header_size();
+            self.file
+                .seek(SeekFrom::Start(self.write_position as u64))?;
+            self.stats.add_file(id);
+            let active = id == self.active_id;
+            let merged = self.load_file(id, active, &mut deleted)?;
+            if active {
+                active_merged = merged;
+                active_position = self.write_position;
+            }
+        }
+        self.write_position = active_position;
+        self.load_namespaces()?;
+
+        // Writes never go after the records of a merged file, which come from before the
+        // history start, so every file's records from there on come in order.
+        if active_merged {
+            self.active_id = self.file_id_counter;
+            self.file_id_counter += 1;
+        } else if recorded.is_none() && self.lock.is_some() {
+            write_active(&self.base_dir, self.active_id)?;
+        }
+
+        log::info!("initialisation complete");
+
+        Ok(())
+    }
+
+    /// Reads the id of every namespace and index from the registries, and the last id handed
+    /// out from the namespace counter, then forgets the records of dropped ones, including
+    /// those older than the namespace that took over their id. Finally fills in the indexes
+    /// from their records.
+    fn load_namespaces(&mut self) -> Result<(), Error> {
+        for registry in [Self::NAMESPACE_REGISTRY, Self::INDEX_REGISTRY] {
+            let entries: Vec<(String, KeyEntry)> = self
+                .key_dir_of(registry)
+                .map(|key_dir| {
+                    key_dir
+                        .iter()
+                        .map(|node| (node.key.clone(), node.value))
+                        .collect()
+                })
+                .unwrap_or_default();
+            for (name, key_entry) in entries {
+                let value = self.read_entry(&key_entry)?;
+                let id = u32::from_be_bytes(value[..].try_into()?);
+                if registry == Self::NAMESPACE_REGISTRY {
+                    self.namespace_ids.insert(name, (id, key_entry.seq));
+                } else {
+                    let index = SecondaryIndex::new(id, key_entry.seq);
+                    self.indexes.insert(name, index);
+                }
+            }
+        }
+
+        let counter = self
+            .key_dir_of(Self::NAMESPACE_COUNTER)
+            .and_then(|key_dir| key_dir.find(&String::new()))
+            .copied();
+        if let Some(key_entry) = counter {
+            let value = self.read_entry(&key_entry)?;
+            self.last_namespace_id = u32::from_be_bytes(value[..].try_into()?);
+        }
+
+        // Records of index namespaces were counted as keys while loading, before the registry
+        // told them apart.
+        for index in self.indexes.values() {
+            let Some(key_dir) = self.namespaces.get(&index.id) else {
+                continue;
+            };
+            for node in key_dir.iter() {
+                let value_size = Self::value_size(index.id, &node.key, &node.value);
+                self.stats
+                    .remove_live(node.key.len(), value_size, &node.value);
+                self.stats.add_internal(&node.value);
+            }
+        }
+
+        let ids: Vec<u32> = self
+            .namespaces
+            .keys()
+            .copied()
+            .filter(|id| *id < Self::HISTORY_START)
+            .collect();
+        // Stores written before the counter only know of the ids they still hold records of.
+        let last_id = ids
+            .iter()
+            .copied()
+            .chain(self.namespace_ids.values().map(|(id, _)| *id))
+            .chain(self.indexes.values().map(|index| index.id))
+            .max()
+            .unwrap_or(0);
+        self.last_namespace_id = self.last_namespace_id.max(last_id);
+        for id in ids {
+            let created = self
+                .namespace_ids
+                .values()
+                .copied()
+                .chain(self.indexes.values().map(|index| (index.id, index.created)))
+                .find(|(other, _)| *other == id)
+                .map(|(_, seq)| seq);
+            let Some(created) = created else {
+                self.unindex_namespace(id);
+                continue;
+            };
+
+            let stale: Vec<String> = self.namespaces[&id]
+                .iter()
+                .filter(|node| node.value.seq < created)
+                .map(|node| node.key.clone())
+                .collect();
+            for key in stale {
+                self.unindex_key(id, &key);
+            }
+        }
+
+        let names: Vec<String> = self.indexes.keys().cloned().collect();
+        for name in names {
+            let id = self.indexes[&name].id;
+            let entries: Vec<(String, KeyEntry)> = self
+                .key_dir_of(id)
+                .map(|key_dir| {
+                    key_dir
+                        .iter()
+                        .map(|node| (node.key.clone(), node.value))
+                        .collect()
+                })
+                .unwrap_or_default();
+            for (key, key_entry) in entries {
+                let values = index::decode_values(&self.read_entry(&key_entry)?)?;
+                self.indexes.get_mut(&name).unwrap().update(&key, values);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the value `key_entry` points at, failing if the record is corrupt.
+    fn read_entry(&self, key_entry: &KeyEntry) -> Result<ValueRef, DbError> {
+        self.source(key_entry.file_id)
+            .and_then(|source| source.read(key_entry, self.cipher.as_deref()))
+            .ok_or(DbError::Corruption {
+                file_id: key_entry.file_id,
+                position: key_entry.position,
+            })
+    }
+
+    /// Loads the records of file `id`, returning whether a merge wrote it, which its record
+    /// of where the history starts tells.
+    fn load_file(
+        &mut self,
+        id: u32,
+        active: bool,
+        deleted: &mut HashMap<(u32, String), u64>,
+    ) -> Result<bool, Error> {
+        let file_len = self.file.metadata()?.len() as usize;
+        let mut merged = false;
+        // The records of a write whose marker has been read, held back until all of them are.
+        let mut batch: Option<PendingBatch> = None;
+        // Where a record cut short at the end of the file starts.
+        let mut torn = None;
+        loop {
+            let position = self.write_position;
+            let corruption = DbError::Corruption {
+                file_id: id,
+                position,
+            };
+            let mut header_buf = [0u8; RECORD_HEADER_SIZE];
+            match self.file.read(&mut header_buf)? {
+                0 => break,
+                read if read < RECORD_HEADER_SIZE => {
+                    torn = Some(position);
+                    break;
+                }
+                _ => {}
+            }
+
+            let (_, timestamp, seq, flags, key_size, value_size) =
+                KeyValue::decode_header(&header_buf)?;
+
+            let total_size = match KeyValue::record_size(key_size, value_size)
+                .filter(|size| *size <= file_len - position)
+            {
+                Some(size) => size,
+                None if active || batch.is_some() => {
+                    torn = Some(position);
+                    break;
+                }
+                None => return Err(corruption.into()),
+            };
+
+            // The last record of the active file may have been written in pieces, so check it is
+            // whole before trusting it.
+            if active && position + total_size == file_len {
+                let mut record = vec![0u8; total_size];
+                self.file.read_exact_at(&mut record, position as u64)?;
+                if !self.formats[&id].verify(&record) {
+                    torn = Some(position);
+                    break;
+                }
+                self.file
+                    .seek(SeekFrom::Start((position + RECORD_HEADER_SIZE) as u64))?;
+            }
+
+            // Only the key is needed here, so plain values are skipped rather than read into
+            // memory; encrypted records have to be opened whole to get at the key.
+            let kv = if flags & KeyValue::ENCRYPTED != 0 {
+                let mut data_buf = vec![0u8; key_size + value_size];
+                self.file.read_exact(&mut data_buf)?;
+                let full_data = [header_buf.to_vec(), data_buf].concat();
+                let mut kv = KeyValue::from_bytes(&full_data, self.cipher.as_deref())?;
+                // Kept so the key entry leaves the sealing out of the value size.
+                kv.flags |= KeyValue::ENCRYPTED;
+                kv
+            } else {
+                let mut key_buf = vec![0u8; key_size];
+                self.file.read_exact(&mut key_buf)?;
+                self.file.seek(SeekFrom::Current(value_size as i64))?;
+                let (namespace, key) = KeyValue::split_key(flags, &key_buf)?;
+                let flags = flags & !KeyValue::NAMESPACED;
+                KeyValue::with_flags(timestamp, seq, flags, key, vec![]).in_namespace(namespace)
+            };
+            self.write_position += total_size;
+
+            if kv.namespace == Self::HISTORY_START {
+                let mut value = [0u8; 8];
+                if value_size != value.len() {
+                    return Err(corruption.into());
+                }
+                self.file
+                    .read_exact_at(&mut value, (position + total_size - 8) as u64)?;
+                self.history_start = self.history_start.max(u64::from_be_bytes(value));
+                self.stats.record_history(id, total_size);
+                merged = true;
+                continue;
+            }
+
+            if kv.namespace == Self::BATCH_MARKER {
+                if batch.is_some() {
+                    return Err(corruption.into());
+                }
+                batch = Some(PendingBatch {
+                    start: position,
+                    marker_size: total_size,
+                    len: kv.key.parse().map_err(|_| corruption)?,
+                    records: vec![],
+                });
+                continue;
+            }
+
+            let Some(pending) = &mut batch else {
+                self.load_record(id, kv, position, total_size, deleted);
+                continue;
+            };
+            pending.records.push((kv, position, total_size));
+            if pending.records.len() == pending.len {
+                let pending = batch.take().unwrap();
+                self.stats.record_marker(id, pending.marker_size);
+                for (kv, position, total_size) in pending.records {
+                    self.load_record(id, kv, position, total_size, deleted);
+                }
+            }
+        }
+
+        // Only the last write to the active file can have been cut short, by a crash, or still be
+        // under way in another process. Holding the lock rules the latter out, so what made it
+        // to disk is cut off, and the next write does not land after it.
+        if let Some(start) = batch.map(|pending| pending.start).or(torn) {
+            log::warn!(
+                "dropping the write cut short at {}.db position {}",
+                id,
+                start
+            );
+            if active && self.lock.is_some() {
+                OpenOptions::new()
+                    .write(true)
+                    .open(self.file_path(id))?
+                    .set_len(start as u64)?;
+            }
+            self.write_position = start;
+        }
+
+        Ok(merged)
+    }
+
+    /// Makes a record read from file `id` at `position` live, unless a newer record of its key
+    /// has been loaded already.
+    fn load_record(
+        &mut self,
+        id: u32,
+        kv: KeyValue,
+        position: usize,
+        total_size: usize,
+        deleted: &mut HashMap<(u32, String), u64>,
+    ) {
+        self.stats.record_written(id, total_size, kv.seq);
+        self.sequence = self.sequence.max(kv.seq);
+
+        let newer_entry = self
+            .key_dir_of(kv.namespace)
+            .and_then(|key_dir| key_dir.find(&kv.key))
+            .is_some_and(|e| e.seq > kv.seq);
+        let deleted_key = (kv.namespace, kv.key.clone());
+        if kv.is_tombstone() {
+            self.stats.add_tombstone(id, total_size);
+            if !newer_entry {
+                self.unindex_key(kv.namespace, &kv.key);
+            }
+            let seq = deleted.entry(deleted_key).or_insert(kv.seq);
+            *seq = (*seq).max(kv.seq);
+        } else if !newer_entry && deleted.get(&deleted_key).is_none_or(|seq| *seq < kv.seq) {
+            let key_entry = KeyEntry::init(id, kv.timestamp, kv.seq, position, total_size)
+                .encrypted(kv.flags & KeyValue::ENCRYPTED != 0);
+            self.index_key(kv.namespace, kv.key.clone(), key_entry);
+        }
+
+        log::debug!("loaded key: {}", kv.key);
+    }
+}
+
+pub(crate) fn now() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+/// Ids of the `{id}.db` data files in `dir`, oldest first.
+pub(crate) fn file_ids_in(dir: &str) -> Result<Vec<u32>, Error> {
+    let mut file_ids: Vec<u32> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_suffix(".db")?.parse().ok()
+        })
+        .collect();
+
+    file_ids.sort();
+
+    Ok(file_ids)
+}
+
+/// Name of the file in a store directory holding the id of the active data file.
+const ACTIVE_FILE: &str = "ACTIVE";
+/// Name of the file in a store directory that an open store locks.
+const LOCK_FILE: &str = "LOCK";
+
+/// The id of the active data file recorded in `dir`, if there is one.
+fn read_active(dir: &str) -> Result<Option<u32>, Error> {
+    match fs::read_to_string(Path::new(dir).join(ACTIVE_FILE)) {
+        Ok(contents) => Ok(Some(contents.trim().parse()?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Records `id` as the active data file in `dir`, durably.
+fn write_active(dir: &str, id: u32) -> Result<(), Error> {
+    let staged = Path::new(dir).join(format!("{}.tmp", ACTIVE_FILE));
+    fs::write(&staged, format!("{}\n", id))?;
+    File::open(&staged)?.sync_all()?;
+    fs::rename(&staged, Path::new(dir).join(ACTIVE_FILE))?;
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Takes the exclusive lock on the store in `dir`, or returns `None` if another open store
+/// holds it. The lock lasts until the returned file is dropped.
+fn lock_dir(dir: &str) -> Result<Option<File>, Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(dir).join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// The records of a single write read back so far by `load_file`, with where it starts.
+struct PendingBatch {
+    start: usize,
+    marker_size: usize,
+    len: usize,
+    /// Each record with its position and size.
+    records: Vec<(KeyValue, usize, usize)>,
+}
+
+/// The records of one data file, read either through a memory map or with positioned reads.
+enum Source {
+    Mapped(Arc<Mmap>, FileFormat),
+    File(Arc<File>, FileFormat),
+}
+
+impl Source {
+    fn read(&self, key_entry: &KeyEntry, cipher: Option<&Cipher>) -> Option<ValueRef> {
+        match self {
+            Source::File(file, format) => {
+                read_value(file, *format, key_entry, cipher).map(ValueRef::owned)
+            }
+            Source::Mapped(map, format) => {
+                let end = key_entry.position + key_entry.total_size;
+                let record = map.get(key_entry.position..end)?;
+                if !format.verify(record) {
+                    return None;
+                }
+
+                let (_, _, _, flags, key_size, _) = KeyValue::decode_header(record).ok()?;
+                if flags & (KeyValue::COMPRESSION | KeyValue::ENCRYPTED) != 0 {
+                    return decode_value(key_entry, record, cipher).map(ValueRef::owned);
+                }
+                let start = key_entry.position + RECORD_HEADER_SIZE + key_size;
+                Some(ValueRef::mapped(map.clone(), start, end))
+            }
+        }
+    }
+}
+
+/// Reads and checks the record `key_entry` points at with a positioned read, so one handle can
+/// serve concurrent readers without seeking.
+pub(crate) fn read_value(
+    file: &File,
+    format: FileFormat,
+    key_entry: &KeyEntry,
+    cipher: Option<&Cipher>,
+) -> Option<Vec<u8>> {
+    let mut record = vec![0u8; key_entry.total_size];
+    if let Err(err) = file.read_exact_at(&mut record, key_entry.position as u64) {
+        log::error!(
+            "failed to read record in {}.db at position {}: {}",
+            key_entry.file_id,
+            key_entry.position,
+            err
+        );
+        return None;
+    }
+
+    if !format.verify(&record) {
+        return None;
+    }
+    decode_value(key_entry, &record, cipher)
+}
+
+/// Decrypts and decompresses the value of a record whose checksum has been checked.
+fn decode_value(key_entry: &KeyEntry, record: &[u8], cipher: Option<&Cipher>) -> Option<Vec<u8>> {
+    let kv = match KeyValue::from_bytes(record, cipher) {
+        Ok(kv) => kv,
+        Err(err) => {
+            log::error!(
+                "failed to read record in {}.db at position {}: {}",
+                key_entry.file_id,
+                key_entry.position,
+                err
+            );
+            return None;
+        }
+    };
+
+    if kv.flags & KeyValue::COMPRESSION == 0 {
+        Some(kv.value)
+    } else {
+        decompress(key_entry, kv.flags, &kv.value)
+    }
+}
+
+fn decompress(key_entry: &KeyEntry, flags: u8, stored: &[u8]) -> Option<Vec<u8>> {
+    match compression::decode(flags, stored) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            log::error!(
+                "failed to decompress record in {}.db at position {}: {}",
+                key_entry.file_id,
+                key_entry.position,
+                err
+            );
+            None
+        }
+    }
+}
+
+impl Write for DiskStorage {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for DiskStorage {
+    /// Waits for a running merge and applies it, so its input files are not left behind next to
+    /// the merged output.
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_merge() {
+            log::error!("background merge failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::MergeTriggers;
+
+    /// A fresh store in a temporary directory.
+    fn temp_store(name: &str) -> DiskStorage {
+        let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+        store.init().unwrap();
+        store
+    }
+
+    /// A store of three sealed files, two thirds dead, one third dead and all live, returned
+    /// with their ids in that order.
+    fn store_with_dead_records(name: &str) -> (DiskStorage, Vec<u32>) {
+        let mut store = temp_store(name);
+
+        // Three records fill a file, so each line of keys lands in a file of its own.
+        for key in ["a", "b", "c", "d", "e", "f", "a", "b", "d", "x"] {
+            store.set(key, b"value").unwrap();
+        }
+        let sealed: Vec<u32> = store
+            .stats
+            .file_ids()
+            .filter(|id| *id != store.active_id)
+            .collect();
+        assert_eq!(sealed.len(), 3);
+
+        (store, sealed)
+    }
+
+    fn candidates(store: &mut DiskStorage, triggers: MergeTriggers) -> Vec<u32> {
+        store.options.merge_triggers = triggers;
+        store.merge_candidates()
+    }
+
+    #[test]
+    fn files_are_picked_by_their_own_dead_ratio() {
+        let (mut store, sealed) = store_with_dead_records("candidates-ratio");
+        let ratio = |ratio| MergeTriggers {
+            file_dead_ratio: Some(ratio),
+            ..MergeTriggers::disabled()
+        };
+
+        assert_eq!(candidates(&mut store, ratio(0.5)), [sealed[0]]);
+        assert_eq!(candidates(&mut store, ratio(0.3)), [sealed[0], sealed[1]]);
+        assert!(candidates(&mut store, ratio(0.9)).is_empty());
+        assert!(candidates(&mut store, MergeTriggers::disabled()).is_empty());
+    }
+
+    #[test]
+    fn total_dead_bytes_pick_every_file_with_dead_records() {
+        let (mut store, sealed) = store_with_dead_records("candidates-total");
+        let dead: u64 = sealed
+            .iter()
+            .filter_map(|id| store.stats.file(*id))
+            .map(|file| file.total_bytes - file.live_bytes)
+            .sum();
+        let total = |bytes| MergeTriggers {
+            total_dead_bytes: Some(bytes),
+            ..MergeTriggers::disabled()
+        };
+
+        assert_eq!(candidates(&mut store, total(dead)), [sealed[0], sealed[1]]);
+        assert!(candidates(&mut store, total(dead + 1)).is_empty());
+    }
+
+    #[test]
+    fn too_many_sealed_files_pick_them_all() {
+        let (mut store, sealed) = store_with_dead_records("candidates-count");
+        let count = |files| MergeTriggers {
+            max_sealed_files: Some(files),
+            ..MergeTriggers::disabled()
+        };
+
+        assert_eq!(candidates(&mut store, count(2)), sealed);
+        assert!(candidates(&mut store, count(3)).is_empty());
+    }
+
+    #[test]
+    fn nothing_is_picked_outside_the_window() {
+        let (mut store, sealed) = store_with_dead_records("candidates-window");
+        let hour = (now() / 3600 % 24) as u32;
+        let window = |window| MergeTriggers {
+            max_sealed_files: Some(0),
+            window: Some(window),
+            ..MergeTriggers::disabled()
+        };
+
+        assert!(candidates(&mut store, window(((hour + 2) % 24, (hour + 3) % 24))).is_empty());
+        assert_eq!(
+            candidates(&mut store, window((hour, (hour + 1) % 24))),
+            sealed
+        );
+    }
+
+    #[test]
+    fn window_may_wrap_past_midnight() {
+        let window = |start, end| MergeTriggers {
+            window: Some((start, end)),
+            ..MergeTriggers::disabled()
+        };
+
+        assert!(MergeTriggers::disabled().in_window(13));
+        assert!(window(9, 17).in_window(9));
+        assert!(window(9, 17).in_window(16));
+        assert!(!window(9, 17).in_window(17));
+        assert!(!window(9, 17).in_window(3));
+        assert!(window(22, 4).in_window(23));
+        assert!(window(22, 4).in_window(0));
+        assert!(window(22, 4).in_window(3));
+        assert!(!window(22, 4).in_window(4));
+        assert!(!window(22, 4).in_window(12));
+    }
+
+    #[test]
+    fn namespace_ids_stop_short_of_the_reserved_range() {
+        let mut store = temp_store("namespace-reserved");
+        store.last_namespace_id = DiskStorage::HISTORY_START - 2;
+        store.namespace("last").set("k", b"v").unwrap();
+        assert_eq!(
+            store.namespace_ids["last"].0,
+            DiskStorage::HISTORY_START - 1
+        );
+
+        let err = store.namespace("one-too-many").set("k", b"v").unwrap_err();
+        assert_eq!(err.to_string(), "too many namespaces");
+        assert!(!store.namespace_ids.contains_key("one-too-many"));
+        assert_eq!(store.namespace("last").get("k"), Some(b"v".to_vec()));
     }
 }
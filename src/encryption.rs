@@ -0,0 +1,127 @@
+use crate::Error;
+use std::{fmt::Debug, fs, path::Path};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, Generate, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+
+/// A 256-bit key that data files are encrypted with, set with
+/// `DiskStorageOptions::encryption_key`. Needs the `encryption` feature.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Reads a key file holding either the 32 key bytes or 64 hex digits.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let contents = fs::read(path)?;
+        if let Ok(bytes) = <[u8; 32]>::try_from(contents.as_slice()) {
+            return Ok(EncryptionKey(bytes));
+        }
+
+        let hex = std::str::from_utf8(&contents)
+            .map(str::trim)
+            .map_err(|_| "key file must hold 32 bytes or 64 hex digits")?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err("key file must hold 32 bytes or 64 hex digits".into());
+        }
+
+        let mut bytes = [0u8; 32];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+        }
+
+        Ok(EncryptionKey(bytes))
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Seals and opens records with XChaCha20-Poly1305. Every sealed record carries its own random
+/// 24-byte nonce, followed by the ciphertext and its 16-byte tag.
+pub(crate) struct Cipher {
+    #[cfg(feature = "encryption")]
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Bytes a sealed record adds to its plaintext: the nonce and the tag.
+    pub(crate) const OVERHEAD: usize = 24 + 16;
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn new(key: &EncryptionKey) -> Result<Self, Error> {
+        Ok(Cipher {
+            aead: XChaCha20Poly1305::new(&Key::from(key.0)),
+        })
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn new(_key: &EncryptionKey) -> Result<Self, Error> {
+        Err(
+            "an encryption key was given, but cask-db was built without the encryption feature"
+                .into(),
+        )
+    }
+
+    /// Encrypts `plaintext`, authenticating `aad` along with it.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XNonce::generate();
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, payload)
+            .map_err(|_| "failed to encrypt record")?;
+
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn seal(&self, _aad: &[u8], _plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        unreachable!("a cipher cannot be created without the encryption feature")
+    }
+
+    /// Decrypts what `seal` produced, checking that neither it nor `aad` has been altered.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < Self::OVERHEAD {
+            return Err("encrypted record is cut short".into());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let nonce = XNonce::try_from(nonce).map_err(|_| "invalid nonce")?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        let plaintext = self
+            .aead
+            .decrypt(&nonce, payload)
+            .map_err(|_| "failed to decrypt record; wrong encryption key?")?;
+
+        Ok(plaintext)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn open(&self, _aad: &[u8], _sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        unreachable!("a cipher cannot be created without the encryption feature")
+    }
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cipher(..)")
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+/// Errors raised by the store itself, as opposed to the I/O and decoding errors it passes on.
+#[derive(Debug)]
+pub enum DbError {
+    /// The requested key does not exist.
+    NotFound { key: String },
+    /// A record failed its checksum or was cut short.
+    Corruption { file_id: u32, position: usize },
+    /// A key is longer than `DiskStorageOptions::max_key_size` allows.
+    KeyTooLarge { size: usize, max: usize },
+    /// A value is longer than `DiskStorageOptions::max_value_size` allows.
+    ValueTooLarge { size: u64, max: u64 },
+    /// A backup's manifest lists a data file that none of the backups in its chain contain.
+    IncompleteBackup { file_id: u32 },
+    /// Changes since `seq` were asked for, but merges have removed some of them; the records
+    /// still on disk only go back to `oldest`.
+    Truncated { seq: u64, oldest: u64 },
+    /// The store directory is locked by another open store, so files cannot be rewritten or
+    /// removed under it.
+    Locked { dir: String },
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NotFound { key } => write!(f, "key not found: {}", key),
+            DbError::Corruption { file_id, position } => {
+                write!(
+                    f,
+                    "corrupt record in {}.db at position {}",
+                    file_id, position
+                )
+            }
+            DbError::KeyTooLarge { size, max } => {
+                write!(
+                    f,
+                    "key of {} bytes exceeds the limit of {} bytes",
+                    size, max
+                )
+            }
+            DbError::ValueTooLarge { size, max } => {
+                write!(
+                    f,
+                    "value of {} bytes exceeds the limit of {} bytes",
+                    size, max
+                )
+            }
+            DbError::IncompleteBackup { file_id } => write!(f, "backup is missing {}.db", file_id),
+            DbError::Truncated { seq, oldest } => write!(
+                f,
+                "changes since {} have been merged away; history starts at {}",
+                seq, oldest
+            ),
+            DbError::Locked { dir } => write!(f, "{} is locked by another open store", dir),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
@@ -1,20 +1,30 @@
+use crate::checksum::{Checksum, Digest};
+use crate::encryption::Cipher;
+use crate::positioned::PositionedIo;
 use crate::Error;
-use std::fmt::Display;
+use std::{fmt::Display, fs::File, io::ErrorKind};
+
+/// Size of the header every record starts with: checksum, timestamp, sequence number, flags,
+/// key size and value size.
+pub(crate) const RECORD_HEADER_SIZE: usize = 37;
 
 #[derive(Debug, Clone, Copy)]
 pub struct KeyEntry {
     pub file_id: u32,
-    timestamp: usize,
+    pub timestamp: usize,
+    pub seq: u64,
     pub position: usize,
     pub total_size: usize,
+    /// Whether the record is sealed with the encryption key, which takes up room of its own.
+    pub encrypted: bool,
 }
 
 impl Display for KeyEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "File ID: {}, Timestamp: {}, Position: {}, Total Size: {}",
-            self.file_id, self.timestamp, self.position, self.total_size
+            "File ID: {}, Timestamp: {}, Seq: {}, Position: {}, Total Size: {}",
+            self.file_id, self.timestamp, self.seq, self.position, self.total_size
         )
     }
 }
@@ -22,103 +32,352 @@ impl Display for KeyEntry {
 impl std::error::Error for KeyEntry {}
 
 impl KeyEntry {
-    pub fn init(file_id: u32, timestamp: usize, position: usize, total_size: usize) -> Self {
+    pub fn init(
+        file_id: u32,
+        timestamp: usize,
+        seq: u64,
+        position: usize,
+        total_size: usize,
+    ) -> Self {
         KeyEntry {
             file_id,
             timestamp,
+            seq,
             position,
             total_size,
+            encrypted: false,
+        }
+    }
+
+    /// Marks the record as sealed with the encryption key, or not.
+    pub fn encrypted(mut self, encrypted: bool) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+}
+
+/// How the records of a data file are laid out and checksummed, read from the header at the
+/// start of the file. Files written before data files had a header are rewritten when the store
+/// is opened, see `upgrade`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileFormat {
+    /// Files starting with `MAGIC`, a version byte and the checksum id, whose checksums cover
+    /// the whole record after the checksum itself.
+    V1(Checksum),
+}
+
+impl FileFormat {
+    const MAGIC: &'static [u8; 4] = b"CASK";
+    const VERSION: u8 = 1;
+    /// Magic, version, checksum id and two reserved bytes.
+    pub(crate) const HEADER_SIZE: usize = 8;
+
+    /// Reads the format of a data file from its first bytes.
+    pub(crate) fn detect(start: &[u8]) -> Result<Self, Error> {
+        if !Self::is_header(start) {
+            return Err("data file has no header".into());
+        }
+        if start[4] != Self::VERSION {
+            return Err(format!("unsupported data file version {}", start[4]).into());
+        }
+
+        Ok(FileFormat::V1(Checksum::from_id(start[5])?))
+    }
+
+    /// Reads the format of the data file `file`.
+    pub(crate) fn read_from(file: &File) -> Result<Self, Error> {
+        Self::detect(&Self::read_start(file)?)
+    }
+
+    /// Whether the data file `file` starts with a header. Files without one were written before
+    /// data files had a header, unless a crash cut the header short.
+    pub(crate) fn has_header(file: &File) -> Result<bool, Error> {
+        Ok(Self::is_header(&Self::read_start(file)?))
+    }
+
+    fn is_header(start: &[u8]) -> bool {
+        start.len() >= Self::HEADER_SIZE && &start[..4] == Self::MAGIC
+    }
+
+    /// The first `HEADER_SIZE` bytes of `file`, or all of it if it is shorter.
+    fn read_start(file: &File) -> Result<Vec<u8>, Error> {
+        let mut start = vec![0u8; Self::HEADER_SIZE];
+        match file.read_exact_at(&mut start, 0) {
+            Ok(()) => Ok(start),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                let len = file.metadata()?.len() as usize;
+                start.truncate(len.min(Self::HEADER_SIZE));
+                Ok(start)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The header a new data file starts with.
+    pub(crate) fn header(self) -> Vec<u8> {
+        let FileFormat::V1(checksum) = self;
+        let mut bytes = Self::MAGIC.to_vec();
+        bytes.extend([Self::VERSION, checksum.id(), 0, 0]);
+        bytes
+    }
+
+    /// Where the first record of a file in this format starts.
+    pub(crate) fn header_size(self) -> usize {
+        self.header().len()
+    }
+
+    /// Checksum of a record as stored on disk, excluding the checksum field itself.
+    pub(crate) fn checksum(self, record: &[u8]) -> u32 {
+        let mut digest = self.start_checksum(record);
+        digest.update(&record[RECORD_HEADER_SIZE..]);
+        digest.finalize()
+    }
+
+    /// Starts the checksum of a record from its header, to be fed the key and value that
+    /// follow it.
+    pub(crate) fn start_checksum(self, header: &[u8]) -> Digest {
+        let FileFormat::V1(checksum) = self;
+        let mut digest = checksum.digest();
+        digest.update(&header[4..RECORD_HEADER_SIZE]);
+        digest
+    }
+
+    /// Whether the checksum stored in a record matches its contents. Encrypted records are
+    /// checksummed as stored, so this holds without the encryption key.
+    pub(crate) fn verify(self, record: &[u8]) -> bool {
+        match KeyValue::decode_header(record) {
+            Ok((crc, ..)) => self.checksum(record) == crc,
+            Err(_) => false,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct KeyValue {
-    pub crc: u32,
     pub timestamp: usize,
+    pub seq: u64,
+    pub flags: u8,
+    /// Id of the namespace the key belongs to, `DEFAULT_NAMESPACE` unless set with
+    /// `in_namespace`.
+    pub namespace: u32,
     pub key: String,
-    pub value: String,
+    pub value: Vec<u8>,
 }
 
 impl KeyValue {
-    pub fn new(timestamp: usize, key: String, value: String) -> Self {
-        let mut bytes = vec![];
-
-        let timestamp_bytes = timestamp.to_be_bytes();
-        let key_bytes = key.as_bytes();
-        let value_bytes = value.as_bytes();
+    /// Set on records that mark their key as deleted; such records carry no value.
+    pub const TOMBSTONE: u8 = 1;
+    /// Set on records whose value is compressed, naming the codec.
+    pub const LZ4: u8 = 1 << 1;
+    pub const ZSTD: u8 = 1 << 2;
+    pub const COMPRESSION: u8 = Self::LZ4 | Self::ZSTD;
+    /// Set on records whose key and value are sealed together with the store's encryption key.
+    /// Such a record stores the sealed bytes in place of its key and has no value of its own.
+    pub const ENCRYPTED: u8 = 1 << 3;
+    /// Bytes an encrypted record stores besides its key and value: the length of the key,
+    /// sealed along with them, then the nonce and tag of the cipher.
+    pub(crate) const SEALING_OVERHEAD: usize = 4 + Cipher::OVERHEAD;
+    /// Set on records whose stored key starts with the big-endian id of its namespace. Records
+    /// of the default namespace leave it unset, so they read the same as before namespaces.
+    pub const NAMESPACED: u8 = 1 << 4;
+    /// The namespace of keys not written through a named namespace.
+    pub const DEFAULT_NAMESPACE: u32 = 0;
 
-        bytes.extend(&timestamp_bytes);
-        bytes.extend(key_bytes);
-        bytes.extend(value_bytes);
-
-        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&bytes);
+    pub fn tombstone(timestamp: usize, seq: u64, key: String) -> Self {
+        Self::with_flags(timestamp, seq, Self::TOMBSTONE, key, vec![])
+    }
 
+    pub fn with_flags(timestamp: usize, seq: u64, flags: u8, key: String, value: Vec<u8>) -> Self {
         KeyValue {
-            crc,
             timestamp,
+            seq,
+            flags,
+            namespace: Self::DEFAULT_NAMESPACE,
             key,
             value,
         }
     }
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
-        let mut bytes = vec![];
-        bytes.extend(self.crc.to_be_bytes());
-        bytes.extend(self.timestamp.to_be_bytes());
-        bytes.extend(self.key.len().to_be_bytes());
-        bytes.extend(self.value.len().to_be_bytes());
-        bytes.extend(self.key.as_bytes());
-        bytes.extend(self.value.as_bytes());
+    /// Moves the record into the namespace with the given id.
+    pub fn in_namespace(mut self, namespace: u32) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & Self::TOMBSTONE != 0
+    }
+
+    /// Encodes the record as it is stored in a data file of the given `format`, sealing its key
+    /// and value with `cipher` if one is given. The header goes along as associated data, so it
+    /// cannot be altered without the record failing to decrypt.
+    pub(crate) fn to_bytes(
+        &self,
+        format: FileFormat,
+        cipher: Option<&Cipher>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = match cipher {
+            None => {
+                let mut bytes = self.encode_prefix(self.value.len());
+                bytes.extend(&self.value);
+                bytes
+            }
+            Some(cipher) => {
+                let key = self.stored_key();
+                let key_len = u32::try_from(key.len())?;
+                let plaintext = [&key_len.to_be_bytes(), key.as_slice(), &self.value].concat();
+                let flags = self.stored_flags() | Self::ENCRYPTED;
+                let sealed_size = key.len() + self.value.len() + Self::SEALING_OVERHEAD;
+
+                let mut bytes = Self::header(self.timestamp, self.seq, flags, sealed_size, 0);
+                let sealed = cipher.seal(&bytes[4..], &plaintext)?;
+                bytes.extend(sealed);
+                bytes
+            }
+        };
+
+        let crc = format.checksum(&bytes);
+        bytes[..4].copy_from_slice(&crc.to_be_bytes());
 
         Ok(bytes)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        let crc = u32::from_be_bytes(bytes[0..4].try_into()?);
-        let timestamp = usize::from_be_bytes(bytes[4..12].try_into()?);
-        let key_size = usize::from_be_bytes(bytes[12..20].try_into()?);
-        let value_size = usize::from_be_bytes(bytes[20..28].try_into()?);
-        let key = String::from_utf8(bytes[28..28 + key_size].to_vec())?;
-        let value = String::from_utf8(bytes[28 + key_size..28 + key_size + value_size].to_vec())?;
+    /// Decodes a record as it is stored on disk, opening it with `cipher` if it is encrypted.
+    /// The checksum is not checked here, see `FileFormat::verify`.
+    pub(crate) fn from_bytes(bytes: &[u8], cipher: Option<&Cipher>) -> Result<Self, Error> {
+        let (_, timestamp, seq, flags, key_size, value_size) = Self::decode_header(bytes)?;
 
-        Ok(KeyValue {
-            crc,
-            timestamp,
-            key,
-            value,
-        })
+        if flags & Self::ENCRYPTED != 0 {
+            let cipher = cipher.ok_or("record is encrypted but no encryption key was given")?;
+            let sealed = &bytes[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key_size];
+            let plaintext = cipher.open(&bytes[4..RECORD_HEADER_SIZE], sealed)?;
+
+            let key_len = plaintext
+                .get(..4)
+                .ok_or("encrypted record is cut short")?
+                .try_into()?;
+            let key_end = 4 + u32::from_be_bytes(key_len) as usize;
+            let key = plaintext
+                .get(4..key_end)
+                .ok_or("encrypted record is cut short")?;
+            let (namespace, key) = Self::split_key(flags, key)?;
+            let value = plaintext[key_end..].to_vec();
+            let flags = flags & !(Self::ENCRYPTED | Self::NAMESPACED);
+
+            return Ok(Self::with_flags(timestamp, seq, flags, key, value).in_namespace(namespace));
+        }
+
+        let key_end = RECORD_HEADER_SIZE + key_size;
+        let (namespace, key) = Self::split_key(flags, &bytes[RECORD_HEADER_SIZE..key_end])?;
+        let value = bytes[key_end..key_end + value_size].to_vec();
+        let flags = flags & !Self::NAMESPACED;
+
+        Ok(Self::with_flags(timestamp, seq, flags, key, value).in_namespace(namespace))
     }
 
-    pub fn encode_header(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    /// Splits a key as stored in a record with the given flags into its namespace id and the
+    /// key itself.
+    pub(crate) fn split_key(flags: u8, stored: &[u8]) -> Result<(u32, String), Error> {
+        let (namespace, key) = if flags & Self::NAMESPACED != 0 {
+            let id = stored.get(..4).ok_or("namespaced key is cut short")?;
+            (u32::from_be_bytes(id.try_into()?), &stored[4..])
+        } else {
+            (Self::DEFAULT_NAMESPACE, stored)
+        };
 
-        let crc_bytes = u32::to_be_bytes(self.crc);
-        let timestamp_bytes = usize::to_be_bytes(self.timestamp);
-        let key_size_bytes = usize::to_be_bytes(self.key.len());
-        let value_size_bytes = usize::to_be_bytes(self.value.len());
+        Ok((namespace, String::from_utf8(key.to_vec())?))
+    }
 
-        bytes.extend(crc_bytes);
-        bytes.extend(timestamp_bytes);
-        bytes.extend(key_size_bytes);
-        bytes.extend(value_size_bytes);
+    /// Size of the key of a record in `namespace` as stored unencrypted.
+    pub(crate) fn stored_key_size(namespace: u32, key: &str) -> usize {
+        match namespace {
+            Self::DEFAULT_NAMESPACE => key.len(),
+            _ => 4 + key.len(),
+        }
+    }
+
+    /// The key as stored in the record, prefixed with its namespace id outside the default
+    /// namespace.
+    fn stored_key(&self) -> Vec<u8> {
+        match self.namespace {
+            Self::DEFAULT_NAMESPACE => self.key.as_bytes().to_vec(),
+            id => [&id.to_be_bytes(), self.key.as_bytes()].concat(),
+        }
+    }
+
+    fn stored_flags(&self) -> u8 {
+        match self.namespace {
+            Self::DEFAULT_NAMESPACE => self.flags,
+            _ => self.flags | Self::NAMESPACED,
+        }
+    }
+
+    /// The header and key of the record as stored unencrypted, with a value of `value_size`
+    /// bytes to follow and the checksum left blank, for values written a piece at a time.
+    pub(crate) fn encode_prefix(&self, value_size: usize) -> Vec<u8> {
+        let key = self.stored_key();
+        let mut bytes = Self::header(
+            self.timestamp,
+            self.seq,
+            self.stored_flags(),
+            key.len(),
+            value_size,
+        );
+        bytes.extend(key);
+        bytes
+    }
+
+    /// Size of a record with a key and value of the given sizes, or `None` if it does not fit in
+    /// memory, which only a corrupt header can claim.
+    pub(crate) fn record_size(key_size: usize, value_size: usize) -> Option<usize> {
+        key_size
+            .checked_add(value_size)?
+            .checked_add(RECORD_HEADER_SIZE)
+    }
+
+    /// A record header with the checksum left blank, to be filled in by `to_bytes`.
+    fn header(
+        timestamp: usize,
+        seq: u64,
+        flags: u8,
+        key_size: usize,
+        value_size: usize,
+    ) -> Vec<u8> {
+        let mut bytes = vec![0; 4];
+
+        bytes.extend(usize::to_be_bytes(timestamp));
+        bytes.extend(u64::to_be_bytes(seq));
+        bytes.push(flags);
+        bytes.extend(usize::to_be_bytes(key_size));
+        bytes.extend(usize::to_be_bytes(value_size));
 
         bytes
     }
 
-    pub fn decode_header(bytes: &[u8]) -> Result<(u32, usize, usize, usize), Error> {
+    pub fn decode_header(bytes: &[u8]) -> Result<(u32, usize, u64, u8, usize, usize), Error> {
+        if bytes.len() < RECORD_HEADER_SIZE {
+            return Err("record header is cut short".into());
+        }
+
         let crc = u32::from_be_bytes(bytes[0..4].try_into()?);
         let timestamp = usize::from_be_bytes(bytes[4..12].try_into()?);
-        let key_size = usize::from_be_bytes(bytes[12..20].try_into()?);
-        let value_size = usize::from_be_bytes(bytes[20..28].try_into()?);
+        let seq = u64::from_be_bytes(bytes[12..20].try_into()?);
+        let flags = bytes[20];
+        let key_size = usize::from_be_bytes(bytes[21..29].try_into()?);
+        let value_size = usize::from_be_bytes(bytes[29..RECORD_HEADER_SIZE].try_into()?);
 
-        Ok((crc, timestamp, key_size, value_size))
+        Ok((crc, timestamp, seq, flags, key_size, value_size))
     }
 }
 
 impl Display for KeyValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Key: {}, Value: {}", self.key, self.value)
+        write!(
+            f,
+            "Key: {}, Value: {}",
+            self.key,
+            String::from_utf8_lossy(&self.value)
+        )
     }
 }
 
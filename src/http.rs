@@ -0,0 +1,453 @@
+use crate::batch::WriteBatch;
+use crate::disk_store::DiskStorage;
+use crate::error::DbError;
+use crate::transfer::{self, Record};
+use crate::watch::Event;
+use crate::Error;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{BufRead, BufReader, Cursor, Read, Write},
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Threads taking requests off the listener.
+const WORKERS: usize = 4;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+/// How long a `GET /watch` stream may stay quiet before a blank line is sent, which also
+/// notices clients that have gone away.
+const WATCH_HEARTBEAT: Duration = Duration::from_secs(15);
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Serves the HTTP/JSON API on `addr` from a small pool of worker threads, all sharing
+/// `store`:
+///
+/// - `GET`, `PUT` and `DELETE /keys/{key}` read, write and delete one key; values travel as
+///   raw request and response bodies.
+/// - `GET /keys?prefix=&limit=&cursor=` lists keys and values a page at a time; pass the
+///   returned `next_cursor` to fetch the next page.
+/// - `POST /batch` applies a JSON array of `{"op": "put", "key", "value", "encoding"}` and
+///   `{"op": "delete", "key"}` operations together.
+/// - `POST /admin/merge` starts merging every sealed data file in the background.
+/// - `GET /stats` returns `DiskStorage::stats` as JSON.
+/// - `GET /watch?prefix=` streams a JSON line for every change to a key starting with
+///   `prefix`, see `DiskStorage::watch`, until the client disconnects. Each watch gets a thread
+///   of its own.
+///
+/// Request bodies longer than the store's `max_value_size` are refused with 413.
+pub fn serve(store: Arc<Mutex<DiskStorage>>, addr: &str) -> Result<(), Error> {
+    let server = Arc::new(Server::http(addr).map_err(|err| err.to_string())?);
+    log::info!("serving HTTP on {}", server.server_addr());
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let store = store.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&store, request);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().map_err(|_| "HTTP worker panicked")?;
+    }
+
+    Ok(())
+}
+
+/// A request the client got wrong, answered with `400 Bad Request`.
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+fn bad_request(message: impl Display) -> Error {
+    BadRequest(message.to_string()).into()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchRequest {
+    Put(Record),
+    Delete { key: String },
+}
+
+/// One line of a `GET /watch` stream.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub(crate) enum WatchMessage {
+    Put {
+        key: String,
+        seq: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<String>,
+    },
+    Delete {
+        key: String,
+        seq: u64,
+    },
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl From<Event> for WatchMessage {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::Put { key, seq, value } => {
+                let (value, encoding) = match value.map(transfer::encode_value) {
+                    Some((value, encoding)) => (Some(value), encoding),
+                    None => (None, None),
+                };
+                WatchMessage::Put {
+                    key,
+                    seq,
+                    value,
+                    encoding,
+                }
+            }
+            Event::Delete { key, seq } => WatchMessage::Delete { key, seq },
+            Event::Lagged { missed } => WatchMessage::Lagged { missed },
+        }
+    }
+}
+
+impl TryFrom<WatchMessage> for Event {
+    type Error = Error;
+
+    fn try_from(message: WatchMessage) -> Result<Self, Error> {
+        Ok(match message {
+            WatchMessage::Put {
+                key,
+                seq,
+                value,
+                encoding,
+            } => {
+                let value = match value {
+                    Some(value) => Some(transfer::decode_value(value, encoding.as_deref())?),
+                    None => None,
+                };
+                Event::Put { key, seq, value }
+            }
+            WatchMessage::Delete { key, seq } => Event::Delete { key, seq },
+            WatchMessage::Lagged { missed } => Event::Lagged { missed },
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Page {
+    items: Vec<Record>,
+    next_cursor: Option<String>,
+}
+
+fn handle(store: &Mutex<DiskStorage>, mut request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+
+    if let (Method::Get, Some(query)) = (&method, url.strip_prefix("/watch")) {
+        if query.is_empty() || query.starts_with('?') {
+            return watch(store, request, query.trim_start_matches('?'));
+        }
+    }
+
+    let response = route(store, &mut request).unwrap_or_else(|err| {
+        let status = status_of(&err);
+        if status >= 500 {
+            log::error!("{} {} failed: {}", method, url, err);
+        }
+        json(status, &serde_json::json!({ "error": err.to_string() }))
+    });
+    log::debug!("{} {} -> {}", method, url, response.status_code().0);
+
+    if let Err(err) = request.respond(response) {
+        log::debug!("failed to send response: {}", err);
+    }
+}
+
+/// Maps the typed errors of the store onto HTTP status codes.
+fn status_of(err: &Error) -> u16 {
+    if err.is::<BadRequest>() {
+        return 400;
+    }
+    match err.downcast_ref::<DbError>() {
+        Some(DbError::NotFound { .. }) => 404,
+        Some(DbError::KeyTooLarge { .. } | DbError::ValueTooLarge { .. }) => 413,
+        _ => 500,
+    }
+}
+
+fn route(store: &Mutex<DiskStorage>, request: &mut Request) -> Result<HttpResponse, Error> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let method = request.method().clone();
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = decode(key)?;
+        return match method {
+            Method::Get => {
+                let value = store.lock().unwrap().get(&key);
+                let value = value.ok_or(DbError::NotFound { key })?;
+                let content_type =
+                    Header::from_bytes("Content-Type", "application/octet-stream").unwrap();
+                Ok(Response::from_data(value).with_header(content_type))
+            }
+            Method::Put => {
+                let max = store.lock().unwrap().max_value_size();
+                let value = read_body(request, max)?;
+                store.lock().unwrap().set(&key, &value)?;
+                Ok(empty(204))
+            }
+            Method::Delete => {
+                let mut store = store.lock().unwrap();
+                if !store.contains(&key) {
+                    return Err(DbError::NotFound { key }.into());
+                }
+                store.delete(&key)?;
+                Ok(empty(204))
+            }
+            _ => Ok(method_not_allowed()),
+        };
+    }
+
+    match (method, path) {
+        (Method::Get, "/keys") => list(store, query),
+        (Method::Post, "/batch") => {
+            let max = store.lock().unwrap().max_value_size();
+            let body = read_body(request, max)?;
+            let ops: Vec<BatchRequest> = serde_json::from_slice(&body).map_err(bad_request)?;
+
+            let mut batch = WriteBatch::new();
+            for op in ops {
+                match op {
+                    BatchRequest::Put(record) => {
+                        let (key, value) = record.into_pair().map_err(bad_request)?;
+                        batch.put(&key, &value);
+                    }
+                    BatchRequest::Delete { key } => {
+                        batch.delete(&key);
+                    }
+                }
+            }
+
+            let mut store = store.lock().unwrap();
+            store.apply_batch(&batch)?;
+            let body = serde_json::json!({ "applied": batch.len(), "sequence": store.sequence() });
+            Ok(json(200, &body))
+        }
+        (Method::Post, "/admin/merge") => {
+            let mut store = store.lock().unwrap();
+            let file_ids: Vec<u32> = store.stats().files.iter().map(|f| f.file_id).collect();
+            let started = store.start_merge(&file_ids)?;
+            Ok(json(202, &serde_json::json!({ "started": started })))
+        }
+        (Method::Get, "/stats") => Ok(json(200, &store.lock().unwrap().stats())),
+        (_, "/keys" | "/batch" | "/admin/merge" | "/stats") => Ok(method_not_allowed()),
+        _ => Ok(json(
+            404,
+            &serde_json::json!({ "error": "no such endpoint" }),
+        )),
+    }
+}
+
+/// Reads the body of `request`, refusing one of more than `max` bytes before buffering it
+/// where the client declares its length, and as soon as it passes `max` where it does not.
+fn read_body(request: &mut Request, max: u64) -> Result<Vec<u8>, Error> {
+    if let Some(size) = request.body_length().filter(|size| *size as u64 > max) {
+        return Err(DbError::ValueTooLarge {
+            size: size as u64,
+            max,
+        }
+        .into());
+    }
+
+    let mut body = vec![];
+    request.as_reader().take(max + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > max {
+        return Err(DbError::ValueTooLarge {
+            size: body.len() as u64,
+            max,
+        }
+        .into());
+    }
+
+    Ok(body)
+}
+
+/// One page of `GET /keys`. The cursor is the last key of the previous page, so pages stay
+/// consistent while keys are added or removed.
+fn list(store: &Mutex<DiskStorage>, query: &str) -> Result<HttpResponse, Error> {
+    let params = params(query)?;
+    let prefix = params.get("prefix").map_or("", String::as_str);
+    let cursor = params.get("cursor").filter(|cursor| !cursor.is_empty());
+    let limit = match params.get("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| bad_request("limit must be a number"))?,
+        None => DEFAULT_PAGE_SIZE,
+    }
+    .clamp(1, MAX_PAGE_SIZE);
+
+    let start = match cursor {
+        Some(cursor) if cursor.as_str() > prefix => cursor.as_str(),
+        _ => prefix,
+    };
+
+    let store = store.lock().unwrap();
+    let mut items: Vec<Record> = store
+        .scan_from(start)
+        .skip_while(|(key, _)| Some(key) == cursor)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .take(limit + 1)
+        .map(|(key, value)| Record::new(key, value))
+        .collect();
+
+    let next_cursor = if items.len() > limit {
+        items.pop();
+        items.last().map(|record| record.key().to_string())
+    } else {
+        None
+    };
+
+    Ok(json(200, &Page { items, next_cursor }))
+}
+
+/// Subscribes to the changes under the `prefix` parameter and streams them to the client from a
+/// thread of its own, leaving the worker free for other requests.
+fn watch(store: &Mutex<DiskStorage>, request: Request, query: &str) {
+    let prefix = match params(query) {
+        Ok(mut params) => params.remove("prefix").unwrap_or_default(),
+        Err(err) => {
+            let response = json(400, &serde_json::json!({ "error": err.to_string() }));
+            if let Err(err) = request.respond(response) {
+                log::debug!("failed to send response: {}", err);
+            }
+            return;
+        }
+    };
+    log::debug!("GET /watch?prefix={} -> 200", prefix);
+
+    let events = store.lock().unwrap().watch(&prefix);
+    thread::spawn(move || {
+        // The response is written by hand so that every event goes out as soon as it arrives,
+        // and ends when the connection closes.
+        let mut writer = request.into_writer();
+        if let Err(err) = stream_events(&mut writer, events) {
+            log::debug!("watch of {:?} ended: {}", prefix, err);
+        }
+    });
+}
+
+fn stream_events(writer: &mut impl Write, events: Receiver<Event>) -> Result<(), Error> {
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+    )?;
+    writer.flush()?;
+
+    loop {
+        match events.recv_timeout(WATCH_HEARTBEAT) {
+            Ok(event) => serde_json::to_writer(&mut *writer, &WatchMessage::from(event))?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+}
+
+/// Follows `GET /watch` on the server at `addr`, calling `on_event` for every change to a key
+/// starting with `prefix` until the server goes away.
+pub fn watch_remote(
+    addr: &str,
+    prefix: &str,
+    mut on_event: impl FnMut(Event) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(addr)?;
+    let prefix = utf8_percent_encode(prefix, NON_ALPHANUMERIC);
+    write!(
+        stream,
+        "GET /watch?prefix={} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        prefix, addr
+    )?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    if status.split_whitespace().nth(1) != Some("200") {
+        let mut body = String::new();
+        std::io::Read::read_to_string(&mut reader, &mut body)?;
+        return Err(format!("{}: {}", status.trim_end(), body.trim_end()).into());
+    }
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let message: WatchMessage = serde_json::from_str(&line)?;
+        on_event(message.try_into()?)?;
+    }
+
+    Ok(())
+}
+
+/// Decodes the `name=value` pairs of a query string.
+fn params(query: &str) -> Result<HashMap<String, String>, Error> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode_param = |text: &str| decode(&text.replace('+', " "));
+        params.insert(decode_param(name)?, decode_param(value)?);
+    }
+
+    Ok(params)
+}
+
+fn decode(text: &str) -> Result<String, Error> {
+    percent_decode_str(text)
+        .decode_utf8()
+        .map(|text| text.into_owned())
+        .map_err(|_| bad_request("keys must be valid UTF-8"))
+}
+
+fn json(status: u16, body: &impl Serialize) -> HttpResponse {
+    let body = serde_json::to_vec(body).unwrap();
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn empty(status: u16) -> HttpResponse {
+    Response::from_data(vec![]).with_status_code(status)
+}
+
+fn method_not_allowed() -> HttpResponse {
+    json(405, &serde_json::json!({ "error": "method not allowed" }))
+}
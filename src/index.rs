@@ -0,0 +1,155 @@
+use crate::disk_store::DiskStorage;
+use crate::Error;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    sync::Arc,
+};
+
+type ExtractFn = dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+/// Turns a value into the values it is indexed under, see `DiskStorage::register_index`.
+#[derive(Clone)]
+pub(crate) struct Extractor(Arc<ExtractFn>);
+
+impl Extractor {
+    pub(crate) fn new(extract: impl Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static) -> Self {
+        Extractor(Arc::new(extract))
+    }
+
+    /// The values `value` is indexed under, sorted and without duplicates.
+    pub(crate) fn extract(&self, value: &[u8]) -> Vec<Vec<u8>> {
+        let mut values = (self.0)(value);
+        values.sort();
+        values.dedup();
+        values
+    }
+}
+
+impl Debug for Extractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extractor(..)")
+    }
+}
+
+/// The in-memory side of a secondary index. On disk the index is a namespace of its own holding
+/// a record per indexed key, whose value lists what the key is indexed under.
+#[derive(Debug)]
+pub(crate) struct SecondaryIndex {
+    /// Id of the namespace the index's records are kept in.
+    pub id: u32,
+    /// Sequence number the index was created at.
+    pub created: u64,
+    /// Keys of the default namespace under every value they are indexed under.
+    entries: BTreeMap<Vec<u8>, BTreeSet<String>>,
+    /// What every indexed key is indexed under, to take it out of `entries` again.
+    values: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl SecondaryIndex {
+    pub(crate) fn new(id: u32, created: u64) -> Self {
+        SecondaryIndex {
+            id,
+            created,
+            entries: BTreeMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// Indexes `key` under `values` instead of whatever it was indexed under before; no values
+    /// takes it out of the index.
+    pub(crate) fn update(&mut self, key: &str, values: Vec<Vec<u8>>) {
+        for value in self.values.remove(key).unwrap_or_default() {
+            if let Some(keys) = self.entries.get_mut(&value) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&value);
+                }
+            }
+        }
+        if values.is_empty() {
+            return;
+        }
+
+        for value in &values {
+            self.entries
+                .entry(value.clone())
+                .or_default()
+                .insert(key.to_string());
+        }
+        self.values.insert(key.to_string(), values);
+    }
+}
+
+/// Encodes the values a key is indexed under as the value of its index record: each one
+/// prefixed with its length as a big-endian u32.
+pub(crate) fn encode_values(values: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for value in values {
+        bytes.extend((value.len() as u32).to_be_bytes());
+        bytes.extend(value);
+    }
+    bytes
+}
+
+pub(crate) fn decode_values(mut bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut values = vec![];
+    while !bytes.is_empty() {
+        let len = bytes.get(..4).ok_or("index record is cut short")?;
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        let value = bytes.get(4..4 + len).ok_or("index record is cut short")?;
+        values.push(value.to_vec());
+        bytes = &bytes[4 + len..];
+    }
+    Ok(values)
+}
+
+/// A secondary index over the keys of a `DiskStorage`, returned by `DiskStorage::index`.
+#[derive(Debug)]
+pub struct Index<'a> {
+    store: &'a DiskStorage,
+    index: &'a SecondaryIndex,
+}
+
+impl<'a> Index<'a> {
+    pub(crate) fn new(store: &'a DiskStorage, index: &'a SecondaryIndex) -> Self {
+        Index { store, index }
+    }
+
+    /// Keys indexed under `value`, in order.
+    pub fn keys(&self, value: &[u8]) -> impl Iterator<Item = &'a str> + 'a {
+        self.index
+            .entries
+            .get(value)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// Every key indexed under `value` with its value, in key order.
+    pub fn get(&self, value: &[u8]) -> Vec<(String, Vec<u8>)> {
+        self.keys(value)
+            .filter_map(|key| Some((key.to_string(), self.store.get(key)?)))
+            .collect()
+    }
+
+    /// Every key indexed under a value in `[start, end)` with its value, ordered by what it is
+    /// indexed under and then by key. A key indexed under several values in the range is
+    /// returned once for each.
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Vec<(String, Vec<u8>)> {
+        if start >= end {
+            return vec![];
+        }
+
+        self.index
+            .entries
+            .range(start.to_vec()..end.to_vec())
+            .flat_map(|(_, keys)| keys)
+            .filter_map(|key| Some((key.clone(), self.store.get(key)?)))
+            .collect()
+    }
+}
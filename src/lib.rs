@@ -1,7 +1,33 @@
 pub mod args;
+#[cfg(feature = "async")]
+pub mod async_db;
+pub mod backup;
+pub mod batch;
+pub mod changes;
+pub mod checksum;
 pub mod commands;
+pub mod compression;
 pub mod disk_store;
+pub mod encryption;
+pub mod error;
 mod format;
+pub mod http;
+pub mod index;
+mod lru;
+mod merge;
+pub mod namespace;
+pub mod options;
+mod positioned;
+pub mod rate_limit;
 mod rb_trees;
+mod rekey;
+pub mod resp;
+pub mod shell;
+pub mod snapshot;
+pub mod stats;
+pub mod transfer;
+mod upgrade;
+pub mod value;
+pub mod watch;
 
 pub type Error = Box<dyn std::error::Error>;
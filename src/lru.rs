@@ -0,0 +1,102 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A least-recently-used map bounded by the total weight of its entries. Each entry is
+/// weighed when inserted; counting every entry as 1 bounds the number of entries instead.
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    weight: usize,
+    entries: HashMap<K, Slot<V>>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    weight: usize,
+    used: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            weight: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Total weight of the entries held.
+    pub(crate) fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// Looks up `key`, marking it as the most recently used entry.
+    pub(crate) fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.entries.get_mut(key)?;
+        let owned_key = self.order.remove(&slot.used)?;
+        self.tick += 1;
+        slot.used = self.tick;
+        self.order.insert(self.tick, owned_key);
+
+        Some(&slot.value)
+    }
+
+    /// Inserts or replaces `key`, then evicts least recently used entries until the total
+    /// weight fits. An entry heavier than the whole capacity is not kept at all.
+    pub(crate) fn insert(&mut self, key: K, value: V, weight: usize) {
+        self.remove(&key);
+        if weight > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Slot {
+                value,
+                weight,
+                used: self.tick,
+            },
+        );
+        self.weight += weight;
+
+        while self.weight > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(slot) = self.entries.remove(&oldest) {
+                self.weight -= slot.weight;
+            }
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.entries.remove(key)?;
+        self.order.remove(&slot.used);
+        self.weight -= slot.weight;
+
+        Some(slot.value)
+    }
+}
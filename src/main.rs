@@ -1,14 +1,63 @@
 use cask_db::args;
+use cask_db::encryption::EncryptionKey;
+use cask_db::error::DbError;
+use cask_db::options::DiskStorageOptions;
 use cask_db::{commands, Error};
 use clap::Parser;
+use log::LevelFilter;
 
-fn main() -> Result<(), Error> {
-    match args::Cli::parse().command {
-        args::Commands::Create(create_args) => commands::create(create_args),
-        args::Commands::Init(init_args) => commands::init(init_args),
-        args::Commands::Get(get_args) => commands::get(get_args),
-        args::Commands::Set(set_args) => commands::set(set_args),
-        args::Commands::Delete(delete_args) => commands::delete(delete_args),
-        args::Commands::Merge(merge_args) => commands::merge(merge_args),
+fn main() {
+    let cli = args::Cli::parse();
+
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
+    if let Err(err) = run(cli) {
+        match err.downcast_ref::<DbError>() {
+            Some(DbError::NotFound { .. }) => {
+                log::warn!("{}", err);
+                std::process::exit(1);
+            }
+            _ => {
+                log::error!("{}", err);
+                std::process::exit(2);
+            }
+        }
+    }
+}
+
+fn run(cli: args::Cli) -> Result<(), Error> {
+    let mut options = DiskStorageOptions::default();
+    if let Some(path) = cli.key_file {
+        options.encryption_key = Some(EncryptionKey::from_file(path)?);
+    }
+
+    match cli.command {
+        args::Commands::Create(create_args) => commands::create(create_args, options),
+        args::Commands::Init(init_args) => commands::init(init_args, options),
+        args::Commands::Get(get_args) => commands::get(get_args, options),
+        args::Commands::Set(set_args) => commands::set(set_args, options),
+        args::Commands::Delete(delete_args) => commands::delete(delete_args, options),
+        args::Commands::Scan(scan_args) => commands::scan(scan_args, options),
+        args::Commands::Merge(merge_args) => commands::merge(merge_args, options),
+        args::Commands::Rekey(rekey_args) => commands::rekey(rekey_args, options),
+        args::Commands::Backup(backup_args) => commands::backup(backup_args, options),
+        args::Commands::Restore(restore_args) => commands::restore(restore_args),
+        args::Commands::Export(export_args) => commands::export(export_args, options),
+        args::Commands::Import(import_args) => commands::import(import_args, options),
+        args::Commands::Shell(shell_args) => commands::shell(shell_args, options),
+        args::Commands::Stats(stats_args) => commands::stats(stats_args, options),
+        args::Commands::Serve(serve_args) => commands::serve(serve_args, options),
+        args::Commands::Watch(watch_args) => commands::watch(watch_args),
     }
 }
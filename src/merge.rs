@@ -0,0 +1,228 @@
+use crate::disk_store::{now, DiskStorage};
+use crate::encryption::Cipher;
+use crate::error::DbError;
+use crate::format::{FileFormat, KeyEntry, KeyValue, RECORD_HEADER_SIZE};
+use crate::rate_limit::{RateLimiter, Throttled};
+use crate::stats::SeqRange;
+use crate::Error;
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+/// A merge running on a background thread.
+pub(crate) type MergeJob = JoinHandle<Result<MergeOutcome, String>>;
+
+/// Everything a background merge needs, captured from the store when the merge starts.
+pub(crate) struct MergePlan {
+    pub base_dir: PathBuf,
+    pub file_ids: Vec<u32>,
+    pub merged_id: u32,
+    /// Format the merged file is written in.
+    pub format: FileFormat,
+    /// `(file_id, position)` of every record that was live when the merge started.
+    pub live: HashSet<(u32, usize)>,
+    /// Tombstones with a lower sequence number can be dropped.
+    pub drop_below: u64,
+    /// Where the history of changes starts once the merge is done, written to the merged file
+    /// unless nothing was ever lost.
+    pub history_start: u64,
+    pub limiter: RateLimiter,
+    /// Opens encrypted records, whose keys are sealed.
+    pub cipher: Option<Arc<Cipher>>,
+    /// Rewrite every record copied under this cipher, or unencrypted for `None`, instead of
+    /// copying its bytes as they are.
+    pub reencode: Option<Option<Arc<Cipher>>>,
+}
+
+/// A live record the merge copied, and where it was copied from.
+pub(crate) struct Relocation {
+    pub namespace: u32,
+    pub key: String,
+    pub from_file: u32,
+    pub from_position: usize,
+    pub entry: KeyEntry,
+}
+
+/// What a finished merge wrote, for the store to swap into its key directory.
+pub(crate) struct MergeOutcome {
+    pub file_ids: Vec<u32>,
+    pub merged_id: u32,
+    pub format: FileFormat,
+    /// Bytes of records in the merged file, not counting its header.
+    pub merged_bytes: usize,
+    /// Sequence numbers of the records in the merged file, if it has any.
+    pub seqs: Option<SeqRange>,
+    pub relocations: Vec<Relocation>,
+    /// Sizes of the tombstones carried over into the merged file.
+    pub tombstones: Vec<usize>,
+    pub history_start: u64,
+    /// Size of the record holding `history_start` at the end of the merged file, if any.
+    pub history_size: usize,
+}
+
+impl MergePlan {
+    pub(crate) fn spawn(self) -> MergeJob {
+        thread::spawn(move || self.run().map_err(|err| err.to_string()))
+    }
+
+    /// Copies the records of the input files that were live when the merge started, plus the
+    /// tombstones that must survive, into `{merged_id}.db`. Every record copied has its checksum
+    /// checked, and is checksummed again if the merged file uses a different algorithm.
+    ///
+    /// The output is written under a temporary name and only renamed once synced, so a crash
+    /// mid-merge never leaves a truncated data file behind.
+    fn run(self) -> Result<MergeOutcome, Error> {
+        let tmp_path = self.base_dir.join(format!("{}.db.tmp", self.merged_id));
+        let merged_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&tmp_path)?;
+        let mut merged_file = BufWriter::new(Throttled::new(merged_file, self.limiter.clone()));
+        merged_file.write_all(&self.format.header())?;
+        let header_size = self.format.header_size();
+
+        let mut outcome = MergeOutcome {
+            file_ids: self.file_ids.clone(),
+            merged_id: self.merged_id,
+            format: self.format,
+            merged_bytes: 0,
+            seqs: None,
+            relocations: vec![],
+            tombstones: vec![],
+            history_start: self.history_start,
+            history_size: 0,
+        };
+
+        for &id in &self.file_ids {
+            let path = self.base_dir.join(format!("{}.db", id));
+            let file = File::open(path)?;
+            let input_format = FileFormat::read_from(&file)?;
+            let file_len = file.metadata()?.len() as usize;
+            let mut file = BufReader::new(Throttled::new(file, self.limiter.clone()));
+            let mut position = input_format.header_size();
+            file.read_exact(&mut vec![0; position])?;
+
+            loop {
+                let mut header_buf = [0u8; RECORD_HEADER_SIZE];
+                match file.read_exact(&mut header_buf) {
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break, // End of file
+                    result => result?,
+                }
+
+                let (_, timestamp, seq, flags, key_size, value_size) =
+                    KeyValue::decode_header(&header_buf)?;
+
+                let record_position = position;
+                let corruption = DbError::Corruption {
+                    file_id: id,
+                    position: record_position,
+                };
+                let total_size = KeyValue::record_size(key_size, value_size)
+                    .filter(|size| *size <= file_len - record_position)
+                    .ok_or(corruption)?;
+                position += total_size;
+
+                let mut data_buf = vec![0u8; total_size - RECORD_HEADER_SIZE];
+                file.read_exact(&mut data_buf)?;
+
+                let is_tombstone = flags & KeyValue::TOMBSTONE != 0;
+                let keep = if is_tombstone {
+                    seq >= self.drop_below
+                } else {
+                    self.live.contains(&(id, record_position))
+                };
+                if !keep {
+                    continue;
+                }
+
+                let mut record = [&header_buf[..], &data_buf].concat();
+                if !input_format.verify(&record) {
+                    let position = record_position;
+                    return Err(DbError::Corruption {
+                        file_id: id,
+                        position,
+                    }
+                    .into());
+                }
+
+                let mut decoded = None;
+                if let Some(output) = &self.reencode {
+                    let kv = KeyValue::from_bytes(&record, self.cipher.as_deref())?;
+                    record = kv.to_bytes(self.format, output.as_deref())?;
+                    decoded = Some(kv);
+                } else if input_format != self.format {
+                    let crc = self.format.checksum(&record);
+                    record[..4].copy_from_slice(&crc.to_be_bytes());
+                }
+                merged_file.write_all(&record)?;
+                let total_size = record.len();
+
+                if is_tombstone {
+                    outcome.tombstones.push(total_size);
+                } else {
+                    let (namespace, key) = match decoded {
+                        Some(kv) => (kv.namespace, kv.key),
+                        None if flags & KeyValue::ENCRYPTED != 0 => {
+                            let kv = KeyValue::from_bytes(&record, self.cipher.as_deref())?;
+                            (kv.namespace, kv.key)
+                        }
+                        None => KeyValue::split_key(flags, &data_buf[..key_size])?,
+                    };
+                    let encrypted = match &self.reencode {
+                        Some(output) => output.is_some(),
+                        None => flags & KeyValue::ENCRYPTED != 0,
+                    };
+                    let entry = KeyEntry::init(
+                        self.merged_id,
+                        timestamp,
+                        seq,
+                        header_size + outcome.merged_bytes,
+                        total_size,
+                    )
+                    .encrypted(encrypted);
+                    outcome.relocations.push(Relocation {
+                        namespace,
+                        key,
+                        from_file: id,
+                        from_position: record_position,
+                        entry,
+                    });
+                }
+
+                outcome.merged_bytes += total_size;
+                match &mut outcome.seqs {
+                    Some(seqs) => seqs.push(seq),
+                    None => outcome.seqs = Some(SeqRange::new(seq)),
+                }
+            }
+        }
+
+        if self.history_start > 1 {
+            let value = self.history_start.to_be_bytes().to_vec();
+            let record = KeyValue::with_flags(now(), 0, 0, String::new(), value)
+                .in_namespace(DiskStorage::HISTORY_START)
+                .to_bytes(self.format, None)?;
+            merged_file.write_all(&record)?;
+            outcome.history_size = record.len();
+        }
+
+        merged_file.into_inner()?.into_inner().sync_all()?;
+
+        if outcome.merged_bytes == 0 && outcome.history_size == 0 {
+            fs::remove_file(&tmp_path)?;
+        } else {
+            fs::rename(
+                &tmp_path,
+                self.base_dir.join(format!("{}.db", self.merged_id)),
+            )?;
+        }
+
+        Ok(outcome)
+    }
+}
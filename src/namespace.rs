@@ -0,0 +1,91 @@
+use crate::disk_store::DiskStorage;
+use crate::value::ValueReader;
+use crate::Error;
+use std::io::Read;
+
+/// The keys of one named namespace of a `DiskStorage`, created with `DiskStorage::namespace`.
+///
+/// A namespace that has never been written to reads as empty; the first `set` creates it.
+/// Dropping it again with `DiskStorage::drop_namespace` removes all of its keys at once.
+#[derive(Debug)]
+pub struct Namespace<'a> {
+    store: &'a mut DiskStorage,
+    name: String,
+    /// `None` until the namespace is created.
+    id: Option<u32>,
+}
+
+impl<'a> Namespace<'a> {
+    pub(crate) fn new(store: &'a mut DiskStorage, name: &str, id: Option<u32>) -> Self {
+        Namespace {
+            store,
+            name: name.to_string(),
+            id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.store
+            .get_ref_in(self.id?, key)
+            .map(|value| value.into_vec())
+    }
+
+    /// Like `DiskStorage::get_reader`, for a key of this namespace.
+    pub fn get_reader(&self, key: &str) -> Option<ValueReader> {
+        self.store.get_reader_in(self.id?, key)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.id.is_some_and(|id| self.store.contains_in(id, key))
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Error> {
+        let id = self.create()?;
+        self.store.set_in(id, key, value)
+    }
+
+    /// Like `DiskStorage::set_from_reader`, for a key of this namespace.
+    pub fn set_from_reader(&mut self, key: &str, value: impl Read, len: u64) -> Result<(), Error> {
+        let id = self.create()?;
+        self.store.set_from_reader_in(id, key, value, len)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        match self.id {
+            Some(id) => self.store.delete_in(id, key),
+            None => Ok(()),
+        }
+    }
+
+    /// Lazily yields every key of the namespace starting with `prefix` and its value, in key
+    /// order.
+    pub fn scan<'b>(&'b self, prefix: &'b str) -> impl Iterator<Item = (String, Vec<u8>)> + 'b {
+        self.scan_from(prefix)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    /// Lazily yields every key of the namespace from `start` onwards and its value, in key
+    /// order.
+    pub fn scan_from<'b>(&'b self, start: &'b str) -> impl Iterator<Item = (String, Vec<u8>)> + 'b {
+        self.id
+            .into_iter()
+            .flat_map(move |id| self.store.scan_from_in(id, start))
+    }
+
+    /// Keys of the namespace starting with `prefix`, in order.
+    pub fn keys<'b>(&'b self, prefix: &'b str) -> impl Iterator<Item = &'b str> + 'b {
+        self.id
+            .into_iter()
+            .flat_map(move |id| self.store.keys_in(id, prefix))
+    }
+
+    fn create(&mut self) -> Result<u32, Error> {
+        let id = self.store.create_namespace(&self.name)?;
+        self.id = Some(id);
+        Ok(id)
+    }
+}
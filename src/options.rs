@@ -0,0 +1,111 @@
+use crate::checksum::Checksum;
+use crate::compression::Compression;
+use crate::encryption::EncryptionKey;
+
+/// Tuning knobs for a `DiskStorage`, passed to `DiskStorage::with_options`.
+#[derive(Debug, Clone)]
+pub struct DiskStorageOptions {
+    /// The active data file is sealed and a new one started once it grows past this size.
+    pub max_file_size: u64,
+    /// When to merge without being asked. Merging drops the history `changes_since` and
+    /// incremental backups read, so it is off unless enabled here.
+    pub merge_triggers: MergeTriggers,
+    /// Bytes per second that merges, backups and exports may read and write between them;
+    /// `None` leaves them unthrottled. Can be changed later with `DiskStorage::set_io_rate_limit`.
+    pub io_rate_limit: Option<u64>,
+    /// Read sealed data files through memory maps instead of a system call per lookup. The
+    /// active file is always read with positioned reads.
+    pub mmap_reads: bool,
+    /// How many data file read handles, and memory maps with `mmap_reads`, to keep open between
+    /// lookups.
+    pub max_open_files: usize,
+    /// Keep up to this many bytes of recently read keys and values in memory so repeated
+    /// `get`s skip the disk; 0 turns the cache off.
+    pub value_cache_bytes: usize,
+    /// Codec for values written from now on. Values already on disk keep whatever codec they
+    /// were written with.
+    pub compression: Compression,
+    /// Longest key `set` accepts, in bytes. Keys are kept in memory, so this bounds what a single
+    /// key can cost.
+    pub max_key_size: usize,
+    /// Longest value `set` accepts, in bytes.
+    pub max_value_size: u64,
+    /// Values shorter than this many bytes are never compressed.
+    pub compression_threshold: usize,
+    /// Encrypt records written from now on with this key, which is also needed to read any
+    /// encrypted records already on disk. Needs the `encryption` feature; see
+    /// `DiskStorage::rekey` for changing the key of an existing store.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Checksum algorithm of data files created from now on, recorded in each file's header.
+    /// Existing files keep the algorithm they were written with.
+    pub checksum: Checksum,
+    /// How many events each receiver of `DiskStorage::watch` holds before it starts missing
+    /// some. Receivers always hold at least one, as none would make them miss every event.
+    pub watch_buffer: usize,
+}
+
+impl Default for DiskStorageOptions {
+    fn default() -> Self {
+        DiskStorageOptions {
+            max_file_size: 100,
+            merge_triggers: MergeTriggers::disabled(),
+            io_rate_limit: None,
+            mmap_reads: false,
+            max_open_files: 64,
+            value_cache_bytes: 0,
+            compression: Compression::None,
+            compression_threshold: 256,
+            max_key_size: 64 * 1024,
+            max_value_size: 1 << 30,
+            encryption_key: None,
+            checksum: Checksum::Crc32c,
+            watch_buffer: 1024,
+        }
+    }
+}
+
+/// When the store should merge on its own, checked after every write and by the thread started
+/// with `DiskStorage::spawn_merge_thread`. Every trigger left as `None` is disabled, so
+/// `MergeTriggers::disabled()` leaves merging entirely to explicit `merge` calls.
+#[derive(Debug, Clone)]
+pub struct MergeTriggers {
+    /// Merge any sealed file whose dead bytes make up at least this fraction of it.
+    pub file_dead_ratio: Option<f64>,
+    /// Merge every sealed file holding dead bytes once the store as a whole has this many.
+    pub total_dead_bytes: Option<u64>,
+    /// Merge every sealed file once there are more than this many of them.
+    pub max_sealed_files: Option<usize>,
+    /// Only merge automatically between these hours of the day (UTC), `(start, end)`.
+    /// The window may wrap past midnight, e.g. `(22, 4)`.
+    pub window: Option<(u32, u32)>,
+    /// Check the triggers after every `set` and `delete`. Turn this off to keep merge work off
+    /// the write path and rely on the background thread instead.
+    pub check_after_writes: bool,
+}
+
+impl MergeTriggers {
+    pub fn disabled() -> Self {
+        MergeTriggers {
+            file_dead_ratio: None,
+            total_dead_bytes: None,
+            max_sealed_files: None,
+            window: None,
+            check_after_writes: true,
+        }
+    }
+
+    /// Whether `hour` (0-23, UTC) falls inside the allowed window.
+    pub fn in_window(&self, hour: u32) -> bool {
+        match self.window {
+            None => true,
+            Some((start, end)) if start <= end => (start..end).contains(&hour),
+            Some((start, end)) => hour >= start || hour < end,
+        }
+    }
+}
+
+impl Default for MergeTriggers {
+    fn default() -> Self {
+        MergeTriggers::disabled()
+    }
+}
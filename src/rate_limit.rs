@@ -0,0 +1,126 @@
+use std::{
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A token bucket shared by the merge worker, backups and exports, so their combined disk
+/// traffic stays under a bytes-per-second budget. Clones share the same bucket, and the rate
+/// can be changed at any time with `set_rate`.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Bytes per second; 0 means unlimited.
+    rate: AtomicU64,
+    /// Tokens available and when they were last topped up. Tokens go negative when a caller
+    /// takes more than is available; the caller then sleeps off the debt.
+    bucket: Mutex<(f64, Option<Instant>)>,
+    throttled_nanos: AtomicU64,
+    waits: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        let limiter = RateLimiter::default();
+        limiter.set_rate(bytes_per_second);
+        limiter
+    }
+
+    /// Changes the budget; `None` lifts the limit.
+    pub fn set_rate(&self, bytes_per_second: Option<u64>) {
+        self.inner
+            .rate
+            .store(bytes_per_second.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        match self.inner.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Total time callers have spent waiting for tokens.
+    pub fn throttled(&self) -> Duration {
+        Duration::from_nanos(self.inner.throttled_nanos.load(Ordering::Relaxed))
+    }
+
+    /// How many times a caller had to wait for tokens.
+    pub fn waits(&self) -> u64 {
+        self.inner.waits.load(Ordering::Relaxed)
+    }
+
+    /// Takes `bytes` tokens from the bucket, sleeping until the budget allows it.
+    pub fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let rate = rate as f64;
+
+        let wait = {
+            let mut bucket = self.inner.bucket.lock().unwrap();
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            // Allow bursts of up to one second's worth of I/O.
+            let refill = last.map_or(rate, |last| (now - last).as_secs_f64() * rate);
+            *tokens = (*tokens + refill).min(rate) - bytes as f64;
+            *last = Some(now);
+
+            if *tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-*tokens / rate)
+        };
+
+        self.inner
+            .throttled_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        self.inner.waits.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(wait);
+    }
+}
+
+/// Wraps a reader or writer so every byte passing through it is charged to a `RateLimiter`.
+#[derive(Debug)]
+pub(crate) struct Throttled<T> {
+    inner: T,
+    limiter: RateLimiter,
+}
+
+impl<T> Throttled<T> {
+    pub(crate) fn new(inner: T, limiter: RateLimiter) -> Self {
+        Throttled { inner, limiter }
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.limiter.acquire(read);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.limiter.acquire(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -95,21 +95,14 @@ impl<K: Ord, V> RBTree<K, V> {
 
     pub fn delete(&mut self, key: &K) {
         unsafe {
-            let mut parent = null_mut();
             let mut node = self.root;
 
             // Find the node to delete
             while !node.is_null() {
                 node = match (*node).key.cmp(key) {
-                    Ordering::Less => {
-                        parent = node;
-                        (*node).right
-                    }
+                    Ordering::Less => (*node).right,
                     Ordering::Equal => break,
-                    Ordering::Greater => {
-                        parent = node;
-                        (*node).left
-                    }
+                    Ordering::Greater => (*node).left,
                 };
             }
 
@@ -117,58 +110,56 @@ impl<K: Ord, V> RBTree<K, V> {
                 return; // Key not found
             }
 
-            let cl = (*node).left;
-            let cr = (*node).right;
-
-            // Special case: deleting the root and the only node
-            if node == self.root && cl.is_null() && cr.is_null() {
-                self.root = null_mut();
-                drop(Box::from_raw(node));
-                return;
-            }
-
+            // `child` takes the place of the node that is physically unlinked; `parent` is its new
+            // parent, tracked separately because `child` may be null.
+            let child;
+            let parent;
             let deleted_color;
 
-            if cl.is_null() {
-                replace_node(self, parent, node, cr);
+            if (*node).left.is_null() {
+                child = (*node).right;
+                parent = (*node).parent;
                 deleted_color = (*node).color;
-            } else if cr.is_null() {
-                replace_node(self, parent, node, cl);
+                replace_node(self, node, child);
+            } else if (*node).right.is_null() {
+                child = (*node).left;
+                parent = (*node).parent;
                 deleted_color = (*node).color;
+                replace_node(self, node, child);
             } else {
+                // Two children: splice out the successor and move it into node's place
                 let mut victim = (*node).right;
                 while !(*victim).left.is_null() {
                     victim = (*victim).left;
                 }
 
-                let vp = (*victim).parent;
-                let vr = (*victim).right;
+                child = (*victim).right;
+                deleted_color = (*victim).color;
 
-                if victim != (*node).right {
-                    (*vp).left = vr;
-                    if !vr.is_null() {
-                        (*vr).parent = vp;
-                    }
+                if (*victim).parent == node {
+                    parent = victim;
+                } else {
+                    parent = (*victim).parent;
+                    replace_node(self, victim, child);
                     (*victim).right = (*node).right;
                     (*(*victim).right).parent = victim;
                 }
 
-                replace_node(self, parent, node, victim);
-                (*victim).left = cl;
-                (*cl).parent = victim;
-                deleted_color = (*victim).color;
+                replace_node(self, node, victim);
+                (*victim).left = (*node).left;
+                (*(*victim).left).parent = victim;
                 (*victim).color = (*node).color;
             }
 
             drop(Box::from_raw(node));
 
             if matches!(deleted_color, Color::Black) {
-                delete_fixup(self, parent);
+                delete_fixup(self, child, parent);
             }
         }
     }
 
-    pub fn iter<'a>(&self) -> RBTreeIterator<'a, K, V> {
+    pub fn iter(&self) -> RBTreeIterator<'_, K, V> {
         let mut iterator = RBTreeIterator { stack: Vec::new() };
         let mut node = self.root;
         unsafe {
@@ -179,8 +170,76 @@ impl<K: Ord, V> RBTree<K, V> {
         }
         iterator
     }
+
+    /// In-order iterator starting at the first key that is `>= key`.
+    pub fn iter_from(&self, key: &K) -> RBTreeIterator<'_, K, V> {
+        let mut iterator = RBTreeIterator { stack: Vec::new() };
+        let mut node = self.root;
+        unsafe {
+            while !node.is_null() {
+                if (*node).key < *key {
+                    node = (*node).right;
+                } else {
+                    iterator.stack.push(&*node);
+                    node = (*node).left;
+                }
+            }
+        }
+        iterator
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for RBTree<K, V> {
+    /// Copies the nodes in place, colours included, instead of inserting every key again.
+    fn clone(&self) -> Self {
+        RBTree {
+            root: unsafe { clone_subtree(self.root, null_mut()) },
+        }
+    }
+}
+
+/// Copies `node` and everything below it, hanging the copy under `parent`. The recursion is only
+/// as deep as the tree, which balancing keeps logarithmic.
+unsafe fn clone_subtree<K: Ord + Clone, V: Clone>(
+    node: *mut RBNode<K, V>,
+    parent: *mut RBNode<K, V>,
+) -> *mut RBNode<K, V> {
+    if node.is_null() {
+        return null_mut();
+    }
+
+    let copy = Box::into_raw(Box::new(RBNode {
+        key: (*node).key.clone(),
+        value: (*node).value.clone(),
+        color: (*node).color,
+        parent,
+        left: null_mut(),
+        right: null_mut(),
+    }));
+    (*copy).left = clone_subtree((*node).left, copy);
+    (*copy).right = clone_subtree((*node).right, copy);
+    copy
+}
+
+impl<K: Ord, V> Drop for RBTree<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut stack = vec![self.root];
+            while let Some(node) = stack.pop() {
+                if !node.is_null() {
+                    stack.push((*node).left);
+                    stack.push((*node).right);
+                    drop(Box::from_raw(node));
+                }
+            }
+        }
+    }
 }
 
+// The tree owns every node exclusively, so it can move between threads like a `Box` would.
+unsafe impl<K: Ord + Send, V: Send> Send for RBTree<K, V> {}
+unsafe impl<K: Ord + Sync, V: Sync> Sync for RBTree<K, V> {}
+
 #[inline]
 unsafe fn insert_fixup<K: Ord, V>(tree: &mut RBTree<K, V>, mut node: *mut RBNode<K, V>) {
     let mut parent: *mut RBNode<K, V> = (*node).parent;
@@ -307,25 +366,27 @@ unsafe fn insert_fixup<K: Ord, V>(tree: &mut RBTree<K, V>, mut node: *mut RBNode
 }
 
 #[inline]
-unsafe fn delete_fixup<K: Ord, V>(tree: &mut RBTree<K, V>, mut parent: *mut RBNode<K, V>) {
-    let mut node: *mut RBNode<K, V> = null_mut();
+fn is_black<K: Ord, V>(node: *mut RBNode<K, V>) -> bool {
+    node.is_null() || unsafe { matches!((*node).color, Color::Black) }
+}
+
+#[inline]
+unsafe fn delete_fixup<K: Ord, V>(
+    tree: &mut RBTree<K, V>,
+    mut node: *mut RBNode<K, V>,
+    mut parent: *mut RBNode<K, V>,
+) {
     let mut sibling: *mut RBNode<K, V>;
-    /* sl and sr denote left and right child of sibling, respectively. */
-    let mut sl: *mut RBNode<K, V>;
-    let mut sr: *mut RBNode<K, V>;
 
-    loop {
-        /*
-         * Loop invariants:
-         * - node is black (or null on first iteration)
-         * - node is not the root (so parent is not null)
-         * - All leaf paths going through parent and node have a
-         *   black node count that is 1 lower than other leaf paths.
-         */
-        sibling = (*parent).right;
-        if node != sibling {
-            /* node = (*parent).left */
-            if matches!((*sibling).color, Color::Red) {
+    /*
+     * Loop invariants:
+     * - node is black (or null), carrying an extra black
+     * - parent is node's parent, and not null unless node is the root
+     */
+    while node != tree.root && is_black(node) {
+        if node == (*parent).left {
+            sibling = (*parent).right;
+            if !is_black(sibling) {
                 /*
                  * Case 1 - left rotate at parent
                  *
@@ -336,113 +397,111 @@ unsafe fn delete_fixup<K: Ord, V>(tree: &mut RBTree<K, V>, mut parent: *mut RBNo
                  *    Sl  Sr      N  Sl
                  */
 
-                left_rotate(tree, parent);
-                (*parent).color = Color::Red;
                 (*sibling).color = Color::Black;
+                (*parent).color = Color::Red;
+                left_rotate(tree, parent);
                 sibling = (*parent).right;
             }
-            sl = (*sibling).left;
-            sr = (*sibling).right;
 
-            if !sl.is_null() && matches!((*sl).color, Color::Red) {
+            if is_black((*sibling).left) && is_black((*sibling).right) {
                 /*
-                 * Case 2 - right rotate at sibling and then left rotate at parent
-                 * (p and sr could be either color here)
+                 * Case 2 - color flip, then recurse at parent
                  *
-                 *   (p)             (p)              (sl)
-                 *   / \             / \              / \
-                 *  N   S    -->    N   sl    -->    P   S
-                 *     / \                \         /     \
-                 *    sl (sr)              S       N      (sr)
-                 *                          \
-                 *                          (sr)
+                 *   (p)             (p)
+                 *   / \             / \
+                 *  N   S    -->    N   s
+                 *     / \             / \
+                 *    Sl  Sr          Sl  Sr
                  */
 
-                (*sl).color = (*parent).color;
-                (*parent).color = Color::Black;
-                right_rotate(tree, sibling);
-                left_rotate(tree, parent);
-            } else if !sr.is_null() && matches!((*sr).color, Color::Red) {
-                /*
-                 * Case 3 - left rotate at parent
-                 * (p could be either color here)
-                 *
-                 *   (p)               S
-                 *   / \              / \
-                 *  N   S    -->    (p) (sr)
-                 *     / \          / \
-                 *    Sl  sr       N   Sl
-                 */
+                (*sibling).color = Color::Red;
+                node = parent;
+                parent = (*node).parent;
+                continue;
+            }
 
-                (*sr).color = (*parent).color;
-                left_rotate(tree, parent);
-            } else {
+            if is_black((*sibling).right) {
                 /*
-                 * Case 4 - color clip
-                 * (p could be either color here)
+                 * Case 3 - right rotate at sibling (then Case 4)
                  *
                  *   (p)             (p)
                  *   / \             / \
-                 *  N   S    -->    N   s
-                 *     / \             / \
-                 *    Sl  Sr          Sl  Sr
+                 *  N   S    -->    N   Sl
+                 *     / \              \
+                 *    sl  Sr             s
+                 *                        \
+                 *                        Sr
                  */
 
+                (*(*sibling).left).color = Color::Black;
                 (*sibling).color = Color::Red;
-                if matches!((*parent).color, Color::Black) {
-                    node = parent;
-                    parent = (*node).parent;
-                    continue;
-                }
-                (*parent).color = Color::Black;
+                right_rotate(tree, sibling);
+                sibling = (*parent).right;
             }
+
+            /*
+             * Case 4 - left rotate at parent
+             *
+             *   (p)               (s)
+             *   / \              / \
+             *  N   S     -->    P   Sr
+             *     / \          / \
+             *   (sl) sr       N  (sl)
+             */
+
+            (*sibling).color = (*parent).color;
+            (*parent).color = Color::Black;
+            (*(*sibling).right).color = Color::Black;
+            left_rotate(tree, parent);
         } else {
-            /* node = (*parent).right */
             sibling = (*parent).left;
-            if matches!((*sibling).color, Color::Red) {
+            if !is_black(sibling) {
                 /*
                  * Case 1 - right rotate at parent
                  */
 
-                right_rotate(tree, parent);
-                (*parent).color = Color::Red;
                 (*sibling).color = Color::Black;
-                sibling = (*parent).right;
+                (*parent).color = Color::Red;
+                right_rotate(tree, parent);
+                sibling = (*parent).left;
             }
-            sl = (*sibling).left;
-            sr = (*sibling).right;
 
-            if !sr.is_null() && matches!((*sr).color, Color::Red) {
+            if is_black((*sibling).left) && is_black((*sibling).right) {
                 /*
-                 * Case 2 - left rotate at sibling and then right rotate at parent
+                 * Case 2 - color flip, then recurse at parent
                  */
 
-                (*sr).color = (*parent).color;
-                (*parent).color = Color::Black;
-                left_rotate(tree, sibling);
-                right_rotate(tree, parent);
-            } else if !sl.is_null() && matches!((*sl).color, Color::Red) {
-                /*
-                 * Case 3 - right rotate at parent
-                 */
+                (*sibling).color = Color::Red;
+                node = parent;
+                parent = (*node).parent;
+                continue;
+            }
 
-                (*sl).color = (*parent).color;
-                right_rotate(tree, parent);
-            } else {
+            if is_black((*sibling).left) {
                 /*
-                 * Case 4 - color flip
+                 * Case 3 - left rotate at sibling (then Case 4)
                  */
 
+                (*(*sibling).right).color = Color::Black;
                 (*sibling).color = Color::Red;
-                if matches!((*parent).color, Color::Black) {
-                    node = parent;
-                    parent = (*node).parent;
-                    continue;
-                }
-                (*parent).color = Color::Black;
+                left_rotate(tree, sibling);
+                sibling = (*parent).left;
             }
+
+            /*
+             * Case 4 - right rotate at parent
+             */
+
+            (*sibling).color = (*parent).color;
+            (*parent).color = Color::Black;
+            (*(*sibling).left).color = Color::Black;
+            right_rotate(tree, parent);
         }
-        break;
+        node = tree.root;
+    }
+
+    if !node.is_null() {
+        (*node).color = Color::Black;
     }
 }
 
@@ -516,13 +575,14 @@ unsafe fn right_rotate<K: Ord, V>(tree: &mut RBTree<K, V>, x: *mut RBNode<K, V>)
     (*y).parent = p;
 }
 
+/// Puts `new` (which may be null) where `node` hangs in the tree.
 #[inline]
 unsafe fn replace_node<K: Ord, V>(
     tree: &mut RBTree<K, V>,
-    parent: *mut RBNode<K, V>,
     node: *mut RBNode<K, V>,
     new: *mut RBNode<K, V>,
 ) {
+    let parent = (*node).parent;
     if parent.is_null() {
         tree.root = new;
     } else if (*parent).left == node {
@@ -530,6 +590,9 @@ unsafe fn replace_node<K: Ord, V>(
     } else {
         (*parent).right = new;
     }
+    if !new.is_null() {
+        (*new).parent = parent;
+    }
 }
 
 pub struct RBTreeIterator<'a, K: Ord, V> {
@@ -554,3 +617,112 @@ impl<'a, K: Ord, V> Iterator for RBTreeIterator<'a, K, V> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Checks order, parent pointers and the red-black rules below `node`, returning its black
+    /// height.
+    unsafe fn check_subtree(node: *mut RBNode<u32, u32>, parent: *mut RBNode<u32, u32>) -> usize {
+        if node.is_null() {
+            return 1;
+        }
+        assert!(
+            (*node).parent == parent,
+            "parent pointer of {}",
+            (*node).key
+        );
+        if matches!((*node).color, Color::Red) {
+            assert!(is_black((*node).left) && is_black((*node).right));
+        }
+        if !(*node).left.is_null() {
+            assert!((*(*node).left).key < (*node).key);
+        }
+        if !(*node).right.is_null() {
+            assert!((*(*node).right).key > (*node).key);
+        }
+
+        let left = check_subtree((*node).left, node);
+        let right = check_subtree((*node).right, node);
+        assert_eq!(left, right, "black height below {}", (*node).key);
+        left + is_black(node) as usize
+    }
+
+    fn check(tree: &RBTree<u32, u32>, model: &BTreeMap<u32, u32>) {
+        unsafe {
+            assert!(is_black(tree.root));
+            check_subtree(tree.root, null_mut());
+        }
+        let entries: Vec<(u32, u32)> = tree.iter().map(|node| (node.key, node.value)).collect();
+        let expected: Vec<(u32, u32)> = model.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, expected);
+    }
+
+    /// A fixed permutation of `0..n`, so every run exercises the same rotations.
+    fn shuffled(n: u32, seed: u32) -> Vec<u32> {
+        let mut keys: Vec<u32> = (0..n).collect();
+        let mut state = seed;
+        for i in (1..keys.len()).rev() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            keys.swap(i, (state >> 8) as usize % (i + 1));
+        }
+        keys
+    }
+
+    #[test]
+    fn delete_keeps_the_tree_balanced() {
+        for seed in 0..8 {
+            let mut tree = RBTree::new();
+            let mut model = BTreeMap::new();
+            for key in shuffled(200, seed) {
+                tree.insert(key, key * 2);
+                model.insert(key, key * 2);
+            }
+            check(&tree, &model);
+
+            for key in shuffled(200, seed + 100) {
+                tree.delete(&key);
+                model.remove(&key);
+                check(&tree, &model);
+                assert!(tree.find(&key).is_none());
+            }
+            assert!(tree.root.is_null());
+        }
+    }
+
+    #[test]
+    fn delete_of_a_missing_key_changes_nothing() {
+        let mut tree = RBTree::new();
+        let mut model = BTreeMap::new();
+        for key in (0..50).map(|key| key * 2) {
+            tree.insert(key, key);
+            model.insert(key, key);
+        }
+        for key in (0..50).map(|key| key * 2 + 1) {
+            tree.delete(&key);
+        }
+        check(&tree, &model);
+    }
+
+    #[test]
+    fn clone_copies_structure_and_leaves_the_original_alone() {
+        let mut tree = RBTree::new();
+        let mut model = BTreeMap::new();
+        for key in shuffled(100, 7) {
+            tree.insert(key, key);
+            model.insert(key, key);
+        }
+
+        let mut copy = tree.clone();
+        let mut copy_model = model.clone();
+        check(&copy, &copy_model);
+        for key in 0..50 {
+            copy.delete(&key);
+            copy_model.remove(&key);
+        }
+        check(&copy, &copy_model);
+        check(&tree, &model);
+    }
+}
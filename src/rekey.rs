@@ -0,0 +1,105 @@
+use crate::Error;
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::Path,
+};
+
+const REKEY_FILE: &str = "REKEY";
+
+/// Records a `DiskStorage::rekey` in progress, so opening the store after a crash can tell
+/// whether the data files under the old key or the one that replaces them are the ones to keep.
+///
+/// It is written before the files are rewritten into `merged_id` and marked `committed` once
+/// that file is complete, before any of the files it replaces are removed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RekeyManifest {
+    pub merged_id: u32,
+    pub replaces: Vec<u32>,
+    pub committed: bool,
+}
+
+impl RekeyManifest {
+    pub(crate) fn read(dir: &Path) -> Result<Option<Self>, Error> {
+        let contents = match fs::read_to_string(dir.join(REKEY_FILE)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut lines = contents.lines();
+        let merged_id = lines
+            .next()
+            .and_then(|line| line.strip_prefix("merged "))
+            .ok_or("malformed rekey manifest")?
+            .parse()?;
+        let replaces = lines
+            .next()
+            .and_then(|line| line.strip_prefix("replaces"))
+            .ok_or("malformed rekey manifest")?
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        let committed = lines.next() == Some("committed");
+
+        Ok(Some(RekeyManifest {
+            merged_id,
+            replaces,
+            committed,
+        }))
+    }
+
+    /// Replaces the manifest in `dir` with this one, durably.
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        let replaces: String = self.replaces.iter().map(|id| format!(" {}", id)).collect();
+        let mut contents = format!("merged {}\nreplaces{}\n", self.merged_id, replaces);
+        if self.committed {
+            contents.push_str("committed\n");
+        }
+
+        let staged = dir.join(format!("{}.tmp", REKEY_FILE));
+        fs::write(&staged, contents)?;
+        File::open(&staged)?.sync_all()?;
+        fs::rename(&staged, dir.join(REKEY_FILE))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    pub(crate) fn remove(dir: &Path) -> Result<(), Error> {
+        fs::remove_file(dir.join(REKEY_FILE))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// Finishes or undoes a rekey of the store in `dir` that a crash interrupted: once committed,
+/// the files under the old key that are left are removed; before that, the partly rewritten
+/// file is, and the store stays under the old key.
+pub(crate) fn recover(dir: &Path) -> Result<(), Error> {
+    let Some(manifest) = RekeyManifest::read(dir)? else {
+        return Ok(());
+    };
+
+    let (remove, outcome) = match manifest.committed {
+        true => (manifest.replaces.clone(), "completed"),
+        false => (vec![manifest.merged_id], "rolled back"),
+    };
+    for id in remove {
+        for name in [format!("{}.db", id), format!("{}.db.tmp", id)] {
+            match fs::remove_file(dir.join(name)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+    }
+    RekeyManifest::remove(dir)?;
+    log::warn!(
+        "{} a rekey of {} that was interrupted",
+        outcome,
+        dir.display()
+    );
+
+    Ok(())
+}
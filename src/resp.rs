@@ -0,0 +1,522 @@
+use crate::batch::WriteBatch;
+use crate::disk_store::DiskStorage;
+use crate::lru::Lru;
+use crate::Error;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Largest bulk string a client may send, matching Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// How many unfinished `SCAN`s the server can resume; the least recently used are forgotten.
+const MAX_CURSORS: usize = 1024;
+
+/// Serves Redis clients speaking RESP2 on `addr`, one thread per connection, all sharing
+/// `store`. Runs until the listener fails.
+///
+/// Expiries set with `EXPIRE` or `SET .. EX` are kept in memory only: keys outlive them if the
+/// server stops first, and other handles on the store never see them.
+pub fn serve(store: Arc<Mutex<DiskStorage>>, addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("serving RESP on {}", listener.local_addr()?);
+
+    let server = Arc::new(Server {
+        store,
+        expiries: Mutex::new(HashMap::new()),
+        cursors: Mutex::new(Lru::new(MAX_CURSORS)),
+        last_cursor: AtomicU64::new(0),
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let server = server.clone();
+
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|addr| addr.to_string());
+            let peer = peer.unwrap_or_else(|_| "unknown peer".to_string());
+            log::debug!("{} connected", peer);
+            if let Err(err) = server.handle(stream) {
+                log::debug!("{} disconnected: {}", peer, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+struct Server {
+    store: Arc<Mutex<DiskStorage>>,
+    /// Deadlines set with `EXPIRE` or `SET .. EX`. They live only as long as the server, so a
+    /// restart forgets them; expired keys are deleted the next time they are looked at.
+    expiries: Mutex<HashMap<String, Instant>>,
+    /// The last key returned by each unfinished `SCAN`, under the cursor handed out with it.
+    cursors: Mutex<Lru<u64, String>>,
+    last_cursor: AtomicU64,
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(message) => write!(out, "-{}\r\n", message),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+impl Server {
+    fn handle(&self, stream: TcpStream) -> Result<(), Error> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(err) => {
+                    Reply::Error(format!("ERR Protocol error: {}", err)).write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Err(err);
+                }
+            };
+            let Some(name) = args.first() else {
+                continue;
+            };
+
+            if name.eq_ignore_ascii_case(b"QUIT") {
+                Reply::Status("OK").write_to(&mut writer)?;
+                break;
+            }
+            self.execute(&args).write_to(&mut writer)?;
+
+            // Pipelined commands are answered together once the client stops sending.
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        match self.run(&name, &args[1..]) {
+            Ok(reply) => reply,
+            Err(err) => Reply::Error(format!("ERR {}", err)),
+        }
+    }
+
+    fn run(&self, name: &str, args: &[Vec<u8>]) -> Result<Reply, Error> {
+        let wrong_arity = || -> Error {
+            format!(
+                "wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )
+            .into()
+        };
+        let mut store = self.store.lock().unwrap();
+
+        let reply = match (name, args) {
+            ("PING", []) => Reply::Status("PONG"),
+            ("PING", [message]) => Reply::Bulk(Some(message.clone())),
+            // Sent by redis-cli on connect; an empty command table is enough for it.
+            ("COMMAND", _) => Reply::Array(vec![]),
+            ("GET", [key]) => {
+                let key = key_str(key)?;
+                self.expire_if_due(&mut store, key)?;
+                Reply::Bulk(store.get(key))
+            }
+            ("SET", [key, value, options @ ..]) => {
+                let key = key_str(key)?;
+                let deadline = match options {
+                    [] => None,
+                    [unit, amount] if unit.eq_ignore_ascii_case(b"EX") => {
+                        Some(deadline(parse_int(amount)?, Duration::from_secs, "set")?)
+                    }
+                    [unit, amount] if unit.eq_ignore_ascii_case(b"PX") => {
+                        Some(deadline(parse_int(amount)?, Duration::from_millis, "set")?)
+                    }
+                    _ => return Err("syntax error".into()),
+                };
+
+                store.set(key, value)?;
+                let mut expiries = self.expiries.lock().unwrap();
+                match deadline {
+                    Some(deadline) => expiries.insert(key.to_string(), deadline),
+                    None => expiries.remove(key),
+                };
+                Reply::Status("OK")
+            }
+            ("DEL", [_, ..]) => {
+                let mut deleted = 0;
+                for key in args {
+                    let key = key_str(key)?;
+                    self.expire_if_due(&mut store, key)?;
+                    if store.contains(key) {
+                        store.delete(key)?;
+                        self.expiries.lock().unwrap().remove(key);
+                        deleted += 1;
+                    }
+                }
+                Reply::Integer(deleted)
+            }
+            ("EXISTS", [_, ..]) => {
+                let mut found = 0;
+                for key in args {
+                    let key = key_str(key)?;
+                    self.expire_if_due(&mut store, key)?;
+                    found += store.contains(key) as i64;
+                }
+                Reply::Integer(found)
+            }
+            ("MGET", [_, ..]) => {
+                let mut values = vec![];
+                for key in args {
+                    let key = key_str(key)?;
+                    self.expire_if_due(&mut store, key)?;
+                    values.push(Reply::Bulk(store.get(key)));
+                }
+                Reply::Array(values)
+            }
+            ("MSET", [_, _, ..]) if args.len().is_multiple_of(2) => {
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| Ok((key_str(&pair[0])?, &pair[1])))
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                let mut batch = WriteBatch::new();
+                for (key, value) in &pairs {
+                    batch.put(key, value);
+                }
+                store.apply_batch(&batch)?;
+
+                let mut expiries = self.expiries.lock().unwrap();
+                for (key, _) in pairs {
+                    expiries.remove(key);
+                }
+                Reply::Status("OK")
+            }
+            ("EXPIRE", [key, seconds]) => {
+                let key = key_str(key)?;
+                let seconds: i64 = std::str::from_utf8(seconds)?.parse()?;
+                let deadline = match seconds {
+                    ..=0 => None,
+                    _ => Some(deadline(seconds, Duration::from_secs, "expire")?),
+                };
+                self.expire_if_due(&mut store, key)?;
+                if !store.contains(key) {
+                    Reply::Integer(0)
+                } else if let Some(deadline) = deadline {
+                    self.expiries
+                        .lock()
+                        .unwrap()
+                        .insert(key.to_string(), deadline);
+                    Reply::Integer(1)
+                } else {
+                    store.delete(key)?;
+                    self.expiries.lock().unwrap().remove(key);
+                    Reply::Integer(1)
+                }
+            }
+            ("KEYS", [pattern]) => {
+                self.expire_all_due(&mut store)?;
+                let keys = store
+                    .keys(literal_prefix(pattern))
+                    .filter(|key| glob_match(pattern, key.as_bytes()))
+                    .map(|key| Reply::Bulk(Some(key.as_bytes().to_vec())))
+                    .collect();
+                Reply::Array(keys)
+            }
+            ("SCAN", [cursor, options @ ..]) => {
+                // A cursor other than 0 stands for the last key returned, so keys written or
+                // deleted between calls do not shift the ones still to come.
+                let after = match parse_int::<u64>(cursor)? {
+                    0 => None,
+                    id => {
+                        let after = self.cursors.lock().unwrap().get(&id).cloned();
+                        Some(after.ok_or("invalid cursor")?)
+                    }
+                };
+                let mut pattern: &[u8] = b"*";
+                let mut count = 10;
+                for option in options.chunks(2) {
+                    match option {
+                        [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+                        [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                            count = parse_int::<usize>(value)?.max(1)
+                        }
+                        _ => return Err("syntax error".into()),
+                    }
+                }
+
+                self.expire_all_due(&mut store)?;
+                let prefix = literal_prefix(pattern);
+                let start = match after.as_deref() {
+                    Some(after) if after > prefix => after,
+                    _ => prefix,
+                };
+                let mut batch: Vec<&str> = store
+                    .keys_from(start)
+                    .skip_while(|key| Some(*key) == after.as_deref())
+                    .take_while(|key| key.starts_with(prefix))
+                    .take(count + 1)
+                    .collect();
+                let next = match batch.len() > count {
+                    true => {
+                        batch.pop();
+                        let id = self.last_cursor.fetch_add(1, Ordering::Relaxed) + 1;
+                        let last = batch[batch.len() - 1].to_string();
+                        self.cursors.lock().unwrap().insert(id, last, 1);
+                        id
+                    }
+                    false => 0,
+                };
+
+                let keys = batch
+                    .into_iter()
+                    .filter(|key| glob_match(pattern, key.as_bytes()))
+                    .map(|key| Reply::Bulk(Some(key.as_bytes().to_vec())))
+                    .collect();
+                Reply::Array(vec![
+                    Reply::Bulk(Some(next.to_string().into_bytes())),
+                    Reply::Array(keys),
+                ])
+            }
+            ("INFO", [] | [_]) => {
+                self.expire_all_due(&mut store)?;
+                let stats = store.stats();
+                let expiring = self.expiries.lock().unwrap().len();
+
+                let mut info = String::new();
+                info.push_str("# Server\r\n");
+                info.push_str(&format!(
+                    "cask_db_version:{}\r\n",
+                    env!("CARGO_PKG_VERSION")
+                ));
+                info.push_str("# Stats\r\n");
+                info.push_str(&format!("sequence:{}\r\n", store.sequence()));
+                info.push_str(&format!("tombstones:{}\r\n", stats.tombstones));
+                info.push_str(&format!("total_bytes:{}\r\n", stats.total_bytes));
+                info.push_str(&format!("live_bytes:{}\r\n", stats.live_bytes));
+                info.push_str(&format!("dead_bytes:{}\r\n", stats.dead_bytes));
+                info.push_str(&format!("data_files:{}\r\n", stats.files.len()));
+                info.push_str("# Keyspace\r\n");
+                info.push_str(&format!(
+                    "db0:keys={},expires={}\r\n",
+                    stats.live_keys, expiring
+                ));
+                Reply::Bulk(Some(info.into_bytes()))
+            }
+            (
+                "PING" | "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "EXPIRE" | "KEYS"
+                | "SCAN" | "INFO",
+                _,
+            ) => return Err(wrong_arity()),
+            _ => return Err(format!("unknown command '{}'", name.to_lowercase()).into()),
+        };
+
+        Ok(reply)
+    }
+
+    /// Deletes `key` if its expiry has passed.
+    fn expire_if_due(&self, store: &mut DiskStorage, key: &str) -> Result<(), Error> {
+        let mut expiries = self.expiries.lock().unwrap();
+        if expiries.get(key).is_some_and(|at| *at <= Instant::now()) {
+            store.delete(key)?;
+            expiries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes every key whose expiry has passed, before commands that list keys.
+    fn expire_all_due(&self, store: &mut DiskStorage) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut expiries = self.expiries.lock().unwrap();
+        let due: Vec<String> = expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in due {
+            store.delete(&key)?;
+            expiries.remove(&key);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads one command, either a RESP array of bulk strings or an inline command line.
+/// Returns `None` once the client has closed the connection.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    };
+
+    let count: usize = parse_int(count)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or("unexpected end of stream")?;
+        let len = line.strip_prefix(b"$").ok_or("expected '$'")?;
+        let len: usize = parse_int(len)?;
+        if len > MAX_BULK_LEN {
+            return Err("invalid bulk length".into());
+        }
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err("expected CRLF after bulk string".into());
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_int<T: std::str::FromStr>(bytes: &[u8]) -> Result<T, Error> {
+    std::str::from_utf8(bytes)?
+        .parse()
+        .map_err(|_| "value is not an integer or out of range".into())
+}
+
+/// When a key given `amount` of `unit` to live expires. Amounts that are not positive, or that
+/// reach past what an `Instant` can hold, are rejected as Redis does.
+fn deadline(amount: i64, unit: fn(u64) -> Duration, command: &str) -> Result<Instant, Error> {
+    u64::try_from(amount)
+        .ok()
+        .filter(|amount| *amount > 0)
+        .and_then(|amount| Instant::now().checked_add(unit(amount)))
+        .ok_or_else(|| format!("invalid expire time in '{}' command", command).into())
+}
+
+fn key_str(key: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(key).map_err(|_| "keys must be valid UTF-8".into())
+}
+
+/// The part of a glob pattern before its first special character, which every match starts
+/// with, so listing can start from there instead of the first key.
+fn literal_prefix(pattern: &[u8]) -> &str {
+    let end = pattern
+        .iter()
+        .position(|byte| b"*?[\\".contains(byte))
+        .unwrap_or(pattern.len());
+    // Cut back to a character boundary if the pattern itself is not valid UTF-8 there.
+    match std::str::from_utf8(&pattern[..end]) {
+        Ok(prefix) => prefix,
+        Err(err) => std::str::from_utf8(&pattern[..err.valid_up_to()]).unwrap(),
+    }
+}
+
+/// Redis-style glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            let rest = &rest[rest.iter().take_while(|byte| **byte == b'*').count()..];
+            (0..=text.len()).any(|skip| glob_match(rest, &text[skip..]))
+        }
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&byte, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => return false, // Unterminated class
+                    [b']', after @ ..] => {
+                        return matched != negate && glob_match(after, text_rest);
+                    }
+                    [b'\\', escaped, after @ ..] => {
+                        matched |= *escaped == byte;
+                        class = after;
+                    }
+                    [low, b'-', high, after @ ..] if *high != b']' => {
+                        let (low, high) = if low <= high {
+                            (low, high)
+                        } else {
+                            (high, low)
+                        };
+                        matched |= (*low..=*high).contains(&byte);
+                        class = after;
+                    }
+                    [other, after @ ..] => {
+                        matched |= *other == byte;
+                        class = after;
+                    }
+                }
+            }
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((literal, rest)) => text.first() == Some(literal) && glob_match(rest, &text[1..]),
+    }
+}
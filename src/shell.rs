@@ -0,0 +1,130 @@
+use crate::disk_store::DiskStorage;
+use crate::Error;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use std::{cell::RefCell, path::PathBuf, rc::Rc};
+
+const COMMANDS: [&str; 9] = [
+    "get", "set", "del", "scan", "stats", "merge", "help", "exit", "quit",
+];
+
+const HELP: &str = "\
+get <key>           print the value of <key>
+set <key> <value>   store <value> (the rest of the line) under <key>
+del <key>           delete <key>
+scan [prefix]       list keys and values starting with [prefix]
+stats               show store statistics
+merge               compact the sealed data files
+exit | quit         leave the shell";
+
+/// Completes command names, then keys for commands that take one.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+    store: Rc<RefCell<DiskStorage>>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+
+        if start == 0 {
+            let commands = COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| command.to_string())
+                .collect();
+            return Ok((0, commands));
+        }
+
+        let mut args = line.split_whitespace();
+        let takes_key = matches!(args.next(), Some("get" | "set" | "del" | "scan"));
+        if !takes_key || line[..start].split_whitespace().count() > 1 {
+            return Ok((start, vec![]));
+        }
+
+        let store = self.store.borrow();
+        Ok((start, store.keys(word).map(String::from).collect()))
+    }
+}
+
+/// Runs an interactive prompt against `store` until the user exits or sends EOF.
+pub fn run(store: DiskStorage) -> Result<(), Error> {
+    let store = Rc::new(RefCell::new(store));
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper {
+        store: store.clone(),
+    }));
+
+    let history_path =
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cask_db_history"));
+    if let Some(path) = &history_path {
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let line = match editor.readline("cask> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        match execute(&mut store.borrow_mut(), line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => println!("error: {}", err),
+        }
+    }
+
+    if let Some(path) = &history_path {
+        editor.save_history(path)?;
+    }
+
+    Ok(())
+}
+
+/// Runs one shell line, returning `false` when the shell should exit.
+fn execute(store: &mut DiskStorage, line: &str) -> Result<bool, Error> {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim_start();
+
+    match command {
+        "get" => match store.get(rest) {
+            Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+            None => println!("(not found)"),
+        },
+        "set" => {
+            let (key, value) = rest.split_once(' ').ok_or("usage: set <key> <value>")?;
+            store.set(key, value.as_bytes())?;
+        }
+        "del" => store.delete(rest)?,
+        "scan" => {
+            for (key, value) in store.scan(rest) {
+                println!("{}: {}", key, String::from_utf8_lossy(&value));
+            }
+        }
+        "stats" => println!("{}", store.stats()),
+        "merge" => store.merge()?,
+        "help" => println!("{}", HELP),
+        "exit" | "quit" => return Ok(false),
+        _ => println!("unknown command: {} (try `help`)", command),
+    }
+
+    Ok(true)
+}
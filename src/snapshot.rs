@@ -0,0 +1,62 @@
+use crate::disk_store::read_value;
+use crate::encryption::Cipher;
+use crate::format::{FileFormat, KeyEntry};
+use crate::rb_trees::RBTree;
+use std::{collections::HashMap, fs::File, sync::Arc};
+
+/// A read-only, point-in-time view of a `DiskStorage`, created with `DiskStorage::snapshot`.
+///
+/// Data files referenced by the snapshot are held open until it is dropped, so they stay
+/// readable even if `merge` replaces them on disk in the meantime.
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: u64,
+    key_dir: Arc<RBTree<String, KeyEntry>>,
+    files: HashMap<u32, (Arc<File>, FileFormat)>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        sequence: u64,
+        key_dir: Arc<RBTree<String, KeyEntry>>,
+        files: HashMap<u32, (Arc<File>, FileFormat)>,
+        cipher: Option<Arc<Cipher>>,
+    ) -> Self {
+        Snapshot {
+            sequence,
+            key_dir,
+            files,
+            cipher,
+        }
+    }
+
+    /// Sequence number of the last write visible to this snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key_entry = self.key_dir.find(&key.to_string())?;
+        let (file, format) = &self.files[&key_entry.file_id];
+        read_value(file, *format, key_entry, self.cipher.as_deref())
+    }
+
+    /// Returns every key in `[start, end)` with its value, in key order.
+    pub fn range(&self, start: &str, end: &str) -> Vec<(String, Vec<u8>)> {
+        self.scan_from(start)
+            .take_while(|(key, _)| key.as_str() < end)
+            .collect()
+    }
+
+    /// Lazily yields every key from `start` onwards and its value, in key order.
+    pub fn scan_from(&self, start: &str) -> impl Iterator<Item = (String, Vec<u8>)> + '_ {
+        self.key_dir
+            .iter_from(&start.to_string())
+            .filter_map(|node| {
+                let (file, format) = &self.files[&node.value.file_id];
+                let value = read_value(file, *format, &node.value, self.cipher.as_deref())?;
+                Some((node.key.clone(), value))
+            })
+    }
+}
@@ -0,0 +1,281 @@
+use crate::format::KeyEntry;
+use serde::Serialize;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::Display,
+};
+
+/// Space usage of one data file.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FileStats {
+    pub file_id: u32,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub live_keys: u64,
+}
+
+/// A point-in-time summary of a store, returned by `DiskStorage::stats`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pub live_keys: u64,
+    /// Delete records still on disk; they count as live bytes until a merge can drop them.
+    pub tombstones: u64,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    /// Write timestamps (seconds since the epoch) of the oldest and newest live keys.
+    pub oldest_timestamp: Option<usize>,
+    pub newest_timestamp: Option<usize>,
+    /// Rough size of the in-memory key directory.
+    pub key_dir_bytes: u64,
+    pub avg_key_size: f64,
+    pub avg_value_size: f64,
+    pub files: Vec<FileStats>,
+    /// The bytes-per-second budget of merges, backups and exports, if any.
+    pub io_rate_limit: Option<u64>,
+    /// Time merges, backups and exports have spent waiting on that budget since the store
+    /// was opened, and how many times they had to wait.
+    pub throttled_ms: u64,
+    pub throttle_waits: u64,
+    /// Data file read handles currently held open by the handle pool.
+    pub open_files: u64,
+    /// Bytes held by the value cache, and how many `get`s it answered or missed.
+    pub cache_bytes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Fraction of `get`s the value cache answered, once it has been consulted at all.
+    pub cache_hit_rate: Option<f64>,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = |t: Option<usize>| t.map_or("-".to_string(), |t| t.to_string());
+
+        writeln!(f, "live keys:        {}", self.live_keys)?;
+        writeln!(f, "tombstones:       {}", self.tombstones)?;
+        writeln!(f, "total bytes:      {}", self.total_bytes)?;
+        writeln!(f, "live bytes:       {}", self.live_bytes)?;
+        writeln!(f, "dead bytes:       {}", self.dead_bytes)?;
+        writeln!(f, "oldest timestamp: {}", timestamp(self.oldest_timestamp))?;
+        writeln!(f, "newest timestamp: {}", timestamp(self.newest_timestamp))?;
+        writeln!(f, "key dir bytes:    {}", self.key_dir_bytes)?;
+        writeln!(f, "avg key size:     {:.1}", self.avg_key_size)?;
+        writeln!(f, "avg value size:   {:.1}", self.avg_value_size)?;
+        match self.io_rate_limit {
+            Some(rate) => writeln!(f, "io rate limit:    {} bytes/s", rate)?,
+            None => writeln!(f, "io rate limit:    -")?,
+        }
+        writeln!(
+            f,
+            "throttled:        {} ms over {} waits",
+            self.throttled_ms, self.throttle_waits
+        )?;
+        writeln!(f, "open files:       {}", self.open_files)?;
+        writeln!(f, "cache bytes:      {}", self.cache_bytes)?;
+        match self.cache_hit_rate {
+            Some(rate) => writeln!(
+                f,
+                "cache hit rate:   {:.1}% ({} hits, {} misses)",
+                rate * 100.0,
+                self.cache_hits,
+                self.cache_misses
+            )?,
+            None => writeln!(f, "cache hit rate:   -")?,
+        }
+        write!(f, "files:")?;
+        for file in &self.files {
+            write!(
+                f,
+                "\n  {}.db: {} bytes, {} live, {} dead, {} keys",
+                file.file_id, file.total_bytes, file.live_bytes, file.dead_bytes, file.live_keys
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The sequence numbers of the records in one data file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SeqRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl SeqRange {
+    pub(crate) fn new(seq: u64) -> Self {
+        SeqRange { min: seq, max: seq }
+    }
+
+    /// Accounts for the record after the last one.
+    pub(crate) fn push(&mut self, seq: u64) {
+        self.min = self.min.min(seq);
+        self.max = self.max.max(seq);
+    }
+}
+
+/// Counters `DiskStorage` keeps up to date on every write so `stats` never has to scan.
+#[derive(Debug, Default)]
+pub(crate) struct StatsTracker {
+    files: BTreeMap<u32, FileStats>,
+    /// Sequence numbers written to each file.
+    seqs: BTreeMap<u32, SeqRange>,
+    /// Tombstones kept in each file, so removing a file removes its tombstones too.
+    file_tombstones: BTreeMap<u32, u64>,
+    tombstones: u64,
+    live_keys: u64,
+    live_key_bytes: u64,
+    live_value_bytes: u64,
+    /// Multiset of live key timestamps, so the oldest survives deletes of other keys.
+    timestamps: BTreeMap<usize, u64>,
+}
+
+impl StatsTracker {
+    pub(crate) fn add_file(&mut self, file_id: u32) {
+        self.files.entry(file_id).or_insert(FileStats {
+            file_id,
+            ..FileStats::default()
+        });
+    }
+
+    pub(crate) fn remove_file(&mut self, file_id: u32) {
+        self.files.remove(&file_id);
+        self.seqs.remove(&file_id);
+        self.tombstones -= self.file_tombstones.remove(&file_id).unwrap_or(0);
+    }
+
+    pub(crate) fn file(&self, file_id: u32) -> Option<&FileStats> {
+        self.files.get(&file_id)
+    }
+
+    pub(crate) fn file_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.files.keys().copied()
+    }
+
+    pub(crate) fn min_seq(&self, file_id: u32) -> Option<u64> {
+        self.seqs.get(&file_id).map(|seqs| seqs.min)
+    }
+
+    pub(crate) fn seqs(&self, file_id: u32) -> Option<SeqRange> {
+        self.seqs.get(&file_id).copied()
+    }
+
+    /// Accounts for a record appended to (or loaded from) a data file.
+    pub(crate) fn record_written(&mut self, file_id: u32, size: usize, seq: u64) {
+        self.add_file(file_id);
+        self.files.get_mut(&file_id).unwrap().total_bytes += size as u64;
+        match self.seqs.entry(file_id) {
+            Entry::Occupied(mut entry) => entry.get_mut().push(seq),
+            Entry::Vacant(entry) => {
+                entry.insert(SeqRange::new(seq));
+            }
+        }
+    }
+
+    /// Accounts for the marker written ahead of the records of a single write, which is dead
+    /// from the start.
+    pub(crate) fn record_marker(&mut self, file_id: u32, size: usize) {
+        self.add_file(file_id);
+        self.files.get_mut(&file_id).unwrap().total_bytes += size as u64;
+    }
+
+    /// Accounts for the record a merge leaves in its file to tell where the history of changes
+    /// starts. It stays live until the file is merged in turn, so files holding little else
+    /// do not look worth merging.
+    pub(crate) fn record_history(&mut self, file_id: u32, size: usize) {
+        self.add_file(file_id);
+        let file = self.files.get_mut(&file_id).unwrap();
+        file.total_bytes += size as u64;
+        file.live_bytes += size as u64;
+    }
+
+    /// Accounts for the records a merge wrote to a new file.
+    pub(crate) fn record_merged(&mut self, file_id: u32, size: usize, seqs: SeqRange) {
+        self.add_file(file_id);
+        self.files.get_mut(&file_id).unwrap().total_bytes += size as u64;
+        self.seqs.insert(file_id, seqs);
+    }
+
+    /// Accounts for a tombstone record that has to stay on disk.
+    pub(crate) fn add_tombstone(&mut self, file_id: u32, size: usize) {
+        self.add_file(file_id);
+        self.files.get_mut(&file_id).unwrap().live_bytes += size as u64;
+        *self.file_tombstones.entry(file_id).or_default() += 1;
+        self.tombstones += 1;
+    }
+
+    /// Accounts for `key_entry` becoming the live record of a key.
+    pub(crate) fn add_live(&mut self, key_size: usize, value_size: usize, key_entry: &KeyEntry) {
+        self.add_file(key_entry.file_id);
+        let file = self.files.get_mut(&key_entry.file_id).unwrap();
+        file.live_bytes += key_entry.total_size as u64;
+        file.live_keys += 1;
+
+        self.live_keys += 1;
+        self.live_key_bytes += key_size as u64;
+        self.live_value_bytes += value_size as u64;
+        *self.timestamps.entry(key_entry.timestamp).or_default() += 1;
+    }
+
+    /// Accounts for `key_entry` becoming the live record of a key the store keeps for itself,
+    /// which takes up space but is not counted as a key.
+    pub(crate) fn add_internal(&mut self, key_entry: &KeyEntry) {
+        self.add_file(key_entry.file_id);
+        self.files.get_mut(&key_entry.file_id).unwrap().live_bytes += key_entry.total_size as u64;
+    }
+
+    pub(crate) fn remove_internal(&mut self, key_entry: &KeyEntry) {
+        if let Some(file) = self.files.get_mut(&key_entry.file_id) {
+            file.live_bytes -= key_entry.total_size as u64;
+        }
+    }
+
+    /// Accounts for `key_entry` no longer being the live record of a key.
+    pub(crate) fn remove_live(&mut self, key_size: usize, value_size: usize, key_entry: &KeyEntry) {
+        if let Some(file) = self.files.get_mut(&key_entry.file_id) {
+            file.live_bytes -= key_entry.total_size as u64;
+            file.live_keys -= 1;
+        }
+
+        self.live_keys -= 1;
+        self.live_key_bytes -= key_size as u64;
+        self.live_value_bytes -= value_size as u64;
+        if let Some(count) = self.timestamps.get_mut(&key_entry.timestamp) {
+            *count -= 1;
+            if *count == 0 {
+                self.timestamps.remove(&key_entry.timestamp);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self, key_dir_node_size: usize) -> Stats {
+        let files: Vec<FileStats> = self
+            .files
+            .values()
+            .map(|file| FileStats {
+                dead_bytes: file.total_bytes - file.live_bytes,
+                ..*file
+            })
+            .collect();
+        let average = |total: u64| match self.live_keys {
+            0 => 0.0,
+            keys => total as f64 / keys as f64,
+        };
+
+        Stats {
+            live_keys: self.live_keys,
+            tombstones: self.tombstones,
+            total_bytes: files.iter().map(|file| file.total_bytes).sum(),
+            live_bytes: files.iter().map(|file| file.live_bytes).sum(),
+            dead_bytes: files.iter().map(|file| file.dead_bytes).sum(),
+            oldest_timestamp: self.timestamps.keys().next().copied(),
+            newest_timestamp: self.timestamps.keys().next_back().copied(),
+            key_dir_bytes: self.live_keys * key_dir_node_size as u64 + self.live_key_bytes,
+            avg_key_size: average(self.live_key_bytes),
+            avg_value_size: average(self.live_value_bytes),
+            files,
+            ..Stats::default()
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::disk_store::DiskStorage;
+use crate::rate_limit::Throttled;
+use crate::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+/// One exported key-value pair. Values that are not valid UTF-8 are base64 encoded and marked
+/// with `encoding: "base64"`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Record {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+impl Record {
+    pub(crate) fn new(key: String, value: Vec<u8>) -> Self {
+        let (value, encoding) = encode_value(value);
+        Record {
+            key,
+            value,
+            encoding,
+        }
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn into_pair(self) -> Result<(String, Vec<u8>), Error> {
+        let value = decode_value(self.value, self.encoding.as_deref())?;
+        Ok((self.key, value))
+    }
+}
+
+/// A value as JSON text: the value itself if it is UTF-8, otherwise its base64 along with the
+/// encoding `"base64"`.
+pub(crate) fn encode_value(value: Vec<u8>) -> (String, Option<String>) {
+    match String::from_utf8(value) {
+        Ok(value) => (value, None),
+        Err(err) => (STANDARD.encode(err.as_bytes()), Some("base64".to_string())),
+    }
+}
+
+/// Reverses `encode_value`.
+pub(crate) fn decode_value(value: String, encoding: Option<&str>) -> Result<Vec<u8>, Error> {
+    match encoding {
+        None | Some("") | Some("utf8") => Ok(value.into_bytes()),
+        Some("base64") => Ok(STANDARD.decode(value)?),
+        Some(other) => Err(format!("unknown value encoding: {}", other).into()),
+    }
+}
+
+/// Streams every live key starting with `prefix` to `writer`, returning how many were written.
+pub fn export(
+    store: &DiskStorage,
+    format: Format,
+    prefix: &str,
+    writer: impl Write,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    let writer = BufWriter::new(Throttled::new(writer, store.io_limiter().clone()));
+
+    match format {
+        Format::Jsonl => {
+            let mut writer = writer;
+            for (key, value) in store.scan(prefix) {
+                serde_json::to_writer(&mut writer, &Record::new(key, value))?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value", "encoding"])?;
+            for (key, value) in store.scan(prefix) {
+                let record = Record::new(key, value);
+                let encoding = record.encoding.as_deref().unwrap_or("utf8");
+                writer.write_record([&record.key, &record.value, encoding])?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Loads every record from `reader` into `store`, returning how many were imported.
+pub fn import(store: &mut DiskStorage, format: Format, reader: impl Read) -> Result<usize, Error> {
+    let mut count = 0;
+
+    match format {
+        Format::Jsonl => {
+            for line in BufReader::new(reader).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (key, value) = serde_json::from_str::<Record>(&line)?.into_pair()?;
+                store.set(&key, &value)?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            for record in csv::Reader::from_reader(reader).deserialize() {
+                let (key, value) = Record::into_pair(record?)?;
+                store.set(&key, &value)?;
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,133 @@
+use crate::checksum::Checksum;
+use crate::disk_store::file_ids_in;
+use crate::error::DbError;
+use crate::format::{FileFormat, KeyValue, RECORD_HEADER_SIZE};
+use crate::Error;
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+
+/// Size of the record header in data files written before records carried a sequence number:
+/// checksum, timestamp, key size and value size. Deletes were not written to disk then, so
+/// every record is a put.
+const BASELINE_HEADER_SIZE: usize = 28;
+
+/// Size of the record in the layout without sequence numbers that starts with `header`, if the
+/// header is all there.
+fn baseline_size(header: &[u8]) -> Option<usize> {
+    let header = header.get(..BASELINE_HEADER_SIZE)?;
+    let key_size = usize::from_be_bytes(header[12..20].try_into().ok()?);
+    let value_size = usize::from_be_bytes(header[20..28].try_into().ok()?);
+    key_size
+        .checked_add(value_size)?
+        .checked_add(BASELINE_HEADER_SIZE)
+}
+
+/// The record in the layout without sequence numbers at the start of `bytes`, if it is all
+/// there and its CRC-32, over the timestamp, key and value, matches.
+fn baseline_record(bytes: &[u8]) -> Option<&[u8]> {
+    let record = bytes.get(..baseline_size(bytes)?)?;
+
+    let mut digest = Checksum::Crc32.digest();
+    digest.update(&record[4..12]);
+    digest.update(&record[BASELINE_HEADER_SIZE..]);
+    let crc = u32::from_be_bytes(record[..4].try_into().ok()?);
+    (digest.finalize() == crc).then_some(record)
+}
+
+/// Converts a record in the layout without sequence numbers, giving it `seq`.
+fn convert(record: &[u8], seq: u64) -> Result<KeyValue, Error> {
+    let timestamp = usize::from_be_bytes(record[4..12].try_into()?);
+    let key_size = usize::from_be_bytes(record[12..20].try_into()?);
+    let key = record[BASELINE_HEADER_SIZE..BASELINE_HEADER_SIZE + key_size].to_vec();
+    let value = record[BASELINE_HEADER_SIZE + key_size..].to_vec();
+
+    Ok(KeyValue::with_flags(
+        timestamp,
+        seq,
+        0,
+        String::from_utf8(key)?,
+        value,
+    ))
+}
+
+/// Rewrites the data files under `dir` that are in the record layout without sequence numbers
+/// into the current format with `checksum`, keeping their ids. Files are replaced one at a
+/// time, so an upgrade cut short picks up where it stopped the next time the store is opened.
+pub(crate) fn upgrade_dir(dir: &str, checksum: Checksum) -> Result<(), Error> {
+    let ids = file_ids_in(dir)?;
+    let mut old = vec![];
+    for &id in &ids {
+        old.push(is_unsequenced(&data_path(dir, id))?);
+    }
+    if !old.contains(&true) {
+        return Ok(());
+    }
+
+    // Records without a sequence number are numbered in the order they were written, after
+    // those of the files before them that already have one.
+    let mut next_seq = 1;
+    for (&id, unsequenced) in ids.iter().zip(old) {
+        let path = data_path(dir, id);
+        let bytes = fs::read(&path)?;
+        if !unsequenced {
+            next_seq = next_seq.max(max_seq(&bytes)? + 1);
+            continue;
+        }
+
+        log::info!(
+            "upgrading {} from the record layout without sequence numbers",
+            path.display()
+        );
+        let format = FileFormat::V1(checksum);
+        let mut upgraded = format.header();
+        let mut position = 0;
+        while position < bytes.len() {
+            let record = baseline_record(&bytes[position..]).ok_or(DbError::Corruption {
+                file_id: id,
+                position,
+            })?;
+
+            let kv = convert(record, next_seq)?;
+            next_seq += 1;
+            upgraded.extend(kv.to_bytes(format, None)?);
+            position += record.len();
+        }
+
+        let staged = path.with_extension("db.upgrade");
+        fs::write(&staged, &upgraded)?;
+        File::open(&staged)?.sync_all()?;
+        fs::rename(&staged, &path)?;
+    }
+
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn data_path(dir: &str, id: u32) -> std::path::PathBuf {
+    Path::new(dir).join(format!("{}.db", id))
+}
+
+/// Whether the data file at `path` is in the record layout without sequence numbers, which
+/// every file without a header is.
+fn is_unsequenced(path: &Path) -> Result<bool, Error> {
+    Ok(!FileFormat::has_header(&File::open(path)?)?)
+}
+
+/// The highest sequence number of the records in the data file `bytes`.
+fn max_seq(bytes: &[u8]) -> Result<u64, Error> {
+    let format = FileFormat::detect(bytes)?;
+    let mut position = format.header_size();
+    let mut max = 0;
+    while position + RECORD_HEADER_SIZE <= bytes.len() {
+        let (_, _, seq, _, key_size, value_size) = KeyValue::decode_header(&bytes[position..])?;
+        max = max.max(seq);
+        match KeyValue::record_size(key_size, value_size) {
+            Some(size) if size <= bytes.len() - position => position += size,
+            _ => break,
+        }
+    }
+    Ok(max)
+}
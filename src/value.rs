@@ -0,0 +1,198 @@
+use crate::checksum::Digest;
+use crate::positioned::PositionedIo;
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    ops::Deref,
+    sync::Arc,
+};
+
+/// A value read with `DiskStorage::get_ref`. Values stored in memory-mapped data files are
+/// borrowed straight from the mapping, which stays alive as long as the `ValueRef` does;
+/// other values are read into a buffer the `ValueRef` owns.
+#[derive(Debug, Clone)]
+pub struct ValueRef {
+    inner: Inner,
+}
+
+#[derive(Debug, Clone)]
+enum Inner {
+    Owned(Vec<u8>),
+    Mapped {
+        map: Arc<Mmap>,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl ValueRef {
+    pub(crate) fn owned(value: Vec<u8>) -> Self {
+        ValueRef {
+            inner: Inner::Owned(value),
+        }
+    }
+
+    pub(crate) fn mapped(map: Arc<Mmap>, start: usize, end: usize) -> Self {
+        ValueRef {
+            inner: Inner::Mapped { map, start, end },
+        }
+    }
+
+    /// Whether the value is borrowed from a memory map rather than copied.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.inner, Inner::Mapped { .. })
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.inner {
+            Inner::Owned(value) => value,
+            Inner::Mapped { map, start, end } => map[start..end].to_vec(),
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            Inner::Owned(value) => value,
+            Inner::Mapped { map, start, end } => &map[*start..*end],
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// A value read a piece at a time with `DiskStorage::get_reader`, so large values need not fit
+/// in memory.
+///
+/// Reading the value through to its end checks the record's checksum on the way, failing the
+/// final read with `InvalidData` if it does not match. Seeking anywhere but the current
+/// position gives up on the check. Compressed and encrypted values cannot be decoded piece by
+/// piece and are read into memory whole when the reader is created.
+pub struct ValueReader {
+    source: ReaderSource,
+}
+
+enum ReaderSource {
+    File {
+        file: Arc<File>,
+        /// Where the value starts in the data file.
+        start: u64,
+        len: u64,
+        position: u64,
+        /// The checksum so far and the one stored in the record, while reading sequentially.
+        digest: Option<(Digest, u32)>,
+    },
+    Buffered(Cursor<ValueRef>),
+}
+
+impl ValueReader {
+    pub(crate) fn file(file: Arc<File>, start: u64, len: u64, digest: Digest, crc: u32) -> Self {
+        ValueReader {
+            source: ReaderSource::File {
+                file,
+                start,
+                len,
+                position: 0,
+                digest: Some((digest, crc)),
+            },
+        }
+    }
+
+    pub(crate) fn buffered(value: ValueRef) -> Self {
+        ValueReader {
+            source: ReaderSource::Buffered(Cursor::new(value)),
+        }
+    }
+
+    /// Length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        match &self.source {
+            ReaderSource::File { len, .. } => *len,
+            ReaderSource::Buffered(cursor) => cursor.get_ref().len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (file, start, len, position, digest) = match &mut self.source {
+            ReaderSource::Buffered(cursor) => return cursor.read(buf),
+            ReaderSource::File {
+                file,
+                start,
+                len,
+                position,
+                digest,
+            } => (file, *start, *len, position, digest),
+        };
+
+        if *position >= len {
+            if let Some((digest, crc)) = digest.take() {
+                if digest.finalize() != crc {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "value does not match its checksum",
+                    ));
+                }
+            }
+            return Ok(0);
+        }
+
+        let wanted = buf.len().min((len - *position) as usize);
+        let read = file.read_at(&mut buf[..wanted], start + *position)?;
+        if read == 0 && wanted > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some((digest, _)) = digest {
+            digest.update(&buf[..read]);
+        }
+        *position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for ValueReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (len, position, digest) = match &mut self.source {
+            ReaderSource::Buffered(cursor) => return cursor.seek(pos),
+            ReaderSource::File {
+                len,
+                position,
+                digest,
+                ..
+            } => (*len, position, digest),
+        };
+
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+
+        if target != *position {
+            *digest = None;
+        }
+        *position = target;
+
+        Ok(target)
+    }
+}
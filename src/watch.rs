@@ -0,0 +1,84 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// A change to a key of the default namespace, delivered to the receivers of
+/// `DiskStorage::watch` once it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `key` was set by the write with sequence number `seq`. The value is left out for values
+    /// streamed in with `set_from_reader`, which are never held in memory.
+    Put {
+        key: String,
+        seq: u64,
+        value: Option<Vec<u8>>,
+    },
+    /// `key` was deleted by the write with sequence number `seq`.
+    Delete { key: String, seq: u64 },
+    /// The receiver fell behind and `missed` events were dropped before the next one.
+    Lagged { missed: u64 },
+}
+
+impl Event {
+    fn key(&self) -> Option<&str> {
+        match self {
+            Event::Put { key, .. } | Event::Delete { key, .. } => Some(key),
+            Event::Lagged { .. } => None,
+        }
+    }
+}
+
+/// The sending side of one `DiskStorage::watch` subscription.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    prefix: String,
+    sender: SyncSender<Event>,
+    /// Events dropped since the receiver last had room, to be reported before the next one.
+    missed: u64,
+}
+
+impl Watcher {
+    /// A watcher of the keys starting with `prefix` whose receiver buffers up to `capacity`
+    /// events, but at least one.
+    pub(crate) fn new(prefix: &str, capacity: usize) -> (Self, Receiver<Event>) {
+        // A channel without room hands events over only to a receiver already waiting, which
+        // a sender that never blocks cannot count on.
+        let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+        let watcher = Watcher {
+            prefix: prefix.to_string(),
+            sender,
+            missed: 0,
+        };
+        (watcher, receiver)
+    }
+
+    /// Passes on `event` if its key is watched, without ever blocking the writer: an event
+    /// that finds the buffer full is dropped and counted instead. Returns `false` once the
+    /// receiver is gone.
+    pub(crate) fn send(&mut self, event: &Event) -> bool {
+        if !event.key().is_some_and(|key| key.starts_with(&self.prefix)) {
+            return true;
+        }
+
+        if self.missed > 0 {
+            let lagged = Event::Lagged {
+                missed: self.missed,
+            };
+            match self.sender.try_send(lagged) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        match self.sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "async")]
+
+use cask_db::{async_db::AsyncDb, batch::WriteBatch, error::DbError, options::DiskStorageOptions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio_stream::StreamExt;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+async fn open(dir: &Path) -> AsyncDb {
+    let options = DiskStorageOptions {
+        max_key_size: 16,
+        ..Default::default()
+    };
+    AsyncDb::open(Some(dir.to_string_lossy().into_owned()), options)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn reads_writes_and_streams_through_the_async_handle() {
+    let dir = temp_dir("async");
+    let db = open(&dir).await;
+    db.set("user:1", b"ada".to_vec()).await.unwrap();
+    db.set("user:2", b"bob".to_vec()).await.unwrap();
+    db.set("user:3", b"cy".to_vec()).await.unwrap();
+    db.set("zebra", b"z".to_vec()).await.unwrap();
+    db.delete("user:2").await.unwrap();
+    assert_eq!(db.get("user:1").await.unwrap(), Some(b"ada".to_vec()));
+    assert_eq!(db.get("user:2").await.unwrap(), None);
+
+    let mut batch = WriteBatch::new();
+    batch.put("user:4", b"dee").delete("zebra");
+    db.apply_batch(batch).await.unwrap();
+
+    // Writes made while a stream is open are not seen by it.
+    let scan = db.scan("user:").await.unwrap();
+    db.set("user:5", b"eve".to_vec()).await.unwrap();
+    let keys: Vec<String> = scan.map(|(key, _)| key).collect().await;
+    assert_eq!(keys, ["user:1", "user:3", "user:4"]);
+
+    let range: Vec<_> = db.range("user:3", "user:5").await.unwrap().collect().await;
+    assert_eq!(
+        range,
+        [
+            ("user:3".to_string(), b"cy".to_vec()),
+            ("user:4".to_string(), b"dee".to_vec())
+        ]
+    );
+
+    // Clones share the store, and errors keep their type across the blocking pool.
+    let clone = db.clone();
+    let err = clone
+        .set("a key that is too long", vec![])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DbError>(),
+        Some(DbError::KeyTooLarge { .. })
+    ));
+
+    clone.merge().await.unwrap();
+    let stats = db.stats().await.unwrap();
+    assert_eq!(stats.live_keys, 4);
+    assert_eq!(stats.dead_bytes, 0);
+    drop((db, clone));
+
+    let db = open(&dir).await;
+    assert_eq!(db.get("user:5").await.unwrap(), Some(b"eve".to_vec()));
+    assert_eq!(db.get("zebra").await.unwrap(), None);
+}
@@ -0,0 +1,121 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn restore_waits_for_the_store_to_be_closed() {
+    let dir = temp_dir("restore-locked");
+    let backup = temp_dir("restore-locked-backup");
+    let mut store = open(&dir);
+    store.set("a", b"1").unwrap();
+    store.checkpoint(&path_str(&backup)).unwrap();
+    store.set("a", b"2").unwrap();
+
+    let err = DiskStorage::restore(&path_str(&backup), Some(path_str(&dir))).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("{} is locked by another open store", dir.display())
+    );
+    assert_eq!(store.get("a"), Some(b"2".to_vec()));
+    drop(store);
+
+    DiskStorage::restore(&path_str(&backup), Some(path_str(&dir))).unwrap();
+    assert_eq!(open(&dir).get("a"), Some(b"1".to_vec()));
+}
+
+/// The data files a backup in `dir` holds, by name.
+fn backed_up(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".db"))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn restore_replays_an_incremental_chain() {
+    let dir = temp_dir("backup-chain");
+    let backups = temp_dir("backup-chain-backups");
+    let restored = temp_dir("backup-chain-restored");
+    let mut store = open(&dir);
+    for key in ["a", "b", "c", "d"] {
+        store.set(key, key.as_bytes()).unwrap();
+    }
+    let full = store.checkpoint_incremental(&path_str(&backups)).unwrap();
+
+    store.set("a", b"2").unwrap();
+    store.delete("b").unwrap();
+    store.set("e", b"e").unwrap();
+    let second = store.checkpoint_incremental(&path_str(&backups)).unwrap();
+
+    // Only files that are new or have grown since the full backup were copied again.
+    let unchanged: Vec<String> = full
+        .files
+        .iter()
+        .filter(|file| second.files.contains(file))
+        .map(|(id, _)| format!("{}.db", id))
+        .collect();
+    assert!(!unchanged.is_empty());
+    let copied = backed_up(&backups.join("1"));
+    assert!(unchanged.iter().all(|name| !copied.contains(name)));
+    assert_eq!(copied.len(), second.files.len() - unchanged.len());
+
+    store.merge().unwrap();
+    store.set("c", b"3").unwrap();
+    store.checkpoint_incremental(&path_str(&backups)).unwrap();
+    store.set("a", b"after the last backup").unwrap();
+    drop(store);
+
+    DiskStorage::restore(&path_str(&backups), Some(path_str(&restored))).unwrap();
+    let store = open(&restored);
+    assert_eq!(store.get("a"), Some(b"2".to_vec()));
+    assert_eq!(store.get("b"), None);
+    assert_eq!(store.get("c"), Some(b"3".to_vec()));
+    assert_eq!(store.get("d"), Some(b"d".to_vec()));
+    assert_eq!(store.get("e"), Some(b"e".to_vec()));
+    assert_eq!(store.stats().live_keys, 4);
+}
+
+#[test]
+fn chain_missing_a_file_is_not_restored() {
+    let dir = temp_dir("backup-broken-chain");
+    let backups = temp_dir("backup-broken-chain-backups");
+    let mut store = open(&dir);
+    for key in ["a", "b", "c", "d"] {
+        store.set(key, key.as_bytes()).unwrap();
+    }
+    store.checkpoint_incremental(&path_str(&backups)).unwrap();
+    store.set("e", b"e").unwrap();
+    store.checkpoint_incremental(&path_str(&backups)).unwrap();
+    store.set("a", b"2").unwrap();
+    drop(store);
+
+    // A file only the full backup holds has gone missing.
+    let lost = &backed_up(&backups.join("0"))[0];
+    fs::remove_file(backups.join("0").join(lost)).unwrap();
+
+    let err = DiskStorage::restore(&path_str(&backups), Some(path_str(&dir))).unwrap_err();
+    assert_eq!(err.to_string(), format!("backup is missing {}", lost));
+    // The store is left as it was.
+    assert_eq!(open(&dir).get("a"), Some(b"2".to_vec()));
+}
@@ -0,0 +1,107 @@
+use cask_db::{
+    batch::WriteBatch, disk_store::DiskStorage, options::DiskStorageOptions, stats::Stats,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path, value_cache_bytes: usize) -> DiskStorage {
+    let options = DiskStorageOptions {
+        value_cache_bytes,
+        ..Default::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+fn lookups(stats: &Stats) -> (u64, u64) {
+    (stats.cache_hits, stats.cache_misses)
+}
+
+#[test]
+fn cached_values_follow_sets_deletes_and_merges() {
+    let dir = temp_dir("cache");
+    let mut store = open(&dir, 1024);
+    for key in ["a", "b", "c", "d"] {
+        store.set(key, b"old").unwrap();
+    }
+    assert_eq!(store.get("a"), Some(b"old".to_vec()));
+    assert_eq!(store.get("a"), Some(b"old".to_vec()));
+    assert_eq!(lookups(&store.stats()), (1, 1));
+
+    store.set("a", b"new").unwrap();
+    assert_eq!(store.get("a"), Some(b"new".to_vec()));
+    store.delete("a").unwrap();
+    assert_eq!(store.get("a"), None);
+
+    store.get("b");
+    let mut batch = WriteBatch::new();
+    batch.put("b", b"batched").delete("c");
+    store.apply_batch(&batch).unwrap();
+    assert_eq!(store.get("b"), Some(b"batched".to_vec()));
+    assert_eq!(store.get("c"), None);
+
+    store.get("d");
+    store.set_from_reader("d", &b"streamed"[..], 8).unwrap();
+    assert_eq!(store.get("d"), Some(b"streamed".to_vec()));
+
+    // Merging moves the records but leaves the values, so what is cached stays good.
+    store.get("b");
+    let before = lookups(&store.stats());
+    store.merge().unwrap();
+    assert_eq!(store.get("b"), Some(b"batched".to_vec()));
+    assert_eq!(store.get("d"), Some(b"streamed".to_vec()));
+    assert_eq!(lookups(&store.stats()), (before.0 + 2, before.1));
+
+    store.namespace("ns").set("b", b"other").unwrap();
+    assert_eq!(store.namespace("ns").get("b"), Some(b"other".to_vec()));
+    assert_eq!(store.get("b"), Some(b"batched".to_vec()));
+}
+
+#[test]
+fn hit_rate_and_size_are_reported() {
+    let dir = temp_dir("cache-stats");
+    let mut store = open(&dir, 32);
+    assert_eq!(store.stats().cache_hit_rate, None);
+    store.set("k1", b"0123456789").unwrap();
+    store.set("k2", b"0123456789").unwrap();
+
+    for _ in 0..3 {
+        store.get("k1");
+    }
+    let stats = store.stats();
+    assert_eq!(lookups(&stats), (2, 1));
+    assert_eq!(stats.cache_hit_rate, Some(2.0 / 3.0));
+    assert_eq!(stats.cache_bytes, 12);
+    // Missing keys never reach the cache.
+    assert_eq!(store.get("missing"), None);
+    assert_eq!(lookups(&store.stats()), (2, 1));
+
+    // The second value fits alongside the first, a third pushes out the least recently used.
+    store.get("k2");
+    assert_eq!(store.stats().cache_bytes, 24);
+    store.set("k3", b"0123456789").unwrap();
+    store.get("k3");
+    store.get("k3");
+    assert!(store.stats().cache_bytes <= 32);
+    store.get("k1");
+    assert_eq!(lookups(&store.stats()), (3, 4));
+
+    // With no cache configured nothing is counted.
+    drop(store);
+    let store = open(&dir, 0);
+    store.get("k1");
+    let stats = store.stats();
+    assert_eq!(lookups(&stats), (0, 0));
+    assert_eq!(stats.cache_bytes, 0);
+    assert_eq!(stats.cache_hit_rate, None);
+}
@@ -0,0 +1,112 @@
+use cask_db::{batch::WriteBatch, changes::Change, disk_store::DiskStorage, error::DbError};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// The changes after `seq` as `(seq, key, value)`, with `None` for deletes.
+fn changes(store: &DiskStorage, seq: u64) -> Vec<(u64, String, Option<Vec<u8>>)> {
+    store
+        .changes_since(seq)
+        .unwrap()
+        .map(|change| {
+            let Change {
+                seq, key, value, ..
+            } = change.unwrap();
+            (seq, key, value)
+        })
+        .collect()
+}
+
+fn change(seq: u64, key: &str, value: Option<&[u8]>) -> (u64, String, Option<Vec<u8>>) {
+    (seq, key.to_string(), value.map(<[u8]>::to_vec))
+}
+
+#[test]
+fn changes_replay_every_write_in_order_with_deletes() {
+    let dir = temp_dir("changes");
+    let mut store = open(&dir);
+    store.set("a", b"1").unwrap();
+    store.set("b", b"2").unwrap();
+    store.delete("a").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put("c", b"3").put("b", b"4");
+    store.apply_batch(&batch).unwrap();
+    store.namespace("other").set("x", b"hidden").unwrap();
+    store.set("a", b"5").unwrap();
+
+    let all = changes(&store, 0);
+    let seqs: Vec<u64> = all.iter().map(|(seq, ..)| *seq).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+    let writes: Vec<_> = all
+        .iter()
+        .map(|(_, key, value)| (key.as_str(), value.as_deref()))
+        .collect();
+    assert_eq!(
+        writes,
+        [
+            ("a", Some(&b"1"[..])),
+            ("b", Some(&b"2"[..])),
+            ("a", None),
+            ("c", Some(&b"3"[..])),
+            ("b", Some(&b"4"[..])),
+            ("a", Some(&b"5"[..])),
+        ]
+    );
+
+    // Resuming after the delete picks up right behind it, also once the store is reopened.
+    let (deleted, ..) = all[2];
+    drop(store);
+    let store = open(&dir);
+    assert_eq!(changes(&store, deleted), all[3..]);
+    assert!(changes(&store, store.sequence()).is_empty());
+}
+
+#[test]
+fn changes_merged_away_are_reported_as_truncated() {
+    let dir = temp_dir("changes-truncated");
+    let mut store = open(&dir);
+    for (key, value) in [("a", "1"), ("b", "2"), ("a", "3"), ("c", "4"), ("d", "5")] {
+        store.set(key, value.as_bytes()).unwrap();
+    }
+    store.delete("b").unwrap();
+    store.set("e", b"6").unwrap();
+    let before_merge = store.sequence();
+    store.merge().unwrap();
+    store.set("f", b"7").unwrap();
+
+    let err = match store.changes_since(0) {
+        Ok(_) => panic!("changes since 0 survived the merge"),
+        Err(err) => err,
+    };
+    let Some(DbError::Truncated { seq, oldest }) = err.downcast_ref::<DbError>() else {
+        panic!("unexpected error {}", err);
+    };
+    assert_eq!(*seq, 0);
+    assert!(*oldest > 1 && *oldest <= before_merge + 1, "{}", oldest);
+
+    // From where the history starts on, every change is still there.
+    let resumed = changes(&store, oldest - 1);
+    assert_eq!(
+        resumed.last(),
+        Some(&change(store.sequence(), "f", Some(b"7")))
+    );
+    assert!(resumed.iter().all(|(seq, ..)| *seq >= *oldest));
+    drop(store);
+
+    let store = open(&dir);
+    assert!(store.changes_since(0).is_err());
+    assert_eq!(changes(&store, oldest - 1), resumed);
+}
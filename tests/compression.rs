@@ -0,0 +1,136 @@
+#![cfg(any(feature = "lz4", feature = "zstd"))]
+
+use cask_db::{compression::Compression, disk_store::DiskStorage, options::DiskStorageOptions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path, compression: Compression) -> DiskStorage {
+    let options = DiskStorageOptions {
+        compression,
+        compression_threshold: 64,
+        ..Default::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// A value that compresses well.
+fn repetitive(len: usize) -> Vec<u8> {
+    b"cask-db ".iter().copied().cycle().take(len).collect()
+}
+
+/// A value that does not compress at all.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// How many bytes writing `value` under `key` adds to the store.
+fn stored_size(store: &mut DiskStorage, key: &str, value: &[u8]) -> u64 {
+    let before = store.stats().total_bytes;
+    store.set(key, value).unwrap();
+    store.stats().total_bytes - before
+}
+
+fn round_trip(name: &str, compression: Compression) {
+    let dir = temp_dir(name);
+    let mut store = open(&dir, compression);
+    let big = repetitive(4096);
+    assert!(stored_size(&mut store, "big", &big) < 1024);
+    store.set("small", b"tiny").unwrap();
+    drop(store);
+
+    // Records say how they were written, so they read back under any setting.
+    let store = open(&dir, Compression::None);
+    assert_eq!(store.get("big"), Some(big));
+    assert_eq!(store.get("small"), Some(b"tiny".to_vec()));
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_values_round_trip() {
+    round_trip("compression-lz4", Compression::Lz4);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_values_round_trip() {
+    round_trip("compression-zstd", Compression::Zstd(3));
+}
+
+fn compression() -> Compression {
+    #[cfg(feature = "zstd")]
+    return Compression::Zstd(3);
+    #[cfg(not(feature = "zstd"))]
+    return Compression::Lz4;
+}
+
+#[test]
+fn only_values_past_the_threshold_that_shrink_are_compressed() {
+    let dir = temp_dir("compression-threshold");
+    let mut store = open(&dir, compression());
+
+    // Short of the threshold: stored as it is, however well it would compress.
+    let short = repetitive(63);
+    assert!(stored_size(&mut store, "short", &short) > 63);
+    let long = repetitive(512);
+    assert!(stored_size(&mut store, "long", &long) < 256);
+    // Compressing this would make it larger, so it is kept as it is too.
+    let random = noise(512);
+    assert!(stored_size(&mut store, "random", &random) > 512);
+    drop(store);
+
+    let store = open(&dir, compression());
+    assert_eq!(store.get("short"), Some(short));
+    assert_eq!(store.get("long"), Some(long));
+    assert_eq!(store.get("random"), Some(random));
+}
+
+#[test]
+fn compressed_records_survive_merge() {
+    let dir = temp_dir("compression-merge");
+    let mut store = open(&dir, compression());
+    for i in 0..6 {
+        store
+            .set(&format!("k{}", i), &repetitive(2048 + i))
+            .unwrap();
+    }
+    for i in 0..3 {
+        store
+            .set(&format!("k{}", i), &repetitive(1024 + i))
+            .unwrap();
+    }
+    store.set("plain", b"uncompressed").unwrap();
+    store.merge().unwrap();
+
+    // Merge copies the compressed bytes rather than inflating them.
+    let stats = store.stats();
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(stats.total_bytes < 6 * 1024, "{}", stats.total_bytes);
+    drop(store);
+
+    let store = open(&dir, Compression::None);
+    for i in 0..6 {
+        let len = if i < 3 { 1024 } else { 2048 } + i;
+        assert_eq!(store.get(&format!("k{}", i)), Some(repetitive(len)));
+    }
+    assert_eq!(store.get("plain"), Some(b"uncompressed".to_vec()));
+    assert!(DiskStorage::verify(&dir.to_string_lossy()).is_ok());
+}
@@ -0,0 +1,96 @@
+use cask_db::{disk_store::DiskStorage, options::DiskStorageOptions};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path, max_open_files: usize) -> DiskStorage {
+    let options = DiskStorageOptions {
+        max_open_files,
+        ..Default::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+const KEYS: [&str; 10] = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+
+/// Writes every key twice, the second time with its value in upper case, spreading them over
+/// several data files.
+fn fill(store: &mut DiskStorage) {
+    for round in 0..2 {
+        for key in KEYS {
+            let value = if round == 0 {
+                key.to_string()
+            } else {
+                key.to_uppercase()
+            };
+            store.set(key, value.as_bytes()).unwrap();
+        }
+    }
+}
+
+fn read_all(store: &DiskStorage) {
+    for key in KEYS {
+        assert_eq!(
+            store.get(key),
+            Some(key.to_uppercase().into_bytes()),
+            "{}",
+            key
+        );
+    }
+}
+
+#[test]
+fn pool_never_holds_more_than_max_open_files() {
+    let dir = temp_dir("handles");
+    let mut store = open(&dir, 2);
+    fill(&mut store);
+    assert!(store.stats().files.len() > 2);
+
+    for _ in 0..2 {
+        read_all(&store);
+        assert_eq!(store.stats().open_files, 2);
+    }
+}
+
+#[test]
+fn handles_of_merged_files_are_dropped() {
+    let dir = temp_dir("handles-merge");
+    let mut store = open(&dir, 64);
+    fill(&mut store);
+    read_all(&store);
+    let before = store.stats();
+    assert!(before.open_files >= 3, "{}", before.open_files);
+    let mut reader = store.get_reader("a").unwrap();
+
+    store.merge().unwrap();
+    // Only the files still on disk may be pooled; the merged ones are gone from the pool.
+    let after = store.stats();
+    assert!(after.files.len() < before.files.len());
+    assert!(after.open_files <= 1, "{}", after.open_files);
+    read_all(&store);
+    assert!(store.stats().open_files <= after.files.len() as u64);
+
+    // A reader made before the merge holds on to its file, so it still reads the old record.
+    let mut value = vec![];
+    reader.read_to_end(&mut value).unwrap();
+    assert_eq!(value, b"A");
+
+    store.set("a", b"new").unwrap();
+    assert_eq!(store.get("a"), Some(b"new".to_vec()));
+    drop(store);
+
+    let store = open(&dir, 1);
+    assert_eq!(store.get("a"), Some(b"new".to_vec()));
+    assert_eq!(store.get("j"), Some(b"J".to_vec()));
+}
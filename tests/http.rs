@@ -0,0 +1,186 @@
+use cask_db::{disk_store::DiskStorage, http, options::DiskStorageOptions};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::Value;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Serves a fresh store in a temporary directory, returning its address.
+fn serve(name: &str, options: DiskStorageOptions) -> String {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    let store = Arc::new(Mutex::new(store));
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let server_addr = addr.clone();
+    thread::spawn(move || {
+        let _ = http::serve(store, &server_addr);
+    });
+
+    addr
+}
+
+/// Sends one request on a connection of its own, returning the status code and body.
+fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = (0..100)
+        .find_map(|_| {
+            TcpStream::connect(addr)
+                .inspect_err(|_| thread::sleep(Duration::from_millis(20)))
+                .ok()
+        })
+        .unwrap_or_else(|| panic!("server on {} did not come up", addr));
+
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("response without a blank line after the headers");
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, response[split + 4..].to_vec())
+}
+
+fn json(addr: &str, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let (status, body) = request(addr, method, path, body.as_bytes());
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[test]
+fn missing_keys_are_not_found() {
+    let addr = serve("http", DiskStorageOptions::default());
+    assert_eq!(request(&addr, "PUT", "/keys/a%20b", b"1").0, 204);
+    assert_eq!(
+        request(&addr, "GET", "/keys/a%20b", b""),
+        (200, b"1".to_vec())
+    );
+
+    let (status, body) = json(&addr, "GET", "/keys/missing", "");
+    assert_eq!(status, 404);
+    assert!(
+        body["error"].as_str().unwrap().contains("missing"),
+        "{}",
+        body
+    );
+    assert_eq!(request(&addr, "DELETE", "/keys/missing", b"").0, 404);
+    assert_eq!(request(&addr, "GET", "/nowhere", b"").0, 404);
+
+    assert_eq!(request(&addr, "DELETE", "/keys/a%20b", b"").0, 204);
+    assert_eq!(request(&addr, "GET", "/keys/a%20b", b"").0, 404);
+}
+
+#[test]
+fn oversized_keys_and_values_are_refused() {
+    let options = DiskStorageOptions {
+        max_key_size: 16,
+        max_value_size: 32,
+        ..Default::default()
+    };
+    let addr = serve("http-too-large", options);
+    let long_key = format!("/keys/{}", "k".repeat(17));
+    assert_eq!(request(&addr, "PUT", &long_key, b"1").0, 413);
+    assert_eq!(request(&addr, "PUT", "/keys/k", &[b'v'; 33]).0, 413);
+    let batch = format!(
+        r#"[{{"op": "put", "key": "k", "value": "{}"}}]"#,
+        "v".repeat(33)
+    );
+    assert_eq!(json(&addr, "POST", "/batch", &batch).0, 413);
+
+    // Nothing of the refused writes made it into the store.
+    assert_eq!(request(&addr, "PUT", "/keys/k", &[b'v'; 32]).0, 204);
+    assert_eq!(request(&addr, "GET", "/keys/k", b"").1, [b'v'; 32]);
+    assert_eq!(request(&addr, "GET", &long_key, b"").0, 404);
+}
+
+#[test]
+fn malformed_batches_are_bad_requests() {
+    let addr = serve("http-batch", DiskStorageOptions::default());
+    for batch in [
+        "not json",
+        r#"{"op": "put", "key": "a", "value": "1"}"#,
+        r#"[{"op": "rename", "key": "a"}]"#,
+        r#"[{"op": "put", "key": "a"}]"#,
+        r#"[{"op": "put", "key": "a", "value": "!!", "encoding": "base64"}]"#,
+        r#"[{"op": "put", "key": "a", "value": "1", "encoding": "rot13"}]"#,
+    ] {
+        let (status, body) = json(&addr, "POST", "/batch", batch);
+        assert_eq!(status, 400, "{}: {}", batch, body);
+    }
+    // None of them was applied, not even in part.
+    assert_eq!(request(&addr, "GET", "/keys/a", b"").0, 404);
+
+    let batch = r#"[
+        {"op": "put", "key": "a", "value": "1"},
+        {"op": "put", "key": "b", "value": "AP8=", "encoding": "base64"},
+        {"op": "delete", "key": "a"}
+    ]"#;
+    let (status, body) = json(&addr, "POST", "/batch", batch);
+    assert_eq!(status, 200);
+    assert_eq!(body["applied"], 3);
+    assert_eq!(request(&addr, "GET", "/keys/a", b"").0, 404);
+    assert_eq!(request(&addr, "GET", "/keys/b", b"").1, [0x00, 0xff]);
+}
+
+#[test]
+fn keys_are_listed_a_page_at_a_time() {
+    let addr = serve("http-keys", DiskStorageOptions::default());
+    for key in ["p:1", "p:2", "p:3", "p:4", "p:5", "q:1"] {
+        assert_eq!(
+            request(&addr, "PUT", &format!("/keys/{}", key), b"x").0,
+            204
+        );
+    }
+
+    // Walk the pages two at a time, deleting a key already returned along the way.
+    let mut cursor = String::new();
+    let mut seen = vec![];
+    loop {
+        let path = format!(
+            "/keys?prefix=p%3A&limit=2&cursor={}",
+            utf8_percent_encode(&cursor, NON_ALPHANUMERIC)
+        );
+        let (status, page) = json(&addr, "GET", &path, "");
+        assert_eq!(status, 200, "{}", page);
+        let items = page["items"].as_array().unwrap();
+        assert!(items.len() <= 2, "{}", page);
+        seen.extend(
+            items
+                .iter()
+                .map(|item| item["key"].as_str().unwrap().to_string()),
+        );
+        if seen.len() == 2 {
+            assert_eq!(request(&addr, "DELETE", "/keys/p%3A1", b"").0, 204);
+        }
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = next.to_string(),
+            None => break,
+        }
+    }
+    assert_eq!(seen, ["p:1", "p:2", "p:3", "p:4", "p:5"]);
+
+    let (_, page) = json(&addr, "GET", "/keys", "");
+    assert_eq!(page["items"].as_array().unwrap().len(), 5);
+    assert!(page["next_cursor"].is_null());
+    assert_eq!(json(&addr, "GET", "/keys?limit=two", "").0, 400);
+}
@@ -0,0 +1,116 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Opens the store in `dir`, indexing each `name,tag,tag..` value under its tags if `indexed`.
+fn open(dir: &Path, indexed: bool) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    if indexed {
+        store
+            .register_index("tags", |value| {
+                value
+                    .split(|b| *b == b',')
+                    .skip(1)
+                    .map(<[u8]>::to_vec)
+                    .collect()
+            })
+            .unwrap();
+    }
+    store.init().unwrap();
+    store
+}
+
+fn keys<'a>(store: &'a DiskStorage, tag: &str) -> Vec<&'a str> {
+    store.index("tags").unwrap().keys(tag.as_bytes()).collect()
+}
+
+#[test]
+fn index_follows_overwrites_and_deletes() {
+    let dir = temp_dir("index");
+    let mut store = open(&dir, true);
+    store.set("u1", b"ada,london").unwrap();
+    store.set("u2", b"bob,paris").unwrap();
+    store.set("u3", b"cy,london,admin").unwrap();
+    assert_eq!(keys(&store, "london"), ["u1", "u3"]);
+    assert_eq!(keys(&store, "admin"), ["u3"]);
+
+    store.set("u1", b"ada,paris").unwrap();
+    assert_eq!(keys(&store, "london"), ["u3"]);
+    assert_eq!(keys(&store, "paris"), ["u1", "u2"]);
+
+    store.delete("u2").unwrap();
+    store.set("u3", b"cy").unwrap();
+    assert_eq!(keys(&store, "paris"), ["u1"]);
+    assert!(keys(&store, "london").is_empty());
+    assert!(keys(&store, "admin").is_empty());
+    assert_eq!(
+        store.index("tags").unwrap().get(b"paris"),
+        [("u1".to_string(), b"ada,paris".to_vec())]
+    );
+    assert!(store.index("missing").is_none());
+}
+
+#[test]
+fn index_is_loaded_on_init_and_rebuilt_once_stale() {
+    let dir = temp_dir("index-rebuild");
+    let mut store = open(&dir, true);
+    store.set("u1", b"ada,london").unwrap();
+    store.set("u2", b"bob,paris").unwrap();
+    drop(store);
+
+    let store = open(&dir, true);
+    assert_eq!(keys(&store, "london"), ["u1"]);
+    drop(store);
+
+    // Written while no extractor was registered, so the index misses it and goes stale.
+    let mut store = open(&dir, false);
+    store.set("u3", b"cy,london").unwrap();
+    assert!(store.index("tags").is_none());
+    drop(store);
+
+    let mut store = open(&dir, true);
+    assert_eq!(keys(&store, "london"), ["u1", "u3"]);
+    store.merge().unwrap();
+    drop(store);
+
+    let store = open(&dir, true);
+    assert_eq!(keys(&store, "london"), ["u1", "u3"]);
+    assert_eq!(keys(&store, "paris"), ["u2"]);
+    assert_eq!(store.stats().live_keys, 3);
+}
+
+#[test]
+fn range_lists_keys_by_what_they_are_indexed_under() {
+    let dir = temp_dir("index-range");
+    let mut store = open(&dir, true);
+    store.set("k1", b"x,b,c").unwrap();
+    store.set("k2", b"x,a").unwrap();
+    store.set("k3", b"x,c").unwrap();
+    store.set("k4", b"x,d").unwrap();
+
+    let index = store.index("tags").unwrap();
+    let keys = |start: &[u8], end: &[u8]| -> Vec<String> {
+        index
+            .range(start, end)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    };
+    assert_eq!(keys(b"b", b"d"), ["k1", "k1", "k3"]);
+    assert_eq!(keys(b"a", b"b"), ["k2"]);
+    assert_eq!(keys(b"a", b"z").len(), 5);
+    assert!(keys(b"d", b"b").is_empty());
+    assert!(keys(b"e", b"z").is_empty());
+    assert_eq!(
+        index.range(b"d", b"e"),
+        [("k4".to_string(), b"x,d".to_vec())]
+    );
+}
@@ -0,0 +1,178 @@
+use cask_db::{
+    batch::WriteBatch, disk_store::DiskStorage, error::DbError, options::DiskStorageOptions,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where the key size sits in a record header.
+const KEY_SIZE_OFFSET: u64 = 21;
+/// The size of the header at the start of every data file.
+const FILE_HEADER_SIZE: u64 = 8;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> Result<DiskStorage, cask_db::Error> {
+    let options = DiskStorageOptions {
+        max_key_size: 8,
+        max_value_size: 16,
+        ..Default::default()
+    };
+    let mut store = DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options)?;
+    store.init()?;
+    Ok(store)
+}
+
+fn db_error(err: &cask_db::Error) -> &DbError {
+    err.downcast_ref::<DbError>()
+        .unwrap_or_else(|| panic!("unexpected error {}", err))
+}
+
+/// Writes `a` to `e` and closes the store, leaving the first records in sealed `0.db`.
+fn sealed_store(name: &str) -> PathBuf {
+    let dir = temp_dir(name);
+    let mut store = open(&dir).unwrap();
+    for key in ["a", "b", "c", "d", "e"] {
+        store.set(key, key.as_bytes()).unwrap();
+    }
+    drop(store);
+    assert!(dir.join("1.db").exists());
+    dir
+}
+
+/// Overwrites the bytes at `position` of file `id`.
+fn overwrite(dir: &Path, id: u32, position: u64, bytes: &[u8]) {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(dir.join(format!("{}.db", id)))
+        .unwrap();
+    file.seek(SeekFrom::Start(position)).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+#[test]
+fn keys_and_values_past_the_limits_are_refused() {
+    let dir = temp_dir("limits");
+    let mut store = open(&dir).unwrap();
+    store.set("12345678", &[0; 16]).unwrap();
+
+    let err = store.set("123456789", b"v").unwrap_err();
+    assert!(matches!(
+        db_error(&err),
+        DbError::KeyTooLarge { size: 9, max: 8 }
+    ));
+    let err = store.set("k", &[0; 17]).unwrap_err();
+    assert!(matches!(
+        db_error(&err),
+        DbError::ValueTooLarge { size: 17, max: 16 }
+    ));
+
+    // Streams are refused by the length they promise, before any of it is read.
+    let err = store
+        .set_from_reader("k", Cursor::new([0; 4]), 1 << 40)
+        .unwrap_err();
+    assert!(matches!(db_error(&err), DbError::ValueTooLarge { size, .. } if *size == 1 << 40));
+
+    // A batch is refused whole when any one of its puts is too large.
+    let mut batch = WriteBatch::new();
+    batch
+        .put("fine", b"1")
+        .delete("12345678")
+        .put("k", &[0; 17]);
+    let err = store.apply_batch(&batch).unwrap_err();
+    assert!(matches!(db_error(&err), DbError::ValueTooLarge { .. }));
+
+    let err = store
+        .namespace("too long a name")
+        .set("k", b"v")
+        .unwrap_err();
+    assert!(matches!(db_error(&err), DbError::KeyTooLarge { .. }));
+
+    assert_eq!(store.get("fine"), None);
+    assert_eq!(store.get("12345678"), Some(vec![0; 16]));
+    assert_eq!(store.get("k"), None);
+    assert_eq!(store.namespaces().count(), 0);
+    assert_eq!(store.stats().live_keys, 1);
+}
+
+#[test]
+fn damaged_record_is_reported_where_it_is() {
+    let dir = sealed_store("limits-corrupt");
+    // The value of the first record, just past its header and one byte key.
+    overwrite(&dir, 0, FILE_HEADER_SIZE + 38, b"X");
+
+    let err = DiskStorage::verify(&dir.to_string_lossy()).unwrap_err();
+    assert!(matches!(
+        db_error(&err),
+        DbError::Corruption {
+            file_id: 0,
+            position: 8
+        }
+    ));
+    assert_eq!(err.to_string(), "corrupt record in 0.db at position 8");
+}
+
+#[test]
+fn huge_key_size_in_a_sealed_file_is_corruption() {
+    let dir = sealed_store("limits-key-size");
+    let mut bytes = vec![];
+    fs::File::open(dir.join("0.db"))
+        .unwrap()
+        .read_to_end(&mut bytes)
+        .unwrap();
+    // The second record, claiming a key far larger than the file or memory.
+    let second = FILE_HEADER_SIZE + 39;
+    overwrite(
+        &dir,
+        0,
+        second + KEY_SIZE_OFFSET,
+        &(usize::MAX - 8).to_be_bytes(),
+    );
+
+    let err = open(&dir).unwrap_err();
+    assert!(matches!(
+        db_error(&err),
+        DbError::Corruption { file_id: 0, position } if *position as u64 == second
+    ));
+    let err = DiskStorage::verify(&dir.to_string_lossy()).unwrap_err();
+    assert!(matches!(
+        db_error(&err),
+        DbError::Corruption { file_id: 0, .. }
+    ));
+
+    // Put back, the store opens as before.
+    overwrite(&dir, 0, 0, &bytes);
+    let store = open(&dir).unwrap();
+    assert_eq!(store.get("b"), Some(b"b".to_vec()));
+}
+
+#[test]
+fn huge_key_size_at_the_end_of_the_active_file_is_cut_off() {
+    let dir = temp_dir("limits-key-size-active");
+    let mut store = open(&dir).unwrap();
+    store.set("a", b"a").unwrap();
+    store.set("b", b"b").unwrap();
+    drop(store);
+
+    let len = fs::metadata(dir.join("0.db")).unwrap().len();
+    let last = len - 39;
+    overwrite(&dir, 0, last + KEY_SIZE_OFFSET, &u64::MAX.to_be_bytes());
+
+    // Taken for a write torn by a crash, so the record goes and the rest stays.
+    let mut store = open(&dir).unwrap();
+    assert_eq!(store.get("a"), Some(b"a".to_vec()));
+    assert_eq!(store.get("b"), None);
+    assert_eq!(fs::metadata(dir.join("0.db")).unwrap().len(), last);
+    store.set("c", b"c").unwrap();
+    drop(store);
+
+    let store = open(&dir).unwrap();
+    assert_eq!(store.get("c"), Some(b"c".to_vec()));
+    assert!(DiskStorage::verify(&dir.to_string_lossy()).is_ok());
+}
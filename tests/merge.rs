@@ -0,0 +1,47 @@
+use cask_db::{batch::WriteBatch, disk_store::DiskStorage};
+use std::{fs, path::Path};
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+#[test]
+fn background_merge_only_relocates_keys_left_untouched() {
+    let dir = std::env::temp_dir().join(format!("cask-db-merge-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut store = open(&dir);
+    for key in ["a", "b", "c", "d", "e", "f", "g"] {
+        store.set(key, key.as_bytes()).unwrap();
+    }
+    store.set("c", b"c2").unwrap();
+
+    let file_ids: Vec<u32> = store.stats().files.iter().map(|f| f.file_id).collect();
+    assert!(store.start_merge(&file_ids).unwrap());
+
+    // Both land before the merge is applied, which must not move them back.
+    let mut batch = WriteBatch::new();
+    batch.put("a", b"a2").delete("b");
+    store.apply_batch(&batch).unwrap();
+    store.finish_merge().unwrap();
+    assert!(!store.is_merging());
+
+    let check = |store: &DiskStorage| {
+        assert_eq!(store.get("a"), Some(b"a2".to_vec()));
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.get("c"), Some(b"c2".to_vec()));
+        for key in ["d", "e", "f", "g"] {
+            assert_eq!(store.get(key), Some(key.as_bytes().to_vec()));
+        }
+        let keys: Vec<&str> = store.keys("").collect();
+        assert_eq!(keys, ["a", "c", "d", "e", "f", "g"]);
+    };
+    check(&store);
+    assert_eq!(store.stats().live_keys, 6);
+    drop(store);
+
+    let store = open(&dir);
+    check(&store);
+    assert_eq!(store.stats().live_keys, 6);
+}
@@ -0,0 +1,82 @@
+use cask_db::{disk_store::DiskStorage, options::DiskStorageOptions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path, max_open_files: usize) -> DiskStorage {
+    let options = DiskStorageOptions {
+        mmap_reads: true,
+        max_open_files,
+        ..Default::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+#[test]
+fn sealed_files_are_mapped_and_the_active_one_is_read() {
+    let dir = temp_dir("mmap");
+    let mut store = open(&dir, 2);
+    for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+        store.set(key, key.to_uppercase().as_bytes()).unwrap();
+    }
+
+    let sealed = store.get_ref("a").unwrap();
+    assert!(sealed.is_mapped());
+    assert_eq!(&*sealed, b"A");
+    let active = store.get_ref("h").unwrap();
+    assert!(!active.is_mapped());
+    assert_eq!(&*active, b"H");
+
+    // More files than maps may be kept open; the ones dropped are mapped again when needed.
+    for _ in 0..2 {
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            assert_eq!(store.get(key), Some(key.to_uppercase().into_bytes()));
+        }
+    }
+    assert!(store.get_ref("missing").is_none());
+}
+
+#[test]
+fn maps_of_merged_files_are_dropped() {
+    let dir = temp_dir("mmap-merge");
+    let mut store = open(&dir, 64);
+    for round in ["1", "2"] {
+        for key in ["a", "b", "c", "d", "e"] {
+            store.set(key, round.as_bytes()).unwrap();
+        }
+    }
+    store.set("f", b"last").unwrap();
+    let before = store.get_ref("a").unwrap();
+    assert!(before.is_mapped());
+
+    store.merge().unwrap();
+    // The values of the sealed files now live in the merged one, which is mapped afresh; the
+    // last writes are still in the active file.
+    for key in ["a", "b", "c", "d"] {
+        let value = store.get_ref(key).unwrap();
+        assert!(value.is_mapped(), "{}", key);
+        assert_eq!(&*value, b"2");
+    }
+    assert!(!store.get_ref("f").unwrap().is_mapped());
+    // The old mapping outlives the removal of its file.
+    assert_eq!(&*before, b"2");
+
+    store.set("a", b"3").unwrap();
+    assert_eq!(store.get("a"), Some(b"3".to_vec()));
+    drop(store);
+
+    let store = open(&dir, 64);
+    assert_eq!(store.get("a"), Some(b"3".to_vec()));
+    assert_eq!(store.get("e"), Some(b"2".to_vec()));
+    assert_eq!(store.get("f"), Some(b"last".to_vec()));
+}
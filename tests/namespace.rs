@@ -0,0 +1,84 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+#[test]
+fn namespaces_keep_their_keys_apart() {
+    let dir = temp_dir("namespace");
+    let mut store = open(&dir);
+    store.set("k", b"default").unwrap();
+    store.namespace("a").set("k", b"a").unwrap();
+    store.namespace("a").set("only-a", b"a").unwrap();
+    store.namespace("b").set("k", b"b").unwrap();
+
+    assert_eq!(store.get("k"), Some(b"default".to_vec()));
+    assert_eq!(store.namespace("a").get("k"), Some(b"a".to_vec()));
+    assert_eq!(store.namespace("b").get("k"), Some(b"b".to_vec()));
+    assert!(!store.contains("only-a"));
+    assert!(!store.namespace("b").contains("only-a"));
+    assert_eq!(store.namespace("missing").get("k"), None);
+
+    store.namespace("a").delete("k").unwrap();
+    assert_eq!(store.get("k"), Some(b"default".to_vec()));
+    assert_eq!(store.namespace("b").get("k"), Some(b"b".to_vec()));
+    drop(store);
+
+    let mut store = open(&dir);
+    assert_eq!(store.namespaces().collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(store.keys("").collect::<Vec<_>>(), ["k"]);
+    let a = store.namespace("a");
+    assert_eq!(a.keys("").collect::<Vec<_>>(), ["only-a"]);
+    assert_eq!(
+        a.scan("").collect::<Vec<_>>(),
+        [("only-a".to_string(), b"a".to_vec())]
+    );
+    assert_eq!(store.stats().live_keys, 3);
+}
+
+#[test]
+fn drop_is_one_record_that_outlasts_reopen_and_merge() {
+    let dir = temp_dir("namespace-drop");
+    let mut store = open(&dir);
+    store.set("kept", b"1").unwrap();
+    for key in ["a", "b", "c", "d"] {
+        store.namespace("gone").set(key, key.as_bytes()).unwrap();
+    }
+    let records = DiskStorage::verify(&dir.to_string_lossy()).unwrap();
+
+    assert!(store.drop_namespace("gone").unwrap());
+    assert!(!store.drop_namespace("gone").unwrap());
+    assert_eq!(
+        DiskStorage::verify(&dir.to_string_lossy()).unwrap(),
+        records + 1
+    );
+    assert_eq!(store.namespace("gone").get("a"), None);
+    assert_eq!(store.namespaces().count(), 0);
+    drop(store);
+
+    let mut store = open(&dir);
+    assert_eq!(store.namespace("gone").get("a"), None);
+    store.merge().unwrap();
+    drop(store);
+
+    // Taking the name again starts out empty, rather than bringing back the old keys.
+    let mut store = open(&dir);
+    assert_eq!(store.namespace("gone").get("a"), None);
+    store.namespace("gone").set("e", b"e").unwrap();
+    assert_eq!(store.namespace("gone").keys("").collect::<Vec<_>>(), ["e"]);
+    assert_eq!(store.get("kept"), Some(b"1".to_vec()));
+    assert_eq!(store.stats().live_keys, 2);
+}
@@ -0,0 +1,85 @@
+use cask_db::{disk_store::DiskStorage, options::DiskStorageOptions, rate_limit::RateLimiter};
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+/// Runs `f`, returning how long it took.
+fn timed(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+#[test]
+fn debt_past_the_burst_is_slept_off_and_counted() {
+    let limiter = RateLimiter::new(Some(100_000));
+    // A second's worth goes through at once.
+    assert!(timed(|| limiter.acquire(100_000)) < Duration::from_millis(50));
+    assert_eq!(limiter.waits(), 0);
+    assert_eq!(limiter.throttled(), Duration::ZERO);
+
+    let took = timed(|| limiter.acquire(20_000));
+    assert!(took >= Duration::from_millis(190), "{:?}", took);
+    assert_eq!(limiter.waits(), 1);
+    let throttled = limiter.throttled();
+    assert!(
+        throttled >= Duration::from_millis(190) && throttled <= took,
+        "{:?}",
+        throttled
+    );
+}
+
+#[test]
+fn rate_changes_apply_to_every_clone_at_once() {
+    let limiter = RateLimiter::new(None);
+    assert_eq!(limiter.rate(), None);
+    limiter.acquire(usize::MAX);
+
+    let shared = limiter.clone();
+    shared.set_rate(Some(1_000_000));
+    assert_eq!(limiter.rate(), Some(1_000_000));
+    limiter.acquire(1_000_000);
+    let took = timed(|| limiter.acquire(100_000));
+    assert!(took >= Duration::from_millis(90), "{:?}", took);
+    assert_eq!(shared.waits(), 1);
+
+    // Lifting the limit lets everything through, however deep in debt the bucket is.
+    shared.set_rate(None);
+    assert!(timed(|| limiter.acquire(usize::MAX)) < Duration::from_millis(50));
+    assert_eq!(shared.waits(), 1);
+}
+
+#[test]
+fn merge_traffic_is_throttled_and_reported_in_stats() {
+    let dir = std::env::temp_dir().join(format!("cask-db-rate-limit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let options = DiskStorageOptions {
+        max_file_size: 4096,
+        ..Default::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    for i in 0..40 {
+        store.set(&format!("k{}", i % 20), &[b'v'; 1000]).unwrap();
+    }
+
+    assert_eq!(store.stats().io_rate_limit, None);
+    store.set_io_rate_limit(Some(40_000));
+    let stats = store.stats();
+    assert_eq!(stats.io_rate_limit, Some(40_000));
+    assert_eq!(stats.throttle_waits, 0);
+
+    // Foreground writes are never held back.
+    assert!(timed(|| store.set("k0", &[b'w'; 1000]).unwrap()) < Duration::from_millis(500));
+
+    // The merge reads and writes some 60 kilobytes, more than a second's worth of budget.
+    let took = timed(|| store.merge().unwrap());
+    let stats = store.stats();
+    assert!(stats.throttle_waits > 0);
+    assert!(stats.throttled_ms >= 200, "{}", stats.throttled_ms);
+    assert!(took.as_millis() as u64 >= stats.throttled_ms, "{:?}", took);
+    assert_eq!(store.get("k0"), Some(vec![b'w'; 1000]));
+    assert_eq!(store.get("k19"), Some(vec![b'v'; 1000]));
+}
@@ -0,0 +1,138 @@
+use cask_db::{
+    disk_store::DiskStorage,
+    options::{DiskStorageOptions, MergeTriggers},
+};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// A fresh directory for a store, removed first if an earlier run left it behind.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let options = DiskStorageOptions {
+        merge_triggers: MergeTriggers::disabled(),
+        ..DiskStorageOptions::default()
+    };
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// Size of every data file in `dir` by name.
+fn data_files(dir: &Path) -> HashMap<String, u64> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".db"))
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, entry.metadata().unwrap().len())
+        })
+        .collect()
+}
+
+/// Sets `key` and returns the path of the data file it went to.
+fn set_and_find(store: &mut DiskStorage, dir: &Path, key: &str, value: &[u8]) -> PathBuf {
+    let before = data_files(dir);
+    store.set(key, value).unwrap();
+    let (name, _) = data_files(dir)
+        .into_iter()
+        .find(|(name, size)| before.get(name) != Some(size))
+        .unwrap();
+    dir.join(name)
+}
+
+fn cut_tail(path: &Path, bytes: u64) {
+    let file = OpenOptions::new().write(true).open(path).unwrap();
+    let len = file.metadata().unwrap().len();
+    file.set_len(len - bytes).unwrap();
+}
+
+#[test]
+fn torn_write_to_the_active_file_is_dropped() {
+    let dir = temp_dir("torn");
+    let mut store = open(&dir);
+    for i in 0..4 {
+        store.set(&format!("key{}", i), b"before").unwrap();
+    }
+    let active = set_and_find(&mut store, &dir, "torn", b"never finished");
+    drop(store);
+    cut_tail(&active, 2);
+
+    let mut store = open(&dir);
+    assert_eq!(store.get("torn"), None);
+    assert_eq!(store.get("key3"), Some(b"before".to_vec()));
+    store.set("after", b"written").unwrap();
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("after"), Some(b"written".to_vec()));
+}
+
+#[test]
+fn torn_write_after_a_merge_is_dropped() {
+    let dir = temp_dir("torn-merged");
+    let mut store = open(&dir);
+    // Three records fill a data file, so the fourth starts the active file.
+    for i in 0..4 {
+        store.set(&format!("key{}", i % 3), &[b'a' + i]).unwrap();
+    }
+    store.merge().unwrap();
+    let active = set_and_find(&mut store, &dir, "torn", b"never finished");
+    drop(store);
+
+    // The merged file has the highest id, so the torn write is not in the last file.
+    let last = data_files(&dir)
+        .into_keys()
+        .max_by_key(|name| name.trim_end_matches(".db").parse::<u32>().unwrap())
+        .unwrap();
+    assert_ne!(dir.join(last), active);
+    cut_tail(&active, 2);
+
+    let mut store = open(&dir);
+    assert_eq!(store.get("torn"), None);
+    assert_eq!(store.get("key0"), Some(b"d".to_vec()));
+    assert_eq!(store.get("key1"), Some(b"b".to_vec()));
+    assert_eq!(store.get("key2"), Some(b"c".to_vec()));
+    store.set("after", b"written").unwrap();
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("after"), Some(b"written".to_vec()));
+    assert_eq!(store.get("key1"), Some(b"b".to_vec()));
+}
+
+#[test]
+fn write_under_way_is_only_cut_off_by_the_lock_holder() {
+    let dir = temp_dir("torn-locked");
+    let mut writer = open(&dir);
+    let active = set_and_find(&mut writer, &dir, "key", b"value");
+    let len = fs::metadata(&active).unwrap().len();
+
+    // The start of a record header, as another process writing it would leave it.
+    OpenOptions::new()
+        .append(true)
+        .open(&active)
+        .unwrap()
+        .write_all(&[0; 20])
+        .unwrap();
+
+    let reader = open(&dir);
+    assert_eq!(reader.get("key"), Some(b"value".to_vec()));
+    assert_eq!(fs::metadata(&active).unwrap().len(), len + 20);
+    drop(reader);
+    drop(writer);
+
+    let store = open(&dir);
+    assert_eq!(store.get("key"), Some(b"value".to_vec()));
+    assert_eq!(fs::metadata(&active).unwrap().len(), len);
+}
@@ -0,0 +1,291 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+fn data_files(dir: &Path) -> BTreeSet<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".db"))
+        .collect()
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+    }
+}
+
+/// Writes a few keys, overwriting some, and returns them as they should read.
+fn fill(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut store = open(dir);
+    for i in 0..8 {
+        store.set(&format!("key{}", i % 5), &[b'a' + i]).unwrap();
+    }
+    store.scan("").collect()
+}
+
+/// The manifest `rekey` leaves behind while it rewrites `replaces` into `merged`.
+fn write_manifest(dir: &Path, merged: &str, replaces: &[String], committed: bool) {
+    let id = |name: &str| name.trim_end_matches(".db").to_string();
+    let replaces: String = replaces
+        .iter()
+        .map(|name| format!(" {}", id(name)))
+        .collect();
+    let mut contents = format!("merged {}\nreplaces{}\n", id(merged), replaces);
+    if committed {
+        contents.push_str("committed\n");
+    }
+    fs::write(dir.join("REKEY"), contents).unwrap();
+}
+
+/// Runs the merge a rekey would on a copy of the store in `dir`, returning the files it
+/// replaced and the one it wrote, which is left in `dir` next to them.
+fn rewrite_copy(dir: &Path) -> (Vec<String>, String) {
+    rewrite_copy_with(dir, |copy| open(copy).merge().unwrap())
+}
+
+/// Runs `rewrite` on a copy of the store in `dir` like `rewrite_copy`.
+fn rewrite_copy_with(dir: &Path, rewrite: impl FnOnce(&Path)) -> (Vec<String>, String) {
+    let copy = dir.with_extension("copy");
+    let _ = fs::remove_dir_all(&copy);
+    copy_dir(dir, &copy);
+
+    let before = data_files(&copy);
+    rewrite(&copy);
+    let after = data_files(&copy);
+    let replaced: Vec<String> = before.difference(&after).cloned().collect();
+
+    // A rekey starts a new active file before it merges, which is left in place too.
+    let active = format!(
+        "{}.db",
+        fs::read_to_string(copy.join("ACTIVE")).unwrap().trim()
+    );
+    let written: Vec<&String> = after.difference(&before).collect();
+    for name in written.iter().map(|name| name.as_str()).chain(["ACTIVE"]) {
+        fs::copy(copy.join(name), dir.join(name)).unwrap();
+    }
+    let merged = written.into_iter().find(|name| **name != active).unwrap();
+    (replaced, merged.clone())
+}
+
+#[test]
+fn interrupted_rekey_is_rolled_back() {
+    let dir = temp_dir("rekey-rollback");
+    let expected = fill(&dir);
+    let (replaced, merged) = rewrite_copy(&dir);
+
+    // The rewritten file is only partly there, so the old files are the ones to keep.
+    let path = dir.join(&merged);
+    let len = fs::metadata(&path).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len / 2)
+        .unwrap();
+    write_manifest(&dir, &merged, &replaced, false);
+
+    let store = open(&dir);
+    assert!(!dir.join("REKEY").exists());
+    assert!(!dir.join(&merged).exists());
+    assert!(replaced.iter().all(|name| dir.join(name).exists()));
+    assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn committed_rekey_is_completed() {
+    let dir = temp_dir("rekey-complete");
+    let expected = fill(&dir);
+    let (replaced, merged) = rewrite_copy(&dir);
+
+    // The crash came after the first of the replaced files was removed.
+    write_manifest(&dir, &merged, &replaced, true);
+    fs::remove_file(dir.join(&replaced[0])).unwrap();
+
+    let store = open(&dir);
+    assert!(!dir.join("REKEY").exists());
+    assert!(dir.join(&merged).exists());
+    assert!(replaced.iter().all(|name| !dir.join(name).exists()));
+    assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+    assert!(DiskStorage::verify(&dir.to_string_lossy()).is_ok());
+}
+
+#[test]
+fn rekey_without_encryption_keeps_every_key() {
+    let dir = temp_dir("rekey-plain");
+    let expected = fill(&dir);
+
+    let mut store = open(&dir);
+    store.rekey(None).unwrap();
+    assert!(!dir.join("REKEY").exists());
+    assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+    store.set("after", b"rekey").unwrap();
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("after"), Some(b"rekey".to_vec()));
+    assert_eq!(store.get("key0"), expected[0].1.clone().into());
+}
+
+#[test]
+fn second_handle_leaves_a_rekey_under_way_alone() {
+    let dir = temp_dir("rekey-second-handle");
+    let expected = fill(&dir);
+    let rekeying = open(&dir);
+    let (replaced, merged) = rewrite_copy(&dir);
+    write_manifest(&dir, &merged, &replaced, false);
+
+    // To the handle without the lock the rekey is still running, not interrupted.
+    let mut other = open(&dir);
+    assert!(dir.join("REKEY").exists());
+    assert!(dir.join(&merged).exists());
+    assert!(replaced.iter().all(|name| dir.join(name).exists()));
+
+    let locked = format!("{} is locked by another open store", dir.display());
+    assert_eq!(other.rekey(None).unwrap_err().to_string(), locked);
+    assert_eq!(other.merge().unwrap_err().to_string(), locked);
+    drop(other);
+    drop(rekeying);
+
+    let store = open(&dir);
+    assert!(!dir.join("REKEY").exists());
+    assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+}
+
+#[cfg(feature = "encryption")]
+mod encrypted {
+    use super::*;
+    use cask_db::{encryption::EncryptionKey, options::DiskStorageOptions};
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey::new([byte; 32])
+    }
+
+    fn open_with(dir: &Path, key: Option<EncryptionKey>) -> Result<DiskStorage, String> {
+        let options = DiskStorageOptions {
+            encryption_key: key,
+            ..Default::default()
+        };
+        let mut store =
+            DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options)
+                .map_err(|err| err.to_string())?;
+        store.init().map_err(|err| err.to_string())?;
+        Ok(store)
+    }
+
+    /// Writes a few keys under `key`, overwriting some, and returns them as they should read.
+    fn fill_with(dir: &Path, key: EncryptionKey) -> Vec<(String, Vec<u8>)> {
+        let mut store = open_with(dir, Some(key)).unwrap();
+        for i in 0..8 {
+            store
+                .set(&format!("key{}", i % 5), format!("secret-{}", i).as_bytes())
+                .unwrap();
+        }
+        store.scan("").collect()
+    }
+
+    fn contains_plaintext(dir: &Path) -> bool {
+        data_files(dir).iter().any(|name| {
+            let bytes = fs::read(dir.join(name)).unwrap();
+            bytes.windows(6).any(|window| window == b"secret")
+        })
+    }
+
+    #[test]
+    fn encrypted_store_needs_its_key() {
+        let dir = temp_dir("encrypted");
+        let expected = fill_with(&dir, key(1));
+        assert!(!contains_plaintext(&dir));
+
+        let store = open_with(&dir, Some(key(1))).unwrap();
+        assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+        drop(store);
+
+        assert_eq!(
+            open_with(&dir, None).unwrap_err(),
+            "record is encrypted but no encryption key was given"
+        );
+        assert_eq!(
+            open_with(&dir, Some(key(2))).unwrap_err(),
+            "failed to decrypt record; wrong encryption key?"
+        );
+    }
+
+    #[test]
+    fn rekey_moves_the_store_to_the_new_key() {
+        let dir = temp_dir("rekey-encrypted");
+        let expected = fill_with(&dir, key(1));
+
+        let mut store = open_with(&dir, Some(key(1))).unwrap();
+        store.rekey(Some(key(2))).unwrap();
+        store.set("after", b"secret-after").unwrap();
+        drop(store);
+        assert!(!contains_plaintext(&dir));
+
+        let store = open_with(&dir, Some(key(2))).unwrap();
+        assert_eq!(store.get("after"), Some(b"secret-after".to_vec()));
+        let before: Vec<_> = store.scan("key").collect();
+        assert_eq!(before, expected);
+        drop(store);
+        assert!(open_with(&dir, Some(key(1))).is_err());
+
+        // Rekeying to no key leaves every record readable without one.
+        let mut store = open_with(&dir, Some(key(2))).unwrap();
+        store.rekey(None).unwrap();
+        drop(store);
+        let store = open_with(&dir, None).unwrap();
+        assert_eq!(store.get("after"), Some(b"secret-after".to_vec()));
+    }
+
+    /// Rekeys a copy of the store in `dir` from `from` to `to`, see `rewrite_copy`.
+    fn rekey_copy(dir: &Path, from: u8, to: u8) -> (Vec<String>, String) {
+        rewrite_copy_with(dir, |copy| {
+            let mut store = open_with(copy, Some(key(from))).unwrap();
+            store.rekey(Some(key(to))).unwrap();
+        })
+    }
+
+    #[test]
+    fn interrupted_rekey_keeps_the_old_key() {
+        let dir = temp_dir("rekey-encrypted-rollback");
+        let expected = fill_with(&dir, key(1));
+        let (replaced, merged) = rekey_copy(&dir, 1, 2);
+        write_manifest(&dir, &merged, &replaced, false);
+
+        let store = open_with(&dir, Some(key(1))).unwrap();
+        assert!(!dir.join("REKEY").exists());
+        assert!(!dir.join(&merged).exists());
+        assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn committed_rekey_is_completed_under_the_new_key() {
+        let dir = temp_dir("rekey-encrypted-complete");
+        let expected = fill_with(&dir, key(1));
+        let (replaced, merged) = rekey_copy(&dir, 1, 2);
+        write_manifest(&dir, &merged, &replaced, true);
+
+        let store = open_with(&dir, Some(key(2))).unwrap();
+        assert!(!dir.join("REKEY").exists());
+        assert!(replaced.iter().all(|name| !dir.join(name).exists()));
+        assert_eq!(store.scan("").collect::<Vec<_>>(), expected);
+    }
+}
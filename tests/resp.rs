@@ -0,0 +1,182 @@
+use cask_db::{disk_store::DiskStorage, resp};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(value: &str) -> Reply {
+    Reply::Bulk(Some(value.to_string()))
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Client {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                return Client {
+                    reader,
+                    writer: stream,
+                };
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("server on {} did not come up", addr);
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let (kind, rest) = line.trim_end().split_at(1);
+        match kind {
+            "+" => Reply::Status(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut bytes = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut bytes).unwrap();
+                    bytes.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(bytes).unwrap()))
+                }
+            },
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                Reply::Array((0..len).map(|_| self.read_reply()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+/// Serves a fresh store in a temporary directory, returning its address.
+fn serve(name: &str) -> String {
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    let store = Arc::new(Mutex::new(store));
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let server_addr = addr.clone();
+    thread::spawn(move || {
+        let _ = resp::serve(store, &server_addr);
+    });
+
+    addr
+}
+
+#[test]
+fn set_get_scan_and_expire_over_a_socket() {
+    let addr = serve("resp");
+    let mut client = Client::connect(&addr);
+
+    assert_eq!(
+        client.call(&["SET", "user:1", "ada"]),
+        Reply::Status("OK".into())
+    );
+    assert_eq!(client.call(&["GET", "user:1"]), bulk("ada"));
+    assert_eq!(client.call(&["GET", "missing"]), Reply::Bulk(None));
+
+    for key in ["user:2", "user:3", "user:4", "other"] {
+        client.call(&["SET", key, "x"]);
+    }
+
+    // Walk the keys two at a time, deleting one already returned along the way.
+    let mut cursor = "0".to_string();
+    let mut seen = vec![];
+    loop {
+        let reply = client.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "2"]);
+        let Reply::Array(parts) = reply else {
+            panic!("unexpected SCAN reply {:?}", reply);
+        };
+        let [Reply::Bulk(Some(next)), Reply::Array(keys)] = &parts[..] else {
+            panic!("unexpected SCAN reply {:?}", parts);
+        };
+        for key in keys {
+            if let Reply::Bulk(Some(key)) = key {
+                seen.push(key.clone());
+            }
+        }
+        if seen.len() == 2 {
+            client.call(&["DEL", "user:1"]);
+        }
+        // Clients read the cursor as an unsigned integer.
+        assert!(next.parse::<u64>().is_ok(), "cursor {:?}", next);
+        if next == "0" {
+            break;
+        }
+        cursor = next.clone();
+    }
+    assert_eq!(seen, ["user:1", "user:2", "user:3", "user:4"]);
+    assert!(matches!(client.call(&["SCAN", "7"]), Reply::Error(_)));
+
+    assert_eq!(client.call(&["EXPIRE", "user:2", "1"]), Reply::Integer(1));
+    assert_eq!(client.call(&["EXPIRE", "missing", "1"]), Reply::Integer(0));
+    assert_eq!(client.call(&["GET", "user:2"]), bulk("x"));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.call(&["GET", "user:2"]), Reply::Bulk(None));
+    assert_eq!(client.call(&["EXISTS", "user:2"]), Reply::Integer(0));
+}
+
+#[test]
+fn out_of_range_expire_times_are_rejected() {
+    let addr = serve("resp-expire");
+    let mut client = Client::connect(&addr);
+    client.call(&["SET", "k", "v"]);
+
+    let invalid =
+        |command: &str| Reply::Error(format!("ERR invalid expire time in '{}' command", command));
+    assert_eq!(
+        client.call(&["EXPIRE", "k", "9223372036854775807"]),
+        invalid("expire")
+    );
+    assert_eq!(
+        client.call(&["SET", "k", "w", "EX", "9223372036854775807"]),
+        invalid("set")
+    );
+    assert_eq!(client.call(&["SET", "k", "w", "EX", "-1"]), invalid("set"));
+    assert_eq!(client.call(&["SET", "k", "w", "PX", "0"]), invalid("set"));
+
+    // The rejected commands changed nothing, and the server still answers every client.
+    assert_eq!(client.call(&["GET", "k"]), bulk("v"));
+    let mut other = Client::connect(&addr);
+    assert_eq!(other.call(&["GET", "k"]), bulk("v"));
+    assert_eq!(other.call(&["EXPIRE", "k", "100"]), Reply::Integer(1));
+    assert_eq!(
+        other.call(&["SET", "k", "w", "PX", "100000"]),
+        Reply::Status("OK".into())
+    );
+}
@@ -0,0 +1,32 @@
+use cask_db::disk_store::DiskStorage;
+use std::fs;
+
+#[test]
+fn snapshot_keeps_its_view_through_writes_and_merges() {
+    let dir = std::env::temp_dir().join(format!("cask-db-snapshot-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    for key in ["a", "b", "c", "d"] {
+        store.set(key, key.as_bytes()).unwrap();
+    }
+
+    let snapshot = store.snapshot().unwrap();
+    let again = store.snapshot().unwrap();
+    store.set("a", b"changed").unwrap();
+    store.delete("b").unwrap();
+    store.set("e", b"e").unwrap();
+    store.merge().unwrap();
+
+    assert_eq!(snapshot.get("a"), Some(b"a".to_vec()));
+    assert_eq!(snapshot.get("b"), Some(b"b".to_vec()));
+    assert_eq!(snapshot.get("e"), None);
+    let keys: Vec<String> = snapshot.scan_from("").map(|(key, _)| key).collect();
+    assert_eq!(keys, ["a", "b", "c", "d"]);
+    assert_eq!(again.range("a", "c").len(), 2);
+
+    assert_eq!(store.get("a"), Some(b"changed".to_vec()));
+    assert_eq!(store.get("b"), None);
+    let keys: Vec<&str> = store.keys("").collect();
+    assert_eq!(keys, ["a", "c", "d", "e"]);
+}
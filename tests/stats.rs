@@ -0,0 +1,73 @@
+use cask_db::{disk_store::DiskStorage, options::DiskStorageOptions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open_with(dir: &Path, options: DiskStorageOptions) -> DiskStorage {
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// Writes `a` and `b` with 6-byte values, then overwrites `a` with a 10-byte one.
+fn fill(store: &mut DiskStorage) {
+    store.set("a", b"value1").unwrap();
+    store.set("b", b"value2").unwrap();
+    store.set("a", b"longer-one").unwrap();
+}
+
+fn assert_sizes(store: &DiskStorage) {
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.avg_key_size, 1.0);
+    assert_eq!(stats.avg_value_size, 8.0);
+}
+
+#[test]
+fn stats_count_key_and_value_bytes() {
+    let dir = temp_dir("stats");
+    let mut store = open_with(&dir, DiskStorageOptions::default());
+    fill(&mut store);
+    assert_sizes(&store);
+    store.merge().unwrap();
+    assert_sizes(&store);
+    drop(store);
+
+    assert_sizes(&open_with(&dir, DiskStorageOptions::default()));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn stats_leave_the_sealing_of_encrypted_records_out() {
+    use cask_db::encryption::EncryptionKey;
+
+    let dir = temp_dir("stats-encrypted");
+    let options = |byte| DiskStorageOptions {
+        encryption_key: Some(EncryptionKey::new([byte; 32])),
+        ..Default::default()
+    };
+    let mut store = open_with(&dir, options(1));
+    fill(&mut store);
+    assert_sizes(&store);
+    drop(store);
+
+    let mut store = open_with(&dir, options(1));
+    assert_sizes(&store);
+    store.rekey(Some(EncryptionKey::new([2; 32]))).unwrap();
+    assert_sizes(&store);
+    store.delete("b").unwrap();
+    assert_eq!(store.stats().avg_value_size, 10.0);
+    drop(store);
+
+    let store = open_with(&dir, options(2));
+    assert_eq!(store.stats().live_keys, 1);
+    assert_eq!(store.stats().avg_value_size, 10.0);
+}
@@ -0,0 +1,128 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// Size of every data file in `dir` by name.
+fn data_files(dir: &Path) -> HashMap<String, u64> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".db"))
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, entry.metadata().unwrap().len())
+        })
+        .collect()
+}
+
+fn value(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn streamed_value_reads_back_whole_and_from_any_position() {
+    let dir = temp_dir("stream");
+    let mut store = open(&dir);
+    let big = value(10_000);
+    store
+        .set_from_reader("big", Cursor::new(&big), big.len() as u64)
+        .unwrap();
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("big"), Some(big.clone()));
+
+    let mut reader = store.get_reader("big").unwrap();
+    assert_eq!(reader.len(), big.len() as u64);
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, big);
+
+    let mut piece = [0u8; 10];
+    assert_eq!(reader.seek(SeekFrom::Start(5000)).unwrap(), 5000);
+    reader.read_exact(&mut piece).unwrap();
+    assert_eq!(piece, big[5000..5010]);
+    assert_eq!(reader.seek(SeekFrom::Current(-20)).unwrap(), 4990);
+    reader.read_exact(&mut piece).unwrap();
+    assert_eq!(piece, big[4990..5000]);
+    assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 9997);
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, big[9997..]);
+    assert!(reader.seek(SeekFrom::Current(-20_000)).is_err());
+
+    assert!(store.get_reader("missing").is_none());
+}
+
+#[test]
+fn stream_of_the_wrong_length_writes_only_what_was_promised() {
+    let dir = temp_dir("stream-length");
+    let mut store = open(&dir);
+    store.set("k", b"old").unwrap();
+    let sizes = data_files(&dir);
+
+    assert!(store.set_from_reader("k", &b"abc"[..], 10).is_err());
+    assert_eq!(store.get("k"), Some(b"old".to_vec()));
+    assert_eq!(data_files(&dir), sizes);
+
+    // A longer stream is cut off at the given length.
+    store
+        .set_from_reader("long", &b"0123456789"[..], 4)
+        .unwrap();
+    assert_eq!(store.get("long"), Some(b"0123".to_vec()));
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("k"), Some(b"old".to_vec()));
+    assert_eq!(store.get("long"), Some(b"0123".to_vec()));
+}
+
+#[test]
+fn torn_streamed_record_is_cut_off() {
+    let dir = temp_dir("stream-torn");
+    let mut store = open(&dir);
+    store.set("a", b"1").unwrap();
+    let before = data_files(&dir);
+    let big = value(1000);
+    store
+        .set_from_reader("big", Cursor::new(&big), big.len() as u64)
+        .unwrap();
+    drop(store);
+
+    // The crash came while the value was still being written.
+    let (name, size) = data_files(&dir)
+        .into_iter()
+        .find(|(name, size)| before.get(name) != Some(size))
+        .unwrap();
+    let file = OpenOptions::new()
+        .write(true)
+        .open(dir.join(&name))
+        .unwrap();
+    file.set_len(size - 400).unwrap();
+
+    let mut store = open(&dir);
+    assert_eq!(store.get("big"), None);
+    assert_eq!(store.get("a"), Some(b"1".to_vec()));
+    store.set("b", b"2").unwrap();
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(store.get("b"), Some(b"2".to_vec()));
+    assert!(DiskStorage::verify(&dir.to_string_lossy()).is_ok());
+}
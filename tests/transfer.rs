@@ -0,0 +1,105 @@
+use cask_db::{
+    disk_store::DiskStorage,
+    transfer::{self, Format},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// Keys with text, binary and awkward values, as `(key, value)`.
+fn records() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("bin:all", (0..=255).collect()),
+        ("bin:invalid-utf8", vec![0xc3, 0x28, b'a', 0xff]),
+        ("bin:nul", vec![0, 0, 0]),
+        ("text:csv", b"a,\"b\"\nc".to_vec()),
+        ("text:empty", vec![]),
+        ("text:plain", "héllo wörld".as_bytes().to_vec()),
+    ]
+}
+
+fn round_trip(name: &str, format: Format) -> String {
+    let mut source = open(&temp_dir(name));
+    for (key, value) in records() {
+        source.set(key, &value).unwrap();
+    }
+    source.set("other", b"left out").unwrap();
+
+    let mut exported = vec![];
+    let count = transfer::export(&source, format, "bin:", &mut exported).unwrap();
+    assert_eq!(count, 3);
+    let count = transfer::export(&source, format, "text:", &mut exported).unwrap();
+    assert_eq!(count, 3);
+    let exported = String::from_utf8(exported).expect("export is text");
+
+    let mut dest = open(&temp_dir(&format!("{}-import", name)));
+    let text = match format {
+        // Both exports start with a header line of their own.
+        Format::Csv => exported.replacen("\nkey,value,encoding\n", "\n", 1),
+        Format::Jsonl => exported.clone(),
+    };
+    let count = transfer::import(&mut dest, format, text.as_bytes()).unwrap();
+    assert_eq!(count, 6);
+    for (key, value) in records() {
+        assert_eq!(dest.get(key), Some(value), "{}", key);
+    }
+    assert_eq!(dest.get("other"), None);
+
+    exported
+}
+
+#[test]
+fn jsonl_round_trips_binary_values_as_base64() {
+    let exported = round_trip("transfer-jsonl", Format::Jsonl);
+    let lines: Vec<serde_json::Value> = exported
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[1]["key"], "bin:invalid-utf8");
+    assert_eq!(lines[1]["value"], "wyhh/w==");
+    assert_eq!(lines[1]["encoding"], "base64");
+    assert_eq!(lines[5]["value"], "héllo wörld");
+    assert!(lines[5].get("encoding").is_none());
+}
+
+#[test]
+fn csv_round_trips_binary_values_as_base64() {
+    let exported = round_trip("transfer-csv", Format::Csv);
+    assert!(exported.starts_with("key,value,encoding\n"));
+    assert!(exported.contains("bin:invalid-utf8,wyhh/w==,base64\n"));
+    assert!(exported.contains("text:csv,\"a,\"\"b\"\"\nc\",utf8\n"));
+}
+
+#[test]
+fn import_stops_at_a_bad_value() {
+    let mut store = open(&temp_dir("transfer-bad"));
+    let jsonl = concat!(
+        r#"{"key": "a", "value": "1"}"#,
+        "\n\n",
+        r#"{"key": "b", "value": "not base64!", "encoding": "base64"}"#,
+        "\n",
+        r#"{"key": "c", "value": "3"}"#,
+    );
+    assert!(transfer::import(&mut store, Format::Jsonl, jsonl.as_bytes()).is_err());
+    assert_eq!(store.get("a"), Some(b"1".to_vec()));
+    assert_eq!(store.get("b"), None);
+
+    let csv = "key,value,encoding\nd,4,rot13\n";
+    let err = transfer::import(&mut store, Format::Csv, csv.as_bytes()).unwrap_err();
+    assert_eq!(err.to_string(), "unknown value encoding: rot13");
+    assert_eq!(store.get("d"), None);
+}
@@ -0,0 +1,90 @@
+use cask_db::disk_store::DiskStorage;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+fn open(dir: &Path) -> DiskStorage {
+    let mut store = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap();
+    store.init().unwrap();
+    store
+}
+
+/// A record as the first version of the store wrote it: CRC-32 of the timestamp, key and value,
+/// then the timestamp, key size and value size, with no sequence number, flags or file header.
+fn baseline_record(timestamp: usize, key: &str, value: &str) -> Vec<u8> {
+    let mut covered = timestamp.to_be_bytes().to_vec();
+    covered.extend(key.as_bytes());
+    covered.extend(value.as_bytes());
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&covered);
+
+    let mut bytes = crc.to_be_bytes().to_vec();
+    bytes.extend(timestamp.to_be_bytes());
+    bytes.extend(key.len().to_be_bytes());
+    bytes.extend(value.len().to_be_bytes());
+    bytes.extend(key.as_bytes());
+    bytes.extend(value.as_bytes());
+    bytes
+}
+
+fn write_baseline(dir: &Path, id: u32, records: &[(&str, &str)]) {
+    let bytes: Vec<u8> = records
+        .iter()
+        .flat_map(|(key, value)| baseline_record(1_700_000_000, key, value))
+        .collect();
+    fs::write(dir.join(format!("{}.db", id)), bytes).unwrap();
+}
+
+#[test]
+fn baseline_files_are_upgraded_on_open() {
+    let dir = temp_dir("upgrade");
+    write_baseline(&dir, 0, &[("a", "1"), ("b", "2")]);
+    write_baseline(&dir, 1, &[("a", "3")]);
+
+    let mut store = open(&dir);
+    assert_eq!(store.get("a"), Some(b"3".to_vec()));
+    assert_eq!(store.get("b"), Some(b"2".to_vec()));
+    assert_eq!(store.sequence(), 3);
+    store.set("c", b"4").unwrap();
+    drop(store);
+
+    let dir_str = dir.to_string_lossy().into_owned();
+    assert_eq!(DiskStorage::verify(&dir_str).unwrap(), 4);
+    let store = open(&dir);
+    assert_eq!(store.get("a"), Some(b"3".to_vec()));
+    assert_eq!(store.get("c"), Some(b"4".to_vec()));
+    assert_eq!(store.sequence(), 4);
+}
+
+#[test]
+fn baseline_records_are_numbered_after_upgraded_files() {
+    let dir = temp_dir("upgrade-resumed");
+    write_baseline(&dir, 0, &[("a", "1"), ("b", "2")]);
+    drop(open(&dir));
+
+    // A file left behind by an upgrade cut short, after one that was already rewritten.
+    write_baseline(&dir, 1, &[("b", "3")]);
+    let store = open(&dir);
+    assert_eq!(store.get("a"), Some(b"1".to_vec()));
+    assert_eq!(store.get("b"), Some(b"3".to_vec()));
+    assert_eq!(store.sequence(), 3);
+}
+
+#[test]
+fn corrupt_baseline_file_is_reported() {
+    let dir = temp_dir("upgrade-corrupt");
+    let mut bytes = baseline_record(1_700_000_000, "a", "1");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(dir.join("0.db"), bytes).unwrap();
+
+    let err = DiskStorage::new(Some(dir.to_string_lossy().into_owned())).unwrap_err();
+    assert_eq!(err.to_string(), "corrupt record in 0.db at position 0");
+}
@@ -0,0 +1,100 @@
+use cask_db::{
+    batch::WriteBatch, disk_store::DiskStorage, options::DiskStorageOptions, watch::Event,
+};
+use std::{fs, sync::mpsc::Receiver};
+
+fn open(name: &str, options: DiskStorageOptions) -> DiskStorage {
+    let dir = std::env::temp_dir().join(format!("cask-db-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut store =
+        DiskStorage::with_options(Some(dir.to_string_lossy().into_owned()), options).unwrap();
+    store.init().unwrap();
+    store
+}
+
+fn put(key: &str, seq: u64, value: &[u8]) -> Event {
+    Event::Put {
+        key: key.to_string(),
+        seq,
+        value: Some(value.to_vec()),
+    }
+}
+
+fn delete(key: &str, seq: u64) -> Event {
+    Event::Delete {
+        key: key.to_string(),
+        seq,
+    }
+}
+
+fn received(receiver: &Receiver<Event>) -> Vec<Event> {
+    receiver.try_iter().collect()
+}
+
+#[test]
+fn watchers_only_see_keys_under_their_prefix() {
+    let mut store = open("watch", DiskStorageOptions::default());
+    let users = store.watch("user:");
+    let everything = store.watch("");
+
+    store.set("user:1", b"ada").unwrap();
+    let first = store.sequence();
+    store.set("order:1", b"tea").unwrap();
+    store.namespace("other").set("user:2", b"bob").unwrap();
+    store.delete("user:1").unwrap();
+    let deleted = store.sequence();
+
+    assert_eq!(
+        received(&users),
+        [put("user:1", first, b"ada"), delete("user:1", deleted)]
+    );
+    assert_eq!(received(&everything).len(), 3);
+}
+
+#[test]
+fn batch_events_arrive_in_order_once_it_is_written() {
+    let mut store = open("watch-batch", DiskStorageOptions::default());
+    store.set("c", b"3").unwrap();
+    let receiver = store.watch("");
+
+    let mut batch = WriteBatch::new();
+    batch.put("a", b"1").put("b", b"2").delete("c");
+    assert!(received(&receiver).is_empty());
+    store.apply_batch(&batch).unwrap();
+
+    let last = store.sequence();
+    assert_eq!(
+        received(&receiver),
+        [
+            put("a", last - 2, b"1"),
+            put("b", last - 1, b"2"),
+            delete("c", last)
+        ]
+    );
+}
+
+#[test]
+fn full_buffer_reports_how_many_events_were_missed() {
+    let options = DiskStorageOptions {
+        watch_buffer: 2,
+        ..Default::default()
+    };
+    let mut store = open("watch-lagged", options);
+    let receiver = store.watch("");
+
+    for key in ["k1", "k2", "k3", "k4", "k5"] {
+        store.set(key, b"v").unwrap();
+    }
+    let events = received(&receiver);
+    assert_eq!(events.len(), 2);
+    assert!(matches!(&events[0], Event::Put { key, .. } if key == "k1"));
+
+    store.set("k6", b"v").unwrap();
+    assert_eq!(
+        received(&receiver),
+        [
+            Event::Lagged { missed: 3 },
+            put("k6", store.sequence(), b"v")
+        ]
+    );
+}